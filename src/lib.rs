@@ -1,8 +1,46 @@
 const HEADER_INFO_OFFSET: u32 = 0x420;
-const FILE_CONTENTS_ALIGNMENT: u32 = 15; // 32k
-const SEGMENT_ALIGNMENT: u32 = 8;
 
-pub const ROM_SIZE: u32 = 0x57058000;
+/// Sizes and alignments used when laying out an ISO.
+///
+/// Alignments are powers of two, so an alignment of 15 places data at multiples of 32KiB.
+pub mod consts {
+    /// Size of a standard single layer GameCube disc.
+    /// `write_iso` pads ISOs to this size, and ISOs can't grow past it unless `OperateOptions::allow_grow` is set.
+    pub const ROM_SIZE: u32 = 0x57058000;
+
+    /// Default alignment of file contents, matching most retail discs.
+    /// Overridden per file by '&&systemdata/alignment.txt'.
+    pub const FILE_CONTENTS_ALIGNMENT: u32 = 15;
+
+    /// Default alignment of the ends of the apploader and DOL, and so of the DOL and FST offsets.
+    /// Overridden by `WriteOptions::segment_alignment`.
+    pub const SEGMENT_ALIGNMENT: u32 = 8;
+
+    /// Largest alignment accepted in 'alignment.txt' or `WriteOptions::segment_alignment`.
+    /// Offsets in the FST are 32 bits, so anything larger could only place data at offset 0.
+    pub const MAX_ALIGNMENT: u32 = 31;
+
+    /// Alignment of file contents with `WriteOptions::strip_junk`, a single 2KiB disc sector.
+    pub const SECTOR_ALIGNMENT: u32 = 11;
+
+    /// Smallest alignment accepted for `IsoOp::InsertAt`. The disc is read in units of 4 bytes.
+    pub const MIN_FILE_ALIGNMENT: u32 = 2;
+
+    /// Longest file or directory name, in bytes, that `write_iso` accepts.
+    /// The FST itself has no limit, but longer names can't be extracted on most filesystems.
+    pub const MAX_FILENAME_LENGTH: usize = 255;
+
+    /// The default alignment of file contents in bytes, rather than as a power of two.
+    pub const fn default_alignment() -> u32 {
+        1 << FILE_CONTENTS_ALIGNMENT
+    }
+}
+
+pub use consts::{ROM_SIZE, MAX_FILENAME_LENGTH};
+use consts::{FILE_CONTENTS_ALIGNMENT, SEGMENT_ALIGNMENT, SECTOR_ALIGNMENT, MAX_ALIGNMENT, MIN_FILE_ALIGNMENT};
+
+/// Name offsets in FST entries are 24 bits, so every name must start within the first 16MiB of the string table.
+const MAX_STRING_TABLE_SIZE: u32 = 1 << 24;
 
 use std::path::{Path, PathBuf};
 
@@ -12,6 +50,15 @@ pub enum ReadISOError {
     RootDirNotEmpty,
     WriteFileError(std::io::Error),
     CreateDirError(std::io::Error),
+    /// With `ExtractLayout::Flat`, two files in the FST have the same flattened name.
+    FlatNameCollision(PathBuf),
+    /// The `ExtractLayout::Flat` separator is empty, '.' or '..', or contains '/' or '\',
+    /// so flattened names would not be single file names in the root directory.
+    InvalidSeparator(String),
+    /// A path in the FST contains '..' or is absolute, so it would be written outside the root directory.
+    UnsafePath(PathBuf),
+    /// The data is a compressed or container image, not a raw disc image.
+    UnsupportedFormat(IsoFormat),
 }
 
 #[derive(Debug)]
@@ -24,9 +71,40 @@ pub enum TreeISOError {
 #[derive(Debug)]
 pub enum WriteISOError {
     ISOTooLarge,
+    /// The file's offset plus its size does not fit in the 32 bit offsets and sizes of the FST.
+    OffsetOverflow(PathBuf),
     InvalidFilename(std::ffi::OsString),
-    ReadFileError(std::io::Error),
-    ReadDirError(std::io::Error),
+    ReadFileError { path: PathBuf, e: std::io::Error },
+    ReadDirError { path: PathBuf, e: std::io::Error },
+    WriteFileError(std::io::Error),
+    /// '&&systemdata/layout.txt' is malformed, or records files which overlap or extend past `ROM_SIZE`.
+    InvalidLayout,
+    InvalidAlignment,
+    /// `WriteOptions::segment_alignment` is larger than `MAX_ALIGNMENT`.
+    InvalidSegmentAlignment(u32),
+    InvalidSizes,
+    /// '&&systemdata/Game.toc' is not a valid FST, or its files overlap it.
+    InvalidToc,
+    /// The file or directory differs from '&&systemdata/Game.toc': it was added, removed, or changed size.
+    /// Delete 'Game.toc' to rebuild the FST instead.
+    TocMismatch(PathBuf),
+    DuplicateFilename(PathBuf),
+    /// The name is longer than `MAX_FILENAME_LENGTH` bytes,
+    /// or it is where the names of all files and directories outgrow the FST's string table.
+    FilenameTooLong(PathBuf),
+    UnsupportedSymlink(PathBuf),
+    /// The apploader's header declares more code and trailer than the file contains.
+    InvalidApploaderSize { declared: u64, actual: u64 },
+    /// 'ISO.hdr' is not 0x2440 bytes, or 'boot.bin' and 'bi2.bin' are not 0x440 and 0x2000 bytes. Contains the size of the file.
+    InvalidHeaderSize(u64),
+    /// 'ISO.hdr', 'AppLoader.ldr', or 'Start.dol' does not exist in '&&systemdata'.
+    /// 'boot.bin' or 'bi2.bin' if only one half of a split header exists.
+    MissingSystemFile(&'static str),
+    /// The FST built for the ISO has overlapping files, files outside the ISO, or inconsistent directory indices.
+    /// This is a bug in gc_fst. Only checked in debug builds or with `WriteOptions::self_check`.
+    InternalLayoutError,
+    #[cfg(feature = "zip")]
+    ZipError(zip::result::ZipError),
 }
 
 #[derive(Debug)]
@@ -34,11 +112,50 @@ pub enum OperateISOError {
     IOError(std::io::Error),
     OpenError { path: PathBuf, e: std::io::Error },
     FileInsertionReplicatesFolder(PathBuf),
+    /// The path doesn't exist in the ISO, isn't a valid path, or has a directory with the same name as an existing file.
     InvalidISOPath(PathBuf),
     InvalidFSPath(PathBuf),
     InvalidISO,
     TOCTooLarge,
     ISOTooLarge,
+    /// The inserted file is too large for the 32 bit sizes of the FST.
+    OffsetOverflow(PathBuf),
+    /// The offset given to `IsoOp::InsertAt` is not a multiple of `1 << MIN_FILE_ALIGNMENT`.
+    MisalignedOffset(u32),
+    /// The region given to `IsoOp::InsertAt` overlaps `conflict`,
+    /// which is another file or 'ISO.hdr', 'AppLoader.ldr', 'Start.dol', or 'Game.toc' for the FST.
+    OffsetOccupied { offset: u32, size: u32, conflict: PathBuf },
+    DolTooLarge { available: u32, required: u64 },
+    ApploaderTooLarge { available: u32, required: u64 },
+    CreateOpeningBnrError(CreateOpeningBnrError),
+}
+
+#[derive(Debug)]
+pub enum ShrinkISOError {
+    IOError(std::io::Error),
+    OpenError { path: PathBuf, e: std::io::Error },
+    InvalidISO,
+}
+
+#[derive(Debug)]
+pub enum RepackISOError {
+    IOError(std::io::Error),
+    OpenError { path: PathBuf, e: std::io::Error },
+    InvalidISO,
+    ISOTooLarge,
+    /// The names of all files and directories outgrow the FST's string table.
+    FilenameTooLong(PathBuf),
+}
+
+#[cfg(feature = "rvz")]
+#[derive(Debug)]
+pub enum ReadRVZError {
+    IOError(std::io::Error),
+    InvalidRVZ,
+    /// Only GameCube discs are supported, not Wii discs.
+    UnsupportedDiscType(u32),
+    /// Only uncompressed and zstd compressed (the RVZ default) files are supported.
+    UnsupportedCompression(u32),
 }
 
 #[derive(Debug)]
@@ -56,6 +173,19 @@ impl From<std::io::Error> for ReadISOFilesError {
     fn from(e: std::io::Error) -> Self { ReadISOFilesError::IOError(e) }
 }
 
+impl From<std::io::Error> for ShrinkISOError {
+    fn from(e: std::io::Error) -> Self { ShrinkISOError::IOError(e) }
+}
+
+impl From<std::io::Error> for RepackISOError {
+    fn from(e: std::io::Error) -> Self { RepackISOError::IOError(e) }
+}
+
+#[cfg(feature = "rvz")]
+impl From<std::io::Error> for ReadRVZError {
+    fn from(e: std::io::Error) -> Self { ReadRVZError::IOError(e) }
+}
+
 impl From<std::io::Error> for TreeISOError {
     fn from(e: std::io::Error) -> Self { TreeISOError::IOError(e) }
 }
@@ -66,10 +196,41 @@ pub enum FromPngError {
     DecodeError(lodepng::Error),
 }
 
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BannerError {
+    /// Banners must be exactly 96*32 pixels.
+    InvalidDimensions { width: u32, height: u32 },
+    /// The data length is not width*height*4 bytes.
+    InvalidDataLength { expected: usize, actual: usize },
+}
+
 #[derive(Clone, Debug)]
 pub struct RGB5A1Image(pub Box<[u8; 0x1800]>);
 
 impl RGB5A1Image {
+    pub const WIDTH: u32 = 96;
+    pub const HEIGHT: u32 = 32;
+
+    /// Convert from an rgba8 image with the given dimensions, such as a decoded png.
+    ///
+    /// Returns an error instead of panicking if the image is not 96*32
+    /// or the data does not contain exactly width*height pixels.
+    pub fn from_rgba8_dynamic(data: &[u8], width: u32, height: u32) -> Result<Self, BannerError> {
+        let expected = width as usize * height as usize * 4;
+        if data.len() != expected {
+            return Err(BannerError::InvalidDataLength { expected, actual: data.len() });
+        }
+        if width != Self::WIDTH || height != Self::HEIGHT {
+            return Err(BannerError::InvalidDimensions { width, height });
+        }
+
+        let mut pixels = Box::new([[0u8; 4]; 96*32]);
+        for (pixel, rgba) in pixels.iter_mut().zip(data.chunks_exact(4)) {
+            pixel.copy_from_slice(rgba);
+        }
+        Ok(Self::from_rgba8(&pixels))
+    }
+
     /// Convert from an rgba8 image. 
     ///
     /// Expects a 96*32 image in rows of pixels.
@@ -112,12 +273,214 @@ impl RGB5A1Image {
 
         Self(out)
     }
+
+    /// Convert to an rgba8 image of 96*32 pixels in rows, the inverse of `from_rgba8`.
+    ///
+    /// Pixels with the top bit set are opaque RGB555.
+    /// Otherwise they are RGB444 with a 3 bit alpha, as found in banners from real discs.
+    pub fn to_rgba8(&self) -> Box<[[u8; 4]; 96*32]> {
+        let mut out = Box::new([[0u8; 4]; 96*32]);
+
+        const TILES_X: usize = 24;
+        const TILES_Y: usize = 8;
+
+        let expand_5 = |c: u16| ((c << 3) | (c >> 2)) as u8;
+        let expand_4 = |c: u16| ((c << 4) | c) as u8;
+        let expand_3 = |c: u16| ((c << 5) | (c << 2) | (c >> 1)) as u8;
+
+        let mut in_i = 0;
+        for tile_y in 0..TILES_Y {
+            for tile_x in 0..TILES_X {
+                for ty in 0..4 {
+                    for tx in 0..4 {
+                        let y = tile_y*4 + ty;
+                        let x = tile_x*4 + tx;
+                        let out_i = x + y*96;
+
+                        let c = u16::from_be_bytes([self.0[in_i], self.0[in_i+1]]);
+                        out[out_i] = if c & 0x8000 != 0 {
+                            [expand_5((c >> 10) & 0x1F), expand_5((c >> 5) & 0x1F), expand_5(c & 0x1F), 255]
+                        } else {
+                            [expand_4((c >> 8) & 0xF), expand_4((c >> 4) & 0xF), expand_4(c & 0xF), expand_3((c >> 12) & 0x7)]
+                        };
+
+                        in_i += 2;
+                    }
+                }
+            }
+        }
+
+        out
+    }
+}
+
+
+/// Passed to progress callbacks after each file is copied.
+#[derive(Copy, Clone, Debug)]
+pub struct Progress<'a> {
+    pub bytes_done: u64,
+    pub bytes_total: u64,
+    pub current_path: &'a Path,
 }
 
+/// Passed to the `log` callback in `WriteOptions`, `ExtractOptions`, and `OperateOptions`
+/// for each entry of the FST as it is processed.
+#[derive(Copy, Clone, Debug)]
+pub enum LogEntry<'a> {
+    File { path: &'a Path, offset: u32, size: u32 },
+    Dir { path: &'a Path },
+}
+
+struct ProgressTracker<'a> {
+    callback: &'a mut dyn FnMut(Progress),
+    bytes_done: u64,
+    bytes_total: u64,
+}
+
+impl ProgressTracker<'_> {
+    fn advance(&mut self, bytes: u64, current_path: &Path) {
+        self.bytes_done += bytes;
+        (self.callback)(Progress {
+            bytes_done: self.bytes_done,
+            bytes_total: self.bytes_total,
+            current_path,
+        });
+    }
+}
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum GameRegion { UsOrJp, Eu, }
 
+impl GameRegion {
+    /// Determines the region from the fourth character of a game ID, such as 'GALE01'.
+    ///
+    /// ```
+    /// use gc_fst::GameRegion;
+    ///
+    /// assert_eq!(GameRegion::from_game_id(b"GALE01"), Some(GameRegion::UsOrJp));
+    /// assert_eq!(GameRegion::from_game_id(b"GALJ01"), Some(GameRegion::UsOrJp));
+    /// assert_eq!(GameRegion::from_game_id(b"GKYK01"), Some(GameRegion::UsOrJp));
+    /// assert_eq!(GameRegion::from_game_id(b"GALP01"), Some(GameRegion::Eu));
+    /// assert_eq!(GameRegion::from_game_id(b"GZLD01"), Some(GameRegion::Eu));
+    /// assert_eq!(GameRegion::from_game_id(b"GZLF01"), Some(GameRegion::Eu));
+    /// assert_eq!(GameRegion::from_game_id(b"GZLS01"), Some(GameRegion::Eu));
+    /// assert_eq!(GameRegion::from_game_id(b"GZLI01"), Some(GameRegion::Eu));
+    /// assert_eq!(GameRegion::from_game_id(b"GZLU01"), Some(GameRegion::Eu));
+    /// assert_eq!(GameRegion::from_game_id(b"GAL#01"), None);
+    /// ```
+    pub fn from_game_id(id: &[u8; 6]) -> Option<GameRegion> {
+        Country::from_game_id(id).region()
+    }
+
+    /// Determines the region from the region value in bi2.bin (0 = Japan, 1 = USA, 2 = PAL, 4 = Korea).
+    pub fn from_region_code(code: u32) -> Option<GameRegion> {
+        match code {
+            0 | 1 | 4 => Some(GameRegion::UsOrJp),
+            2 => Some(GameRegion::Eu),
+            _ => None,
+        }
+    }
+
+    /// The magic at the start of an 'opening.bnr' for this region.
+    pub fn banner_magic(self) -> &'static [u8; 4] {
+        match self {
+            GameRegion::UsOrJp => b"BNR1",
+            GameRegion::Eu => b"BNR2",
+        }
+    }
+}
+
+/// The country a game was released in, from the fourth character of its game ID.
+///
+/// ```
+/// use gc_fst::{Country, GameRegion};
+///
+/// assert_eq!(Country::from_game_id(b"GALE01"), Country::Usa);
+/// assert_eq!(Country::from_game_id(b"GALJ01"), Country::Japan);
+/// assert_eq!(Country::from_game_id(b"GALP01"), Country::Europe);
+/// assert_eq!(Country::from_game_id(b"GZLD01"), Country::Germany);
+/// assert_eq!(Country::from_game_id(b"GZLU01"), Country::Australia);
+/// assert_eq!(Country::from_game_id(b"GALE01").region(), Some(GameRegion::UsOrJp));
+/// assert_eq!(Country::from_game_id(b"GZLF01").region(), Some(GameRegion::Eu));
+/// assert_eq!(Country::from_game_id(b"GAL#01"), Country::Unknown(b'#'));
+/// ```
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Country {
+    /// 'J'
+    Japan,
+    /// 'E'
+    Usa,
+    /// 'K'
+    Korea,
+    /// 'W'
+    Taiwan,
+    /// 'P', and 'X', 'Y', 'L', and 'M', used for alternate European releases and imports.
+    Europe,
+    /// 'D'
+    Germany,
+    /// 'F'
+    France,
+    /// 'S'
+    Spain,
+    /// 'I'
+    Italy,
+    /// 'H'
+    Netherlands,
+    /// 'U'
+    Australia,
+    /// 'R'
+    Russia,
+    Unknown(u8),
+}
+
+impl Country {
+    pub fn from_game_id(id: &[u8; 6]) -> Country {
+        match id[3] {
+            b'J' => Country::Japan,
+            b'E' => Country::Usa,
+            b'K' => Country::Korea,
+            b'W' => Country::Taiwan,
+            b'P' | b'X' | b'Y' | b'L' | b'M' => Country::Europe,
+            b'D' => Country::Germany,
+            b'F' => Country::France,
+            b'S' => Country::Spain,
+            b'I' => Country::Italy,
+            b'H' => Country::Netherlands,
+            b'U' => Country::Australia,
+            b'R' => Country::Russia,
+            c => Country::Unknown(c),
+        }
+    }
+
+    /// NTSC countries use `UsOrJp` and PAL countries use `Eu`.
+    pub fn region(self) -> Option<GameRegion> {
+        match self {
+            Country::Japan | Country::Usa | Country::Korea | Country::Taiwan => Some(GameRegion::UsOrJp),
+            Country::Europe | Country::Germany | Country::France | Country::Spain | Country::Italy
+                | Country::Netherlands | Country::Australia | Country::Russia => Some(GameRegion::Eu),
+            Country::Unknown(_) => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Country::Japan => "Japan",
+            Country::Usa => "USA",
+            Country::Korea => "Korea",
+            Country::Taiwan => "Taiwan",
+            Country::Europe => "Europe",
+            Country::Germany => "Germany",
+            Country::France => "France",
+            Country::Spain => "Spain",
+            Country::Italy => "Italy",
+            Country::Netherlands => "Netherlands",
+            Country::Australia => "Australia",
+            Country::Russia => "Russia",
+            Country::Unknown(_) => "unknown",
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct GameInfo<'a> {
     pub region: GameRegion,
@@ -147,6 +510,18 @@ pub enum CreateOpeningBnrError {
     FullGameTitleTooLong,
     FullDevTitleTooLong,
     GameDescTooLong,
+    UnknownRegion,
+    /// The character can't be represented in the region's encoding.
+    #[cfg(feature = "encoding")]
+    UnencodableCharacter(char),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BuildIsoHdrError {
+    /// 'boot.bin' must be exactly 0x440 bytes. Contains the actual size.
+    InvalidBootBinSize(usize),
+    /// 'bi2.bin' must be exactly 0x2000 bytes. Contains the actual size.
+    InvalidBi2BinSize(usize),
 }
 
 impl<'a> GameInfo<'a> {
@@ -161,374 +536,2811 @@ impl<'a> GameInfo<'a> {
     }
 }
 
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum GameIdError {
+    /// Game IDs are exactly 6 bytes.
+    InvalidLength,
+    /// Game IDs only contain ASCII characters.
+    InvalidCharacter,
+}
+
+/// Checks that `id` can be written as a game ID, such as 'GALE01' or 'G2ME01': 6 ASCII characters.
+///
+/// ```
+/// use gc_fst::{validate_game_id, GameIdError};
+///
+/// assert_eq!(validate_game_id("GALE01"), Ok(*b"GALE01"));
+/// assert_eq!(validate_game_id("GALE0é"), Err(GameIdError::InvalidCharacter));
+/// assert_eq!(validate_game_id("🎮🎮"), Err(GameIdError::InvalidCharacter));
+/// assert_eq!(validate_game_id("GALE01X"), Err(GameIdError::InvalidLength));
+/// ```
+pub fn validate_game_id(id: &str) -> Result<[u8; 6], GameIdError> {
+    // checked first, so a multi-byte character isn't reported as the wrong length
+    if !id.is_ascii() { return Err(GameIdError::InvalidCharacter); }
+    id.as_bytes().try_into().map_err(|_| GameIdError::InvalidLength)
+}
+
 /// Converts fields into an 'opening.bnr' file.
+///
+/// Strings are copied as-is, so they should be ASCII. See `create_opening_bnr_encoded` for other characters.
 pub fn create_opening_bnr(info: GameInfo) -> Result<Box<[u8; 0x1960]>, CreateOpeningBnrError> {
     info.verify()?;
+    opening_bnr_from_fields(info.region, info.banner, [
+        info.game_title.as_bytes(),
+        info.developer_title.as_bytes(),
+        info.full_game_title.as_bytes(),
+        info.full_developer_title.as_bytes(),
+        info.game_description.as_bytes(),
+    ])
+}
 
-    let mut file = Box::new([0u8; 0x1960]);
-    let region = match info.region {
-        GameRegion::UsOrJp => b"BNR1",
-        GameRegion::Eu => b"BNR2",
+/// Like `create_opening_bnr`, but strings are transcoded to the encoding the GameCube menu and Dolphin use for the region:
+/// Shift-JIS for `UsOrJp`, and Windows-1252 for `Eu`.
+///
+/// Length limits apply to the encoded strings.
+#[cfg(feature = "encoding")]
+pub fn create_opening_bnr_encoded(info: GameInfo) -> Result<Box<[u8; 0x1960]>, CreateOpeningBnrError> {
+    let encoding = match info.region {
+        GameRegion::UsOrJp => encoding_rs::SHIFT_JIS,
+        GameRegion::Eu => encoding_rs::WINDOWS_1252,
     };
-    file[0..4].copy_from_slice(region);
-    file[0x20..][..0x1800].copy_from_slice(&*info.banner.0);
-    file[0x1820..][..info.game_title.len()].copy_from_slice(info.game_title.as_bytes());
-    file[0x1840..][..info.developer_title.len()].copy_from_slice(info.developer_title.as_bytes());
-    file[0x1860..][..info.full_game_title.len()].copy_from_slice(info.full_game_title.as_bytes());
-    file[0x18A0..][..info.full_developer_title.len()].copy_from_slice(info.full_developer_title.as_bytes());
-    file[0x18E0..][..info.game_description.len()].copy_from_slice(info.game_description.as_bytes());
-
-    Ok(file)
-}
 
-pub fn write_iso(root: &Path) -> Result<Vec<u8>, WriteISOError> {
-    let mut iso = Vec::with_capacity(ROM_SIZE as usize);
-    let mut path = root.to_path_buf();
-    
-    // write special files -------------------------------------------------
+    let encode = |s: &str| {
+        let (bytes, _, had_errors) = encoding.encode(s);
+        if had_errors {
+            let c = s.chars()
+                .find(|c| encoding.encode(c.encode_utf8(&mut [0u8; 4])).2)
+                .unwrap_or(char::REPLACEMENT_CHARACTER);
+            return Err(CreateOpeningBnrError::UnencodableCharacter(c));
+        }
+        Ok(bytes.into_owned())
+    };
 
-    path.push("&&systemdata");
+    opening_bnr_from_fields(info.region, info.banner, [
+        &encode(info.game_title)?,
+        &encode(info.developer_title)?,
+        &encode(info.full_game_title)?,
+        &encode(info.full_developer_title)?,
+        &encode(info.game_description)?,
+    ])
+}
 
+/// `fields` are the game title, developer title, full game title, full developer title, and description, already encoded.
+fn opening_bnr_from_fields(
+    region: GameRegion,
+    banner: &RGB5A1Image,
+    fields: [&[u8]; 5],
+) -> Result<Box<[u8; 0x1960]>, CreateOpeningBnrError> {
+    // offset, size including the null terminator
+    const FIELDS: [(usize, usize, CreateOpeningBnrError); 5] = [
+        (0x1820, 0x20, CreateOpeningBnrError::GameTitleTooLong),
+        (0x1840, 0x20, CreateOpeningBnrError::DevTitleTooLong),
+        (0x1860, 0x40, CreateOpeningBnrError::FullGameTitleTooLong),
+        (0x18A0, 0x40, CreateOpeningBnrError::FullDevTitleTooLong),
+        (0x18E0, 0x80, CreateOpeningBnrError::GameDescTooLong),
+    ];
 
-    path.push("ISO.hdr");
-    {
-        let mut header_file = std::fs::File::open(&path).map_err(|e| WriteISOError::ReadFileError(e))?;
-        std::io::copy(&mut header_file, &mut iso).map_err(|e| WriteISOError::ReadFileError(e))?;
+    let mut file = Box::new([0u8; 0x1960]);
+    file[0..4].copy_from_slice(region.banner_magic());
+    file[0x20..][..0x1800].copy_from_slice(&*banner.0);
+    for (field, (offset, size, too_long)) in fields.iter().zip(FIELDS) {
+        if field.len() >= size { return Err(too_long); }
+        file[offset..][..field.len()].copy_from_slice(field);
     }
-    path.pop();
-    // overwritten later: dol_offset, fst_offset, fst_size, max_fst_size @ 0x420
 
+    Ok(file)
+}
 
-    path.push("AppLoader.ldr");
-    {
-        let mut apploader_file = std::fs::File::open(&path).map_err(|e| WriteISOError::ReadFileError(e))?;
-        std::io::copy(&mut apploader_file, &mut iso).map_err(|e| WriteISOError::ReadFileError(e))?;
-    }
-    path.pop();
+/// Like `create_opening_bnr`, but the region is determined from the game ID instead of `info.region`.
+///
+/// ```
+/// use gc_fst::{create_opening_bnr_for_game_id, CreateOpeningBnrError, GameInfo, GameRegion, RGB5A1Image};
+///
+/// let banner = RGB5A1Image(Box::new([0; 0x1800]));
+/// let info = GameInfo {
+///     region: GameRegion::UsOrJp,
+///     game_title: "Melee",
+///     developer_title: "Nintendo",
+///     full_game_title: "Super Smash Bros. Melee",
+///     full_developer_title: "Nintendo / HAL Laboratory",
+///     game_description: "",
+///     banner: &banner,
+/// };
+///
+/// assert_eq!(&create_opening_bnr_for_game_id(b"GALE01", info).unwrap()[0..4], b"BNR1");
+/// assert_eq!(&create_opening_bnr_for_game_id(b"GALJ01", info).unwrap()[0..4], b"BNR1");
+/// assert_eq!(&create_opening_bnr_for_game_id(b"GALP01", info).unwrap()[0..4], b"BNR2");
+/// assert_eq!(&create_opening_bnr_for_game_id(b"GALD01", info).unwrap()[0..4], b"BNR2");
+/// assert_eq!(&create_opening_bnr_for_game_id(b"GALF01", info).unwrap()[0..4], b"BNR2");
+/// assert!(matches!(create_opening_bnr_for_game_id(b"GAL#01", info), Err(CreateOpeningBnrError::UnknownRegion)));
+/// ```
+pub fn create_opening_bnr_for_game_id(game_id: &[u8; 6], info: GameInfo) -> Result<Box<[u8; 0x1960]>, CreateOpeningBnrError> {
+    let region = GameRegion::from_game_id(game_id).ok_or(CreateOpeningBnrError::UnknownRegion)?;
+    create_opening_bnr(GameInfo { region, ..info })
+}
 
+/// Header fields to change with `set_header`. Fields left as `None` are not modified.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct HeaderEdit<'a> {
+    pub game_id: Option<[u8; 6]>,
 
-    let rounded_size = align(iso.len() as u32, SEGMENT_ALIGNMENT);
-    iso.resize(rounded_size as usize, 0u8);
+    /// Must be less than 0x20 bytes.
+    pub title: Option<&'a str>,
 
+    /// The whole 0x3E0 byte name field that `title` starts, which some homebrew loaders show instead.
+    /// Must be less than 0x3E0 bytes, and can't be set together with `title`.
+    pub full_title: Option<&'a str>,
 
-    path.push("Start.dol");
-    let dol_offset = iso.len() as u32;
-    {
-        let mut dol_file = std::fs::File::open(&path).map_err(|e| WriteISOError::ReadFileError(e))?;
-        std::io::copy(&mut dol_file, &mut iso).map_err(|e| WriteISOError::ReadFileError(e))?;
-    }
-    path.pop();
+    /// Zero based, so the second disc of a game is 1. Must be 0 or 1, as no GameCube release has more than two discs.
+    pub disc_number: Option<u8>,
 
+    /// The region byte in bi2.bin (0 = Japan, 1 = USA, 2 = PAL, 4 = Korea).
+    pub region: Option<u8>,
 
-    let rounded_size = align(iso.len() as u32, SEGMENT_ALIGNMENT);
-    iso.resize(rounded_size as usize, 0u8);
+    /// Also set the game title and full game title in the iso's 'opening.bnr' to the header's title,
+    /// for every language in the banner, so the menu and the header show the same name.
+    /// Only works on a full iso with an 'opening.bnr' in its root.
+    pub sync_banner: bool,
+}
 
-    // pop &&systemdata
-    path.pop();
+#[derive(Debug)]
+pub enum SetHeaderError {
+    IOError(std::io::Error),
+    OpenError { path: PathBuf, e: std::io::Error },
+    /// The file is too small to be a header, or is an iso with an invalid fst offset.
+    InvalidHeader,
+    TitleTooLong,
+    FullTitleTooLong,
+    /// `title` and `full_title` were both set. They share the same bytes, so only one can be written.
+    ConflictingTitles,
+    InvalidDiscNumber(u8),
+    /// `sync_banner` was set, but the file has no valid 'opening.bnr' in its root.
+    MissingBanner,
+    /// `sync_banner` was set, but the game ID is for a different region than the banner's magic,
+    /// so the banner would need to be recreated with `create_opening_bnr_for_game_id`.
+    BannerRegionMismatch(GameRegion),
+}
 
-    // write filesystem header, string table, and contents ---------------------------------------
+impl From<std::io::Error> for SetHeaderError {
+    fn from(e: std::io::Error) -> Self { SetHeaderError::IOError(e) }
+}
 
-    let fst_offset = iso.len() as u32;
+/// Edits the header of an 'ISO.hdr' file or a full iso in place.
+///
+/// The file, and the banner if `sync_banner` is set, are checked before anything is written,
+/// so passing a file which is not a header or iso will not corrupt it.
+pub fn set_header(path: &Path, edit: HeaderEdit) -> Result<(), SetHeaderError> {
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    const REGION_OFFSET: u64 = 0x458;
+
+    let mut f = std::fs::File::options().read(true).write(true).open(path)
+        .map_err(|e| SetHeaderError::OpenError { path: path.to_path_buf(), e })?;
+
+    let len = f.metadata()?.len();
+    if len < 0x440 { return Err(SetHeaderError::InvalidHeader); }
+    if edit.region.is_some() && len < REGION_OFFSET + 4 { return Err(SetHeaderError::InvalidHeader); }
+
+    // a full iso has file data after the header, so it must also have a sensible fst
+    if len > 0x2440 {
+        let mut buf = [0u8; 12];
+        f.seek(SeekFrom::Start(HEADER_INFO_OFFSET as _))?;
+        f.read_exact(&mut buf)?;
+        let fst_offset = u32::from_be_bytes(buf[4..8].try_into().unwrap()) as u64;
+        let fs_size = u32::from_be_bytes(buf[8..12].try_into().unwrap()) as u64;
+        if fst_offset < 0x2440 || fs_size < 0xC || fst_offset + fs_size > len {
+            return Err(SetHeaderError::InvalidHeader);
+        }
+    }
 
-    // we need the number of entries before we can write the strings, so we do a lil prepass.
-    let (entry_count, total_string_length) = count_entries(&path)?;
-    iso.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0]);
-    // entry_count technically includes this header, so we add 1 to it.
-    iso.extend_from_slice(&(entry_count+1).to_be_bytes());
-
-    let fs_end = fst_offset + 0xC*(entry_count+1);
-    let string_start = fs_end;
-    let string_end = string_start + total_string_length;
-    let fs_size = string_end - fst_offset;
-    iso.resize(string_end as usize, 0u8);
-
-    write_u32(&mut iso, HEADER_INFO_OFFSET+0, dol_offset);
-    write_u32(&mut iso, HEADER_INFO_OFFSET+4, fst_offset);
-    write_u32(&mut iso, HEADER_INFO_OFFSET+8, fs_size);
-    write_u32(&mut iso, HEADER_INFO_OFFSET+12, fs_size);
-
-    let entry_start = fst_offset + 0xC;
-    let mut entry_offset = entry_start;
-    let mut string_offset = string_start;
-
-    write_dir(
-        &mut path, 
-        &mut iso,
-        0,
-        entry_start,
-        &mut entry_offset,
-        string_start,
-        &mut string_offset,
-    )?;
-    
-    // mex makes the iso smaller, so apparently that's alright.
-    if iso.len() > ROM_SIZE as usize { return Err(WriteISOError::ISOTooLarge); }
-    iso.resize(ROM_SIZE as usize, 0u8);
+    if let Some(n) = edit.disc_number.filter(|&n| n > 1) { return Err(SetHeaderError::InvalidDiscNumber(n)); }
 
-    Ok(iso)
-}
+    let title = match edit.title {
+        Some(title) if title.len() >= 0x20 => return Err(SetHeaderError::TitleTooLong),
+        Some(title) => {
+            let mut bytes = [0u8; 0x20];
+            bytes[0..title.len()].copy_from_slice(title.as_bytes());
+            Some(bytes)
+        }
+        None => None,
+    };
 
-/// recursively called for each dir in root
-fn write_dir(
-    path: &Path, 
-    iso: &mut Vec<u8>,
-    parent_dir_idx: u32,
-    entry_start: u32,
-    entry_offset: &mut u32, 
-    string_start: u32,
-    string_offset: &mut u32, 
-) -> Result<(), WriteISOError> {
-    let mut path = path.to_path_buf();
+    let full_title = match edit.full_title {
+        Some(_) if title.is_some() => return Err(SetHeaderError::ConflictingTitles),
+        Some(full_title) if full_title.len() >= 0x3E0 => return Err(SetHeaderError::FullTitleTooLong),
+        Some(full_title) => {
+            let mut bytes = [0u8; 0x3E0];
+            bytes[0..full_title.len()].copy_from_slice(full_title.as_bytes());
+            Some(bytes)
+        }
+        None => None,
+    };
 
-    struct Entry {
-        pub name: String,
-        pub size: Option<u32>,
-    }
+    // offset of 'opening.bnr' and its number of languages
+    let banner = match edit.sync_banner {
+        true => {
+            if len <= 0x2440 { return Err(SetHeaderError::MissingBanner); }
+            let (offset, size) = find_iso_file(&mut f, Path::new("opening.bnr")).map_err(|e| match e {
+                ReadISOFilesError::IOError(e) => SetHeaderError::IOError(e),
+                _ => SetHeaderError::MissingBanner,
+            })?;
+            let mut magic = [0u8; 4];
+            f.seek(SeekFrom::Start(offset as _))?;
+            f.read_exact(&mut magic)?;
+            if size < 0x1960 || (&magic != b"BNR1" && &magic != b"BNR2") { return Err(SetHeaderError::MissingBanner); }
+
+            let game_id = match edit.game_id {
+                Some(game_id) => game_id,
+                None => {
+                    let mut game_id = [0u8; 6];
+                    f.seek(SeekFrom::Start(0x00))?;
+                    f.read_exact(&mut game_id)?;
+                    game_id
+                }
+            };
+            if let Some(region) = GameRegion::from_game_id(&game_id) {
+                if &magic != region.banner_magic() { return Err(SetHeaderError::BannerRegionMismatch(region)); }
+            }
 
-    let mut entries = Vec::with_capacity(256);
+            // BNR2 banners have a block of strings for each language
+            Some((offset as u64, (size as u64 - 0x1820) / 0x140))
+        }
+        false => None,
+    };
 
-    for entry in std::fs::read_dir(&path).map_err(|e| WriteISOError::ReadDirError(e))? {
-        let entry = entry.map_err(|e| WriteISOError::ReadDirError(e))?;
-        let metadata = entry.metadata().map_err(|e| WriteISOError::ReadDirError(e))?;
-        if metadata.is_file() {
-            entries.push(Entry {
-                name: entry.file_name().into_string().map_err(|f| WriteISOError::InvalidFilename(f))?,
-                size: Some(metadata.len() as u32),
-            })
-        } else if metadata.is_dir() {
-            let dir_name = entry.file_name();
-            if dir_name == "&&systemdata" { continue; }
+    if let Some(game_id) = edit.game_id {
+        f.seek(SeekFrom::Start(0x00))?;
+        f.write_all(&game_id)?;
+    }
+    if let Some(disc_number) = edit.disc_number {
+        f.seek(SeekFrom::Start(0x06))?;
+        f.write_all(&[disc_number])?;
+    }
+    if let Some(title) = title {
+        f.seek(SeekFrom::Start(0x20))?;
+        f.write_all(&title)?;
+    }
+    if let Some(full_title) = full_title {
+        f.seek(SeekFrom::Start(0x20))?;
+        f.write_all(&full_title)?;
+    }
+    if let Some(region) = edit.region {
+        f.seek(SeekFrom::Start(REGION_OFFSET))?;
+        f.write_all(&(region as u32).to_be_bytes())?;
+    }
 
-            entries.push(Entry {
-                name: dir_name.into_string().map_err(|f| WriteISOError::InvalidFilename(f))?,
-                size: None,
-            })
+    if let Some((banner_offset, languages)) = banner {
+        let title = match title {
+            Some(title) => title,
+            None => {
+                let mut title = [0u8; 0x20];
+                f.seek(SeekFrom::Start(0x20))?;
+                f.read_exact(&mut title)?;
+                title[0x1F] = 0;
+                title
+            }
+        };
+        let mut full_title = [0u8; 0x40];
+        full_title[..0x20].copy_from_slice(&title);
+
+        for i in 0..languages {
+            f.seek(SeekFrom::Start(banner_offset + 0x1820 + i * 0x140))?;
+            f.write_all(&title)?;
+            f.seek(SeekFrom::Start(banner_offset + 0x1860 + i * 0x140))?;
+            f.write_all(&full_title)?;
         }
     }
 
-    fn cmp_case_insensitive(a: &str, b: &str) -> std::cmp::Ordering {
-        a.chars()
-            .map(|c| c.to_ascii_lowercase())
-            .cmp(b.chars().map(|c| c.to_ascii_lowercase()))
-    }
+    Ok(())
+}
 
-    entries.sort_by(|a, b| cmp_case_insensitive(&a.name, &b.name));
+/// Replaces the whole 0x3E0 byte name field of an 'ISO.hdr' file or a full iso, which some homebrew loaders show.
+/// Its first 0x20 bytes are also the header's title.
+///
+/// Like `set_header` with only `full_title` set.
+pub fn set_full_title(path: &Path, full_title: &str) -> Result<(), SetHeaderError> {
+    set_header(path, HeaderEdit { full_title: Some(full_title), ..HeaderEdit::default() })
+}
 
-    for Entry { name, size } in entries {
-        if let Some(size) = size {
-            let rounded_size = align(iso.len() as u32, FILE_CONTENTS_ALIGNMENT);
-            iso.resize(rounded_size as usize, 0u8);
+/// Fields read from the header of an 'ISO.hdr' file or a full iso by `read_header`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IsoHeader {
+    pub game_id: [u8; 6],
+    /// Zero based, so the second disc of a game is 1.
+    pub disc_number: u8,
+    /// The name in the first 0x20 bytes of the name field, which `set_header` writes and the banner is synced from.
+    pub title: String,
+    /// The whole 0x3E0 byte name field, which some homebrew loaders show.
+    /// Usually the same as `title`.
+    pub full_title: String,
+    /// The region value in bi2.bin (0 = Japan, 1 = USA, 2 = PAL, 4 = Korea).
+    pub region_code: u32,
+}
 
-            // entry data
-            write_u32(iso, *entry_offset, *string_offset - string_start);
-            let contents_offset = iso.len() as u32;
-            write_u32(iso, *entry_offset+4, contents_offset);
-            write_u32(iso, *entry_offset+8, size);
-            *entry_offset += 0xC;
+impl IsoHeader {
+    /// The last two characters of the game ID, identifying the publisher, such as '01' for Nintendo.
+    pub fn maker_code(&self) -> [u8; 2] {
+        [self.game_id[4], self.game_id[5]]
+    }
 
-            // file name
-            let file_name_len = name.len() as u32;
-            iso[*string_offset as usize..][..file_name_len as usize].copy_from_slice(name.as_bytes());
-            iso[(*string_offset + file_name_len) as usize] = 0; // ensure null terminator
-            *string_offset += file_name_len + 1;
+    pub fn country(&self) -> Country {
+        Country::from_game_id(&self.game_id)
+    }
 
-            // contents
-            path.push(&name);
-            let mut file = std::fs::File::open(&path).map_err(|e| WriteISOError::ReadFileError(e))?;
-            std::io::copy(&mut file, iso).map_err(|e| WriteISOError::ReadFileError(e))?;
-            path.pop();
-        } else {
-            // entry data
-            let string_offset_from_start = *string_offset - string_start;
-            let mut w0 = string_offset_from_start.to_be_bytes();
-            w0[0] = 1; // directory flag
-            iso[*entry_offset as usize..][..4].copy_from_slice(&w0);
-            write_u32(iso, *entry_offset+4, parent_dir_idx);
-            // next idx written later
-            let next_idx_offset = *entry_offset + 8;
-            *entry_offset += 0xC;
-
-            // dir name
-            let dir_name_len = name.len() as u32;
-            iso[*string_offset as usize..][..dir_name_len as usize].copy_from_slice(name.as_bytes());
-            iso[(*string_offset + dir_name_len) as usize] = 0; // null terminator
-            *string_offset += dir_name_len + 1;
-
-            let sub_dir = path.join(&name);
-            let entry_index = (*entry_offset - entry_start) / 0xC; // 1-based index, so compute after 12 byte increment was added.
-            write_dir(
-                &sub_dir,
-                iso,
-                entry_index,
-                entry_start,
-                entry_offset,
-                string_start,
-                string_offset,
-            )?;
-
-            // Add 1 to fix off by one. These indices are a little weird.
-            let next_idx = (*entry_offset - entry_start) / 0xC + 1;
-            write_u32(iso, next_idx_offset, next_idx);
-        }
+    /// The region from bi2.bin, falling back to the game ID if the region value is unknown.
+    pub fn region(&self) -> Option<GameRegion> {
+        GameRegion::from_region_code(self.region_code).or_else(|| self.country().region())
     }
+}
 
-    Ok(())
+/// Combines a separate 'boot.bin' and 'bi2.bin', such as those from other tools, into the 'ISO.hdr' that `write_iso` reads.
+///
+/// ```
+/// use gc_fst::{build_iso_hdr, BuildIsoHdrError};
+///
+/// let iso_hdr = build_iso_hdr(&[1; 0x440], &[2; 0x2000]).unwrap();
+/// assert_eq!((iso_hdr[0x43F], iso_hdr[0x440]), (1, 2));
+/// assert_eq!(build_iso_hdr(&[1; 0x400], &[2; 0x2000]), Err(BuildIsoHdrError::InvalidBootBinSize(0x400)));
+/// ```
+pub fn build_iso_hdr(boot_bin: &[u8], bi2_bin: &[u8]) -> Result<[u8; 0x2440], BuildIsoHdrError> {
+    if boot_bin.len() != 0x440 { return Err(BuildIsoHdrError::InvalidBootBinSize(boot_bin.len())); }
+    if bi2_bin.len() != 0x2000 { return Err(BuildIsoHdrError::InvalidBi2BinSize(bi2_bin.len())); }
+
+    let mut iso_hdr = [0u8; 0x2440];
+    iso_hdr[..0x440].copy_from_slice(boot_bin);
+    iso_hdr[0x440..].copy_from_slice(bi2_bin);
+    Ok(iso_hdr)
 }
 
-fn count_entries(path: &Path) -> Result<(u32, u32), WriteISOError> {
-    let mut entry_count = 0;
-    let mut total_string_length = 0;
+/// Reads the game ID, titles, and region from an 'ISO.hdr' file or a full iso.
+pub fn read_header(path: &Path) -> Result<IsoHeader, ReadISOFilesError> {
+    use std::io::Read;
 
-    for entry in std::fs::read_dir(path).map_err(|e| WriteISOError::ReadDirError(e))? {
-        let entry = entry.map_err(|e| WriteISOError::ReadDirError(e))?;
-        match entry.file_type() {
-            Err(e) => return Err(WriteISOError::ReadDirError(e)),
-            Ok(f) if f.is_file() => {
-                entry_count += 1;
-                total_string_length += entry.file_name().len() as u32 + 1;
-            }
-            Ok(f) if f.is_dir() => {
-                let file_name = entry.file_name();
-                if file_name == "&&systemdata" { continue; }
-
-                entry_count += 1;
-                total_string_length += file_name.len() as u32 + 1;
-                // must realloc due to borrowing issues. No big deal cuz we're IO bottlenecked anyways.
-                let new_path = path.join(&file_name);
-                let (ec, sl) = count_entries(&new_path)?;
-                entry_count += ec;
-                total_string_length += sl;
-            }
+    let mut header = [0u8; 0x45C];
+    std::fs::File::open(path)?
+        .read_exact(&mut header)
+        .map_err(|_| ReadISOFilesError::InvalidISO)?;
+
+    Ok(IsoHeader::parse(&header))
+}
+
+impl IsoHeader {
+    fn parse(header: &[u8; 0x45C]) -> IsoHeader {
+        let string = |bytes: &[u8]| {
+            let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+            String::from_utf8_lossy(&bytes[..len]).into_owned()
+        };
 
-            // ignore symlinks
-            _ => (),
+        IsoHeader {
+            game_id: header[0..6].try_into().unwrap(),
+            disc_number: header[6],
+            title: string(&header[0x20..0x40]),
+            full_title: string(&header[0x20..0x400]),
+            region_code: read_u32(header, 0x458).unwrap(),
         }
     }
+}
 
-    Ok((entry_count, total_string_length))
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct DolSegment {
+    /// Offset of the segment in the DOL file.
+    pub offset: u32,
+    /// Address the segment is loaded to.
+    pub address: u32,
+    pub size: u32,
 }
 
-pub fn read_iso(iso: &[u8]) -> Result<(), ReadISOError> {
-    // mex makes the iso smaller, so apparently that's alright.
-    if iso.len() > ROM_SIZE as usize { return Err(ReadISOError::InvalidISO); }
+/// The header of a DOL executable, such as 'Start.dol'.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Dol {
+    pub text: [DolSegment; 7],
+    pub data: [DolSegment; 11],
+    pub bss_address: u32,
+    pub bss_size: u32,
+    pub entry_point: u32,
+}
 
-    let fst_offset = read_u32(iso, HEADER_INFO_OFFSET+4);
-    let entry_count = read_u32(iso, fst_offset + 0x8);
-    let string_table_offset = fst_offset + entry_count * 0xC;
-    let entry_start_offset = fst_offset + 0xC;
+impl Dol {
+    pub const HEADER_SIZE: usize = 0x100;
 
-    // write regular files ---------------------------------------------------
+    /// Returns None if `data` is smaller than the DOL header.
+    pub fn parse(data: &[u8]) -> Option<Dol> {
+        if data.len() < Self::HEADER_SIZE { return None; }
 
-    let mut path = PathBuf::from("./root/");
-    
-    if std::fs::read_dir(&path).is_ok_and(|p| p.count() != 0) {
-        return Err(ReadISOError::RootDirNotEmpty);
+        let segment = |i: u32| Some(DolSegment {
+            offset: read_u32(data, i*4)?,
+            address: read_u32(data, 0x48 + i*4)?,
+            size: read_u32(data, 0x90 + i*4)?,
+        });
+
+        let mut segments = [DolSegment::default(); 18];
+        for (i, s) in segments.iter_mut().enumerate() {
+            *s = segment(i as u32)?;
+        }
+
+        Some(Dol {
+            text: segments[..7].try_into().unwrap(),
+            data: segments[7..].try_into().unwrap(),
+            bss_address: read_u32(data, 0xD8)?,
+            bss_size: read_u32(data, 0xDC)?,
+            entry_point: read_u32(data, 0xE0)?,
+        })
     }
-    std::fs::create_dir_all(&path).map_err(|e| ReadISOError::CreateDirError(e))?;
 
-    let mut dir_end_indices = Vec::with_capacity(8);
-    let mut offset = entry_start_offset;
-    let mut entry_index = 1;
+    /// Text segments followed by data segments.
+    pub fn segments(&self) -> impl Iterator<Item = &DolSegment> {
+        self.text.iter().chain(self.data.iter())
+    }
 
-    while offset < string_table_offset {
-        while Some(entry_index) == dir_end_indices.last().copied() {
-            // dir has ended
-            dir_end_indices.pop();
-            path.pop();
-        }
+    /// Size of the DOL file, determined by the end of the last segment.
+    pub fn total_size(&self) -> u32 {
+        self.segments().map(|s| s.offset.saturating_add(s.size)).max().unwrap_or(0)
+    }
+}
 
-        let is_file = iso[offset as usize] == 0;
+/// The header of the apploader, such as 'AppLoader.ldr'. It is at 0x2440 in the ISO.
+///
+/// ```
+/// use gc_fst::AppLoader;
+///
+/// let mut header = [0u8; 0x20];
+/// header[..10].copy_from_slice(b"2001/04/12");
+/// header[0x10..0x14].copy_from_slice(&0x81200300u32.to_be_bytes());
+/// header[0x14..0x18].copy_from_slice(&0x1000u32.to_be_bytes());
+/// header[0x18..0x1C].copy_from_slice(&0x6E4u32.to_be_bytes());
+///
+/// let apploader = AppLoader::parse(&header).unwrap();
+/// assert_eq!(apploader.version, "2001/04/12");
+/// assert_eq!(apploader.entry_point, 0x81200300);
+/// assert_eq!(apploader.total_size(), 0x1720);
+/// assert_eq!(AppLoader::parse(&header[..0x1C]), None);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AppLoader {
+    /// The build date, such as "2001/04/12".
+    pub version: String,
+    pub entry_point: u32,
+    pub code_size: u32,
+    pub trailer_size: u32,
+}
 
-        let mut filename_offset_buf = [0; 4];
-        filename_offset_buf[1] = iso[offset as usize+1];
-        filename_offset_buf[2] = iso[offset as usize+2];
-        filename_offset_buf[3] = iso[offset as usize+3];
-        let filename_offset = u32::from_be_bytes(filename_offset_buf);
-        let filename = read_filename(iso, string_table_offset + filename_offset)
-            .ok_or(ReadISOError::InvalidISO)?;
+impl AppLoader {
+    pub const HEADER_SIZE: usize = 0x20;
 
-        if is_file {
-            let file_offset = read_u32(iso, offset+4);
-            let file_size = read_u32(iso, offset+8);
+    /// Returns None if `data` is smaller than the apploader header.
+    pub fn parse(data: &[u8]) -> Option<AppLoader> {
+        if data.len() < Self::HEADER_SIZE { return None; }
 
-            path.push(filename);
-            std::fs::write(&path, &iso[file_offset as usize..][..file_size as usize])
-                .map_err(|e| ReadISOError::WriteFileError(e))?;
-            path.pop();
-        } else {
-            //let parent_idx = read_u32(iso, offset+4); // unused
-            let next_idx = read_u32(iso, offset+8);
-            dir_end_indices.push(next_idx);
-            path.push(filename);
-            std::fs::create_dir_all(&path).map_err(|e| ReadISOError::CreateDirError(e))?;
-        }
+        let version = &data[..0x10];
+        let version_len = version.iter().position(|&b| b == 0).unwrap_or(version.len());
 
-        offset += 0xC;
-        entry_index += 1;
+        Some(AppLoader {
+            version: String::from_utf8_lossy(&version[..version_len]).into_owned(),
+            entry_point: read_u32(data, 0x10)?,
+            code_size: read_u32(data, 0x14)?,
+            trailer_size: read_u32(data, 0x18)?,
+        })
     }
-    
-    // write special (&&systemdata) files ------------------------------------
 
-    path.clear();
-    path.push("./root");
-    path.push("&&systemdata");
-    std::fs::create_dir_all(&path).map_err(|e| ReadISOError::CreateDirError(e))?;
+    /// Size of the apploader file, including the header, aligned to 32 bytes.
+    pub fn total_size(&self) -> u64 {
+        align(Self::HEADER_SIZE as u64 + self.code_size as u64 + self.trailer_size as u64, 5)
+    }
+}
 
-    path.push("ISO.hdr");
-    std::fs::write(&path, &iso[0..0x2440])
-        .map_err(|e| ReadISOError::WriteFileError(e))?;
-    path.pop();
+/// Reads the apploader header from an ISO without extracting it.
+pub fn read_apploader(iso_path: &Path) -> Result<AppLoader, ReadISOFilesError> {
+    use std::io::{Read, Seek, SeekFrom};
 
-    path.push("AppLoader.ldr");
-    let apploader_code_size = read_u32(iso, 0x2454);
-    let apploader_trailer_size = read_u32(iso, 0x2458);
-    let apploader_total_size = align(apploader_code_size + apploader_trailer_size, 5);
-    let apploader_end = 0x2440 + apploader_total_size;
-    std::fs::write(&path, &iso[0x2440..apploader_end as usize])
-        .map_err(|e| ReadISOError::WriteFileError(e))?;
-    path.pop();
+    let mut iso = std::fs::File::open(iso_path)?;
+    let mut header = [0u8; AppLoader::HEADER_SIZE];
+    iso.seek(SeekFrom::Start(0x2440))?;
+    iso.read_exact(&mut header).map_err(|_| ReadISOFilesError::InvalidISO)?;
 
-    path.push("Start.dol");
-    let dol_offset = read_u32(iso, HEADER_INFO_OFFSET);
-    let dol_size = (0..18).map(|i| {
-        let segment_offset = read_u32(iso, dol_offset+i*4);
-        let segment_size = read_u32(iso, dol_offset + 0x90 + i*4);
-        segment_offset+segment_size
-    }).max().unwrap();
-    let dol_end = dol_offset + dol_size;
-    std::fs::write(&path, &iso[dol_offset as usize..dol_end as usize])
-        .map_err(|e| ReadISOError::WriteFileError(e))?;
-    path.pop();
+    Ok(AppLoader::parse(&header).unwrap())
+}
 
-    // We don't write Game.toc. It's pretty much useless.
-    // The point of exporting the fs is to modify, add, and remove files,
-    // which means we have to recreate the table of contents anyways when rebuilding the iso.
+pub fn write_iso(root: &Path) -> Result<Vec<u8>, WriteISOError> {
+    write_iso_with_progress(root, &mut |_| {})
+}
 
-    Ok(())
+/// Like `write_iso`, but calls `progress` after each file is copied.
+pub fn write_iso_with_progress(root: &Path, progress: &mut dyn FnMut(Progress)) -> Result<Vec<u8>, WriteISOError> {
+    let mut iso = std::io::Cursor::new(Vec::with_capacity(ROM_SIZE as usize));
+    write_iso_to_with_progress(root, &mut iso, progress)?;
+    Ok(iso.into_inner())
 }
 
-#[derive(Copy, Clone, Debug)]
-pub enum IsoOp<'a> {
-    Insert { iso_path: &'a Path, input_path: &'a Path },
-    Delete { iso_path: &'a Path },
+/// An ISO built by `write_iso_sized`.
+#[derive(Clone, Debug)]
+pub struct WriteResult {
+    /// The ISO, padded to `ROM_SIZE` like `write_iso` unless `strip_junk` was set.
+    pub iso: Vec<u8>,
+    /// Aligned end of the real data, before the padding, from `IsoLayout::data_len`.
+    pub data_len: usize,
 }
 
-#[derive(Copy, Clone, Debug)]
-enum FsEntry<'a> {
-    PushDir { name: &'a str },
-    PopDir,
-    File {
+/// Like `write_iso_to_with_options`, but returns the ISO along with how much of it is data rather than padding.
+pub fn write_iso_sized(root: &Path, options: &WriteOptions) -> Result<WriteResult, WriteISOError> {
+    let layout = plan_iso(root, options)?;
+    let mut iso = std::io::Cursor::new(Vec::with_capacity(layout.iso_len() as usize));
+    write_planned_iso(&layout, &mut iso, &mut |_| {})?;
+    Ok(WriteResult { iso: iso.into_inner(), data_len: layout.data_len() as usize })
+}
+
+/// Rebuilds an ISO from `root`, writing directly to `out` rather than buffering the whole image.
+///
+/// `out` should be empty. Padding up to `ROM_SIZE` is done by seeking, so it is left as a hole.
+pub fn write_iso_to<W: std::io::Write + std::io::Seek>(root: &Path, out: &mut W) -> Result<(), WriteISOError> {
+    write_iso_to_with_progress(root, out, &mut |_| {})
+}
+
+/// Like `write_iso_to`, but calls `progress` after each file is copied.
+pub fn write_iso_to_with_progress<W: std::io::Write + std::io::Seek>(
+    root: &Path,
+    out: &mut W,
+    progress: &mut dyn FnMut(Progress),
+) -> Result<(), WriteISOError> {
+    write_iso_to_with_options(root, out, &WriteOptions::default(), progress)
+}
+
+#[derive(Clone, Default)]
+pub struct WriteOptions<'a> {
+    /// Files with identical contents share a single region in the ISO.
+    /// Some tools assume file regions never overlap, so this is off by default.
+    pub dedup: bool,
+    /// Symlinks in the root are followed and their targets included.
+    /// Otherwise, a symlink returns `WriteISOError::UnsupportedSymlink`.
+    pub follow_symlinks: bool,
+    /// Alternate paths for the files normally read from '&&systemdata'.
+    pub system_files: SystemFiles,
+    /// Order of files and directories within each directory of the FST.
+    /// Entries recorded in 'layout.txt' keep their recorded order regardless.
+    pub sort_order: SortOrder,
+    /// Alignment of the ends of the apploader and DOL, as a power of two. `None` uses `SEGMENT_ALIGNMENT`.
+    /// Some games were mastered with a larger alignment, which can be matched with this.
+    pub segment_alignment: Option<u32>,
+    /// Pack file contents at 2KiB instead of 32KiB and end the ISO after the last file, like NKit does,
+    /// making the smallest ISO that still loads.
+    /// This is lossy: the original padding and offsets are not kept, so the ISO won't match a dump of the disc.
+    /// 'layout.txt', 'sizes.txt', and 'Game.toc' are ignored, though 'alignment.txt' still applies.
+    pub strip_junk: bool,
+    /// Called for each file and directory as it is placed in the FST.
+    pub log: Option<&'a dyn Fn(LogEntry)>,
+    /// Check the FST before writing for overlapping files, files outside the ISO, and bad directory indices,
+    /// returning `InternalLayoutError` instead of writing a corrupt ISO. Always done in debug builds.
+    /// An FST kept from 'Game.toc' is not checked, as it comes from the original disc.
+    pub self_check: bool,
+}
+
+// `log` can't derive Debug
+impl std::fmt::Debug for WriteOptions<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WriteOptions")
+            .field("dedup", &self.dedup)
+            .field("follow_symlinks", &self.follow_symlinks)
+            .field("system_files", &self.system_files)
+            .field("sort_order", &self.sort_order)
+            .field("segment_alignment", &self.segment_alignment)
+            .field("strip_junk", &self.strip_junk)
+            .field("log", &self.log.is_some())
+            .field("self_check", &self.self_check)
+            .finish()
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum SortOrder {
+    /// Sorted by name, ignoring ASCII case. Most discs are mastered this way.
+    #[default]
+    CaseInsensitive,
+    /// Sorted by the raw bytes of each name, so uppercase comes before lowercase.
+    CaseSensitive,
+    /// The order the directory is read in, which depends on the OS and filesystem.
+    Unsorted,
+    /// Oldest modification time first, such as files extracted with `ExtractOptions::fst_order_mtimes`.
+    /// Files with the same time are in the order the directory is read in.
+    Modified,
+}
+
+/// Paths to use instead of the special files in '&&systemdata', such as those extracted by other tools.
+///
+/// Relative paths are relative to the root. Files which aren't set use their usual name in '&&systemdata'.
+#[derive(Clone, Debug, Default)]
+pub struct SystemFiles {
+    /// 'ISO.hdr'. Takes priority over `boot_bin` and `bi2_bin`.
+    pub header: Option<PathBuf>,
+    /// The first 0x440 bytes of the header. Used along with `bi2_bin` instead of 'ISO.hdr'.
+    pub boot_bin: Option<PathBuf>,
+    /// The last 0x2000 bytes of the header.
+    pub bi2_bin: Option<PathBuf>,
+    /// 'AppLoader.ldr', called 'apploader.img' by Dolphin.
+    pub apploader: Option<PathBuf>,
+    /// 'Start.dol', called 'main.dol' by Dolphin.
+    pub dol: Option<PathBuf>,
+}
+
+pub fn write_iso_to_with_options<W: std::io::Write + std::io::Seek>(
+    root: &Path,
+    out: &mut W,
+    options: &WriteOptions,
+    progress: &mut dyn FnMut(Progress),
+) -> Result<(), WriteISOError> {
+    let layout = plan_iso(root, options)?;
+    write_planned_iso(&layout, out, progress)
+}
+
+/// Where `write_iso` will place everything, computed by `plan_iso` without copying any file contents.
+#[derive(Clone, Debug)]
+pub struct IsoLayout {
+    pub dol_offset: u32,
+    pub fst_offset: u32,
+    /// size of the FST, including the string table
+    pub fs_size: u32,
+    /// number of FST entries, including the root
+    pub entry_count: u32,
+    pub string_table_size: u32,
+    /// End of the last file's contents. The ISO is padded to `ROM_SIZE` if it ends before that, unless `strip_junk` was set.
+    pub data_end: u64,
+    /// Every file in FST order.
+    pub files: Vec<PlannedFile>,
+
+    /// whether to pad the ISO to `ROM_SIZE`
+    pad: bool,
+    /// whether to run `check_fst` before writing
+    self_check: bool,
+    /// (path, name, size, end including padding) of the header files, apploader, and dol, in order
+    system_files: Vec<(PathBuf, &'static str, u64, u64)>,
+    fst: Vec<u8>,
+}
+
+impl IsoLayout {
+    /// Size of the written ISO: `data_end`, padded to `ROM_SIZE` unless `strip_junk` was set.
+    pub fn iso_len(&self) -> u64 {
+        match self.pad {
+            true => self.data_end.max(ROM_SIZE as u64),
+            false => self.data_end,
+        }
+    }
+
+    /// Aligned end of the real data, before the padding: `data_end` aligned like the next file's contents would be.
+    /// When `strip_junk` was set the ISO ends right after the last file, so this is `data_end`.
+    pub fn data_len(&self) -> u64 {
+        align(self.data_end, FILE_CONTENTS_ALIGNMENT).min(self.iso_len())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PlannedFile {
+    pub iso_path: PathBuf,
+    pub input_path: PathBuf,
+    pub offset: u32,
+    pub size: u32,
+    /// The contents are shared with an earlier file when deduplicating, so they aren't copied again.
+    pub shared: bool,
+}
+
+/// Scans `root` and places every file like `write_iso_to_with_options`, without copying anything.
+///
+/// Returns `ISOTooLarge` if the files don't fit on a disc.
+/// When `options.dedup` is set, files are read to find duplicates.
+pub fn plan_iso(root: &Path, options: &WriteOptions) -> Result<IsoLayout, WriteISOError> {
+    let mut path = root.to_path_buf();
+    let mut pos = 0u64;
+    let mut system_files = Vec::with_capacity(4);
+
+    // files are padded while writing, so the positions only need to stay within the disc
+    let pad = |pos: &mut u64, end: u64| {
+        if end > ROM_SIZE as u64 { return Err(WriteISOError::ISOTooLarge); }
+        *pos = (*pos).max(end);
+        Ok(())
+    };
+    let system_file_size = |path: &Path, name: &'static str| {
+        path.metadata().map(|m| m.len()).map_err(|e| system_file_error(path.into(), name, e))
+    };
+    
+    // special files -------------------------------------------------------
+
+    let segment_alignment = options.segment_alignment.unwrap_or(SEGMENT_ALIGNMENT);
+    if segment_alignment > MAX_ALIGNMENT { return Err(WriteISOError::InvalidSegmentAlignment(segment_alignment)); }
+
+    let custom_system_files = &options.system_files;
+    let system_file = |custom: &Option<PathBuf>, default: &str| match custom {
+        Some(custom) => root.join(custom),
+        None => root.join("&&systemdata").join(default),
+    };
+
+    // the header may have been extracted as separate boot.bin and bi2.bin files
+    let iso_hdr = system_file(&custom_system_files.header, "ISO.hdr");
+    let split_header = custom_system_files.header.is_none()
+        && (custom_system_files.boot_bin.is_some() || custom_system_files.bi2_bin.is_some() || !iso_hdr.exists());
+    let header_files = match split_header {
+        true => {
+            // with no split header either, the usual ISO.hdr is what's missing
+            let custom = custom_system_files.boot_bin.is_some() || custom_system_files.bi2_bin.is_some();
+            let boot_bin = system_file(&custom_system_files.boot_bin, "boot.bin");
+            let bi2_bin = system_file(&custom_system_files.bi2_bin, "bi2.bin");
+            let boot_bin_name = if custom || boot_bin.exists() || bi2_bin.exists() { "boot.bin" } else { "ISO.hdr" };
+            vec![(boot_bin, boot_bin_name), (bi2_bin, "bi2.bin")]
+        }
+        false => vec![(iso_hdr, "ISO.hdr")],
+    };
+    for (header_file, name) in header_files {
+        let size = system_file_size(&header_file, name)?;
+        let expected_size = match name {
+            "boot.bin" => 0x440,
+            "bi2.bin" => 0x2000,
+            _ => 0x2440,
+        };
+        if size != expected_size { return Err(WriteISOError::InvalidHeaderSize(size)); }
+        pos += size;
+        system_files.push((header_file, name, size, pos));
+    }
+    // overwritten later: dol_offset, fst_offset, fst_size, max_fst_size @ 0x420
+
+
+    path.push("&&systemdata");
+    path.push("sizes.txt");
+    let sizes = match std::fs::read_to_string(&path) {
+        _ if options.strip_junk => SystemSizes::default(),
+        Ok(s) => SystemSizes::parse(&s).ok_or(WriteISOError::InvalidSizes)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => SystemSizes::default(),
+        Err(e) => return Err(WriteISOError::ReadFileError { path, e }),
+    };
+    path.pop();
+    path.pop();
+
+
+    let apploader_path = system_file(&custom_system_files.apploader, "AppLoader.ldr");
+    let apploader = std::fs::read(&apploader_path)
+        .map_err(|e| system_file_error(apploader_path.clone(), "AppLoader.ldr", e))?;
+    check_apploader_size(&apploader)?;
+    pos += apploader.len() as u64;
+    if let Some(size) = sizes.apploader { pad(&mut pos, 0x2440 + size as u64)?; }
+    let rounded_size = align(pos, segment_alignment);
+    pad(&mut pos, rounded_size)?;
+    system_files.push((apploader_path, "AppLoader.ldr", apploader.len() as u64, pos));
+
+
+    let dol_offset = pos;
+    let dol_path = system_file(&custom_system_files.dol, "Start.dol");
+    let dol_size = system_file_size(&dol_path, "Start.dol")?;
+    pos += dol_size;
+    if let Some(size) = sizes.dol { pad(&mut pos, dol_offset + size as u64)?; }
+    let rounded_size = align(pos, segment_alignment);
+    pad(&mut pos, rounded_size)?;
+    system_files.push((dol_path, "Start.dol", dol_size, pos));
+
+
+    path.push("&&systemdata");
+    path.push("layout.txt");
+    let layout = match std::fs::read_to_string(&path) {
+        _ if options.strip_junk => None,
+        Ok(s) => Some(Layout::parse(&s).ok_or(WriteISOError::InvalidLayout)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(WriteISOError::ReadFileError { path, e }),
+    };
+    path.pop();
+
+    path.push("alignment.txt");
+    let alignment = match std::fs::read_to_string(&path) {
+        Ok(s) => Alignment::parse(&s).ok_or(WriteISOError::InvalidAlignment)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Alignment::default(),
+        Err(e) => return Err(WriteISOError::ReadFileError { path, e }),
+    };
+    path.pop();
+
+    path.push("Game.toc");
+    let toc = match std::fs::read(&path) {
+        _ if options.strip_junk => None,
+        Ok(toc) => Some(toc),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(WriteISOError::ReadFileError { path, e }),
+    };
+    path.pop();
+
+    // pop &&systemdata
+    path.pop();
+
+    // filesystem header, string table, and contents ---------------------------------------
+
+    let fst_offset = pos;
+
+    // we need the number of entries before we can write the strings, so we do a lil prepass.
+    let (entry_count, total_string_length, _) = count_entries(&path, options.follow_symlinks)?;
+
+    if let Some(toc) = toc {
+        let fs_end = fst_offset + toc.len() as u64;
+        pad(&mut pos, fs_end)?;
+        let (files, data_end) = plan_toc_files(&path, &toc, entry_count, fs_end, options)?;
+        if data_end > ROM_SIZE as u64 { return Err(WriteISOError::ISOTooLarge); }
+
+        return Ok(IsoLayout {
+            dol_offset: dol_offset as u32,
+            fst_offset: fst_offset as u32,
+            fs_size: toc.len() as u32,
+            entry_count: entry_count+1,
+            string_table_size: toc.len() as u32 - 0xC*(entry_count+1),
+            data_end,
+            files,
+            pad: true,
+            self_check: false,
+            system_files,
+            fst: toc,
+        });
+    }
+
+    let string_start = 0xC*(entry_count+1);
+    let fs_size = string_start + total_string_length;
+    let fs_end = fst_offset + fs_size as u64;
+    pad(&mut pos, fs_end)?;
+
+    // Files which don't keep their recorded offset are placed after every recorded file.
+    let data_end = match layout {
+        Some(ref layout) => align(fs_end.max(layout.data_end()), FILE_CONTENTS_ALIGNMENT),
+        None => fs_end,
+    };
+
+    // The fst is built in memory and written once file contents have been copied.
+    let source = DiskSource { root, follow_symlinks: options.follow_symlinks };
+    let mut writer = FstWriter::new(source, options, entry_count, total_string_length, fs_end);
+    writer.data_end = data_end;
+    writer.layout = layout;
+    writer.alignment = alignment;
+    writer.write_dir(path, 0)?;
+
+    let FstWriter { fst, data_end, files, .. } = writer;
+    let files = files.into_iter()
+        .map(|f| PlannedFile { iso_path: f.iso_path, input_path: f.file, offset: f.offset, size: f.size, shared: f.shared })
+        .collect();
+
+    Ok(IsoLayout {
+        // both are before the file contents, so they are within ROM_SIZE
+        dol_offset: dol_offset as u32,
+        fst_offset: fst_offset as u32,
+        fs_size,
+        entry_count: entry_count+1,
+        string_table_size: total_string_length,
+        data_end,
+        files,
+        pad: !options.strip_junk,
+        self_check: options.self_check || cfg!(debug_assertions),
+        system_files,
+        fst,
+    })
+}
+
+/// Places files at the offsets recorded in 'Game.toc', which is written as the FST unchanged.
+///
+/// Returns the files and the end of their contents.
+/// The root must contain exactly the files and directories in the TOC, with the same sizes.
+fn plan_toc_files(root: &Path, toc: &[u8], entry_count: u32, fs_end: u64, options: &WriteOptions) -> Result<(Vec<PlannedFile>, u64), WriteISOError> {
+    let iter = FstIter::new(toc).map_err(|_| WriteISOError::InvalidToc)?;
+    // the root doesn't count itself
+    if iter.entry_count() != entry_count + 1 {
+        return Err(WriteISOError::TocMismatch(root.to_path_buf()));
+    }
+
+    let mut files = Vec::with_capacity(entry_count as usize);
+    let mut data_end = fs_end;
+    for event in iter {
+        match event.map_err(|_| WriteISOError::InvalidToc)? {
+            FstEvent::File { path, offset, size, .. } => {
+                let input_path = root.join(&path);
+                match input_path.metadata() {
+                    Ok(m) if m.is_file() && m.len() == size as u64 => (),
+                    _ => return Err(WriteISOError::TocMismatch(input_path)),
+                }
+                if (offset as u64) < fs_end { return Err(WriteISOError::InvalidToc); }
+
+                if let Some(log) = options.log {
+                    log(LogEntry::File { path: &path, offset, size });
+                }
+                data_end = data_end.max(offset as u64 + size as u64);
+                files.push(PlannedFile { iso_path: path, input_path, offset, size, shared: false });
+            }
+            FstEvent::EnterDir { path, .. } => {
+                if !root.join(&path).is_dir() { return Err(WriteISOError::TocMismatch(root.join(&path))); }
+                if let Some(log) = options.log {
+                    log(LogEntry::Dir { path: &path });
+                }
+            }
+            FstEvent::ExitDir => (),
+        }
+    }
+
+    Ok((files, data_end))
+}
+
+/// Writes an ISO planned by `plan_iso`, copying the special files and file contents into place.
+///
+/// `out` should be empty. Files which changed size since planning are truncated or padded with zeros to their planned size.
+pub fn write_planned_iso<W: std::io::Write + std::io::Seek>(
+    layout: &IsoLayout,
+    out: &mut W,
+    progress: &mut dyn FnMut(Progress),
+) -> Result<(), WriteISOError> {
+    use std::io::{Read, SeekFrom};
+
+    if layout.self_check {
+        check_fst(&layout.fst, layout.fst_offset as u64 + layout.fs_size as u64, layout.iso_len())?;
+    }
+
+    out.seek(SeekFrom::Start(0)).map_err(WriteISOError::WriteFileError)?;
+    let mut pos = 0u64;
+
+    for (path, name, size, end) in layout.system_files.iter() {
+        pos += copy_system_file(path, name, *size, out)?;
+        pad_to(out, &mut pos, *end)?;
+    }
+    pad_to(out, &mut pos, layout.fst_offset as u64 + layout.fs_size as u64)?;
+
+    let mut progress = ProgressTracker {
+        callback: progress,
+        bytes_done: 0,
+        bytes_total: layout.files.iter().map(|f| f.size as u64).sum(),
+    };
+
+    for file in layout.files.iter() {
+        if file.shared {
+            progress.advance(file.size as u64, &file.input_path);
+            continue;
+        }
+
+        let path = &file.input_path;
+        let f = std::fs::File::open(path).map_err(|e| WriteISOError::ReadFileError { path: path.clone(), e })?;
+        // only copy the size recorded in the fst, in case the file changed since we looked at it.
+        let mut f = f.take(file.size as u64);
+        out.seek(SeekFrom::Start(file.offset as u64)).map_err(WriteISOError::WriteFileError)?;
+        let copied = std::io::copy(&mut f, out).map_err(|e| WriteISOError::ReadFileError { path: path.clone(), e })?;
+        progress.advance(copied, path);
+    }
+
+    finish_iso(out, &layout.fst, layout.dol_offset as u64, layout.fst_offset as u64, layout.data_end, layout.pad)
+}
+
+/// Checks that no two files in `fst` overlap, every file is between `fs_end` and `iso_len`,
+/// and every directory is within its parent and ends where its parent does or earlier.
+/// Empty files take no space, so their offsets aren't checked.
+fn check_fst(fst: &[u8], fs_end: u64, iso_len: u64) -> Result<(), WriteISOError> {
+    let entry = |i: u32| -> Option<(bool, u32, u32)> {
+        let offset = i.checked_mul(0xC)?;
+        Some((*fst.get(offset as usize)? == 1, read_u32(fst, offset + 4)?, read_u32(fst, offset + 8)?))
+    };
+
+    let entry_count = match entry(0) {
+        Some((true, _, entry_count)) => entry_count,
+        _ => return Err(WriteISOError::InternalLayoutError),
+    };
+
+    // (index, next index) of each directory containing the current entry
+    let mut dirs = vec![(0, entry_count)];
+    let mut regions = Vec::new();
+    for i in 1..entry_count {
+        while dirs.last().is_some_and(|&(_, next_idx)| next_idx <= i) { dirs.pop(); }
+        let &(parent, parent_next_idx) = dirs.last().ok_or(WriteISOError::InternalLayoutError)?;
+
+        match entry(i).ok_or(WriteISOError::InternalLayoutError)? {
+            (true, parent_idx, next_idx) => {
+                if parent_idx != parent || next_idx <= i || next_idx > parent_next_idx {
+                    return Err(WriteISOError::InternalLayoutError);
+                }
+                dirs.push((i, next_idx));
+            }
+            (false, _, 0) => (),
+            (false, offset, size) => {
+                let (offset, size) = (offset as u64, size as u64);
+                if offset < fs_end || offset + size > iso_len { return Err(WriteISOError::InternalLayoutError); }
+                regions.push((offset, size));
+            }
+        }
+    }
+
+    // deduplicated files share the same region exactly
+    regions.sort_unstable();
+    regions.dedup();
+    if regions.windows(2).any(|w| w[0].0 + w[0].1 > w[1].0) {
+        return Err(WriteISOError::InternalLayoutError);
+    }
+
+    Ok(())
+}
+
+/// Writes the fst and header info, then pads the iso to `ROM_SIZE` if `pad` is set.
+fn finish_iso<W: std::io::Write + std::io::Seek>(
+    out: &mut W,
+    fst: &[u8],
+    dol_offset: u64,
+    fst_offset: u64,
+    data_end: u64,
+    pad: bool,
+) -> Result<(), WriteISOError> {
+    use std::io::SeekFrom;
+
+    // both are before the file contents, so they are within ROM_SIZE
+    let (dol_offset, fst_offset) = (dol_offset as u32, fst_offset as u32);
+    let fs_size = fst.len() as u32;
+
+    out.seek(SeekFrom::Start(fst_offset as u64)).map_err(WriteISOError::WriteFileError)?;
+    out.write_all(fst).map_err(WriteISOError::WriteFileError)?;
+
+    let mut header_info = [0u8; 16];
+    header_info[ 0..][..4].copy_from_slice(&dol_offset.to_be_bytes());
+    header_info[ 4..][..4].copy_from_slice(&fst_offset.to_be_bytes());
+    header_info[ 8..][..4].copy_from_slice(&fs_size.to_be_bytes());
+    header_info[12..][..4].copy_from_slice(&fs_size.to_be_bytes());
+    out.seek(SeekFrom::Start(HEADER_INFO_OFFSET as u64)).map_err(WriteISOError::WriteFileError)?;
+    out.write_all(&header_info).map_err(WriteISOError::WriteFileError)?;
+    
+    // mex makes the iso smaller, so apparently that's alright.
+    let end = match pad {
+        true => data_end.max(ROM_SIZE as u64),
+        false => data_end,
+    };
+    // empty files at the end may not have extended the iso yet
+    if out.seek(SeekFrom::End(0)).map_err(WriteISOError::WriteFileError)? < end {
+        out.seek(SeekFrom::Start(end - 1)).map_err(WriteISOError::WriteFileError)?;
+        out.write_all(&[0]).map_err(WriteISOError::WriteFileError)?;
+    }
+
+    Ok(())
+}
+
+/// Makes sure the code and trailer sizes in the apploader header don't extend past the end of the file.
+/// Otherwise, the apploader loaded at boot would include whatever follows it on the disc.
+fn check_apploader_size(apploader: &[u8]) -> Result<(), WriteISOError> {
+    let declared = match AppLoader::parse(apploader) {
+        Some(header) => AppLoader::HEADER_SIZE as u64 + header.code_size as u64 + header.trailer_size as u64,
+        None => AppLoader::HEADER_SIZE as u64,
+    };
+    let actual = apploader.len() as u64;
+    if declared > actual { return Err(WriteISOError::InvalidApploaderSize { declared, actual }); }
+    Ok(())
+}
+
+/// copies at most `size` bytes, returning the number of bytes copied
+fn copy_system_file<W: std::io::Write>(path: &Path, name: &'static str, size: u64, out: &mut W) -> Result<u64, WriteISOError> {
+    use std::io::Read;
+
+    let f = std::fs::File::open(path).map_err(|e| system_file_error(path.into(), name, e))?;
+    std::io::copy(&mut f.take(size), out).map_err(|e| WriteISOError::ReadFileError { path: path.into(), e })
+}
+
+fn system_file_error(path: PathBuf, name: &'static str, e: std::io::Error) -> WriteISOError {
+    match e.kind() {
+        std::io::ErrorKind::NotFound => WriteISOError::MissingSystemFile(name),
+        _ => WriteISOError::ReadFileError { path, e },
+    }
+}
+
+/// writes zeros until `pos` reaches `end`
+fn pad_to<W: std::io::Write>(out: &mut W, pos: &mut u64, end: u64) -> Result<(), WriteISOError> {
+    if end > ROM_SIZE as u64 { return Err(WriteISOError::ISOTooLarge); }
+
+    const ZEROS: [u8; 4096] = [0u8; 4096];
+    while *pos < end {
+        let n = (end - *pos).min(ZEROS.len() as u64);
+        out.write_all(&ZEROS[..n as usize]).map_err(WriteISOError::WriteFileError)?;
+        *pos += n;
+    }
+
+    Ok(())
+}
+
+/// Original FST order and file placement, recorded in `&&systemdata/layout.txt` when extracting.
+///
+/// Each line is `<offset> <size> <path>` for files and `- - <path>/` for directories, in FST order.
+/// Recorded files may share exactly the same contents, as deduplicated files do, but may not otherwise overlap.
+#[derive(Clone, Debug, Default)]
+struct Layout {
+    /// path in iso -> (fst index, offset and size if this is a file)
+    entries: std::collections::HashMap<PathBuf, (usize, Option<(u32, u32)>)>,
+}
+
+impl Layout {
+    fn parse(s: &str) -> Option<Layout> {
+        let mut entries = std::collections::HashMap::new();
+
+        for (i, line) in s.lines().filter(|l| !l.is_empty()).enumerate() {
+            let mut words = line.splitn(3, ' ');
+            let offset = words.next()?;
+            let size = words.next()?;
+            let path = words.next()?;
+
+            let placement = if offset == "-" {
+                None
+            } else {
+                let offset = u32::from_str_radix(offset.strip_prefix("0x")?, 16).ok()?;
+                let size = size.parse::<u32>().ok()?;
+                Some((offset, size))
+            };
+
+            let path = path.split('/').filter(|c| !c.is_empty()).collect::<PathBuf>();
+            entries.insert(path, (i, placement));
+        }
+
+        // recorded offsets are written to the fst as is, so they must not place files on top of each other
+        let mut placements = entries.values().filter_map(|&(_, placement)| placement).collect::<Vec<_>>();
+        if placements.iter().any(|&(offset, size)| offset as u64 + size as u64 > ROM_SIZE as u64) { return None; }
+        placements.retain(|&(_, size)| size != 0);
+        placements.sort_unstable();
+        placements.dedup();
+        if placements.windows(2).any(|w| w[0].0 as u64 + w[0].1 as u64 > w[1].0 as u64) { return None; }
+
+        Some(Layout { entries })
+    }
+
+    /// end of the last recorded file
+    fn data_end(&self) -> u64 {
+        self.entries.values()
+            .filter_map(|(_, placement)| placement.map(|(offset, size)| offset as u64 + size as u64))
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Space the apploader and dol took up in the original iso, recorded in `&&systemdata/sizes.txt` when extracting.
+///
+/// Each line is `<file name> <size>`, such as `Start.dol 1612032`.
+/// When rebuilding, a file smaller than its recorded size is followed by zeros up to that size,
+/// so padding after it in the original iso is kept and the following offsets don't change.
+#[derive(Copy, Clone, Debug, Default)]
+struct SystemSizes {
+    apploader: Option<u32>,
+    dol: Option<u32>,
+}
+
+impl SystemSizes {
+    fn parse(s: &str) -> Option<SystemSizes> {
+        let mut sizes = SystemSizes::default();
+
+        for line in s.lines().filter(|l| !l.is_empty()) {
+            let (name, size) = line.split_once(' ')?;
+            let size = size.parse::<u32>().ok()?;
+            match name {
+                "AppLoader.ldr" => sizes.apploader = Some(size),
+                "Start.dol" => sizes.dol = Some(size),
+                _ => return None,
+            }
+        }
+
+        Some(sizes)
+    }
+
+    /// Finds the space the apploader and dol take up in an iso.
+    /// Each is only recorded when the following region comes directly after it.
+    fn from_offsets(apploader_offset: u32, dol_offset: u32, fst_offset: u32, apploader_size: u64, dol_size: u32) -> SystemSizes {
+        SystemSizes {
+            apploader: dol_offset.checked_sub(apploader_offset).filter(|&region| region as u64 >= apploader_size),
+            dol: fst_offset.checked_sub(dol_offset).filter(|&region| region >= dol_size),
+        }
+    }
+
+    fn write(&self) -> String {
+        let mut s = String::new();
+        if let Some(size) = self.apploader { s.push_str(&format!("AppLoader.ldr {}\n", size)); }
+        if let Some(size) = self.dol { s.push_str(&format!("Start.dol {}\n", size)); }
+        s
+    }
+}
+
+/// Per file alignment, read from `&&systemdata/alignment.txt`.
+///
+/// Each line is `<pattern> <alignment bits>`, such as `*.ast 15`. Lines starting with '#' are ignored.
+/// Patterns containing '/' match the whole path in the iso, otherwise only the file name.
+/// '*' matches any number of characters and '?' matches one. The first matching line is used,
+/// and files matching no line use `FILE_CONTENTS_ALIGNMENT`.
+#[derive(Clone, Debug, Default)]
+struct Alignment {
+    rules: Vec<(String, u32)>,
+}
+
+impl Alignment {
+    fn parse(s: &str) -> Option<Alignment> {
+        let mut rules = Vec::new();
+
+        for line in s.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+            let (pattern, bits) = line.rsplit_once(char::is_whitespace)?;
+            let bits = bits.parse::<u32>().ok().filter(|&b| b <= MAX_ALIGNMENT)?;
+            rules.push((pattern.trim_end().to_string(), bits));
+        }
+
+        Some(Alignment { rules })
+    }
+
+    fn bits(&self, iso_path: &Path) -> Option<u32> {
+        let full_path = layout_path(iso_path);
+        let file_name = full_path.rsplit('/').next().unwrap_or(&full_path);
+
+        self.rules.iter()
+            .find(|(pattern, _)| match pattern.contains('/') {
+                true => glob_match(pattern.trim_start_matches('/').as_bytes(), full_path.as_bytes()),
+                false => glob_match(pattern.as_bytes(), file_name.as_bytes()),
+            })
+            .map(|&(_, bits)| bits)
+    }
+}
+
+/// Matches '*' and '?' wildcards. '*' also matches '/'.
+fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    let mut p = 0;
+    let mut i = 0;
+    // position after the last '*' and the position in `s` it is currently matching up to
+    let mut backtrack = None;
+
+    while i < s.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                p += 1;
+                backtrack = Some((p, i));
+            }
+            Some(&c) if c == b'?' || c == s[i] => {
+                p += 1;
+                i += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_i)) => {
+                    p = star_p;
+                    i = star_i + 1;
+                    backtrack = Some((star_p, star_i + 1));
+                }
+                None => return false,
+            }
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// An entry of a directory read by an `FstSource`.
+struct SourceEntry<S: FstSource + ?Sized> {
+    name: String,
+    kind: SourceKind<S>,
+    /// only used for `SortOrder::Modified`
+    modified: Option<std::time::SystemTime>,
+}
+
+enum SourceKind<S: FstSource + ?Sized> {
+    File { file: S::File, size: u32 },
+    Dir(S::Dir),
+}
+
+/// Where `FstWriter` reads directories and file contents from:
+/// the root directory for `plan_iso`, an `IsoBuilder`, or the ISO being repacked.
+trait FstSource {
+    /// Identifies the contents of a file, such as its path on disk.
+    type File;
+    /// Identifies a directory whose entries can be read.
+    type Dir;
+
+    /// The entries of `dir`, in the order they are read.
+    fn read_dir(&mut self, dir: Self::Dir) -> Result<Vec<SourceEntry<Self>>, WriteISOError>;
+
+    /// Hashes the contents of a file when deduplicating.
+    fn hash(&mut self, file: &Self::File, size: u32) -> Result<blake3::Hash, WriteISOError>;
+
+    /// Files with the same key already share their contents, so they keep sharing them without being hashed.
+    fn shared_key(&self, _file: &Self::File) -> Option<(u32, u32)> { None }
+
+    /// The path used in errors for the entry at `iso_path`.
+    fn error_path(&self, iso_path: &Path) -> PathBuf { iso_path.to_path_buf() }
+}
+
+/// Reads the root directory on disk for `plan_iso`.
+struct DiskSource<'a> {
+    root: &'a Path,
+    follow_symlinks: bool,
+}
+
+impl FstSource for DiskSource<'_> {
+    type File = PathBuf;
+    type Dir = PathBuf;
+
+    fn read_dir(&mut self, path: PathBuf) -> Result<Vec<SourceEntry<Self>>, WriteISOError> {
+        let mut entries = Vec::with_capacity(256);
+
+        for entry in std::fs::read_dir(&path).map_err(|e| WriteISOError::ReadDirError { path: path.clone(), e })? {
+            let entry = entry.map_err(|e| WriteISOError::ReadDirError { path: path.clone(), e })?;
+            let metadata = entry_metadata(&entry, self.follow_symlinks)?;
+            let kind = if metadata.is_file() {
+                let size = u32::try_from(metadata.len()).map_err(|_| WriteISOError::OffsetOverflow(entry.path()))?;
+                SourceKind::File { file: entry.path(), size }
+            } else if metadata.is_dir() {
+                if entry.file_name() == "&&systemdata" { continue; }
+                SourceKind::Dir(entry.path())
+            } else {
+                continue;
+            };
+
+            entries.push(SourceEntry {
+                name: entry.file_name().into_string().map_err(WriteISOError::InvalidFilename)?,
+                kind,
+                modified: metadata.modified().ok(),
+            });
+        }
+
+        // Names differing only by case are treated as the same file by some loaders.
+        let mut names = entries.iter().map(|e| e.name.as_str()).collect::<Vec<_>>();
+        names.sort_by(|a, b| cmp_case_insensitive(a, b));
+        if let Some(w) = names.windows(2).find(|w| cmp_case_insensitive(w[0], w[1]).is_eq()) {
+            return Err(WriteISOError::DuplicateFilename(path.join(w[1])));
+        }
+
+        Ok(entries)
+    }
+
+    fn hash(&mut self, path: &PathBuf, size: u32) -> Result<blake3::Hash, WriteISOError> {
+        hash_file(path, size)
+    }
+
+    fn error_path(&self, iso_path: &Path) -> PathBuf {
+        self.root.join(iso_path)
+    }
+}
+
+/// A file placed by `FstWriter`.
+struct PlacedFile<F> {
+    iso_path: PathBuf,
+    file: F,
+    offset: u32,
+    size: u32,
+    /// The contents are shared with an earlier file, so they aren't copied again.
+    shared: bool,
+}
+
+/// Builds the fst in memory and places file contents, for `plan_iso`, `IsoBuilder`, and `repack_iso`.
+/// Nothing is copied; the caller copies the contents of each placed file that isn't shared.
+struct FstWriter<'a, S: FstSource> {
+    source: S,
+    fst: Vec<u8>,
+    entry_offset: u32,
+    string_start: u32,
+    string_offset: u32,
+    /// end of the fst in the iso. File contents can't be placed before this.
+    fs_end: u64,
+    /// end of file contents placed so far
+    data_end: u64,
+    layout: Option<Layout>,
+    alignment: Alignment,
+    options: &'a WriteOptions<'a>,
+    /// path in the iso of the directory being written
+    iso_path: PathBuf,
+    /// (size, hash) -> offset of file contents already written, used when deduplicating.
+    written_contents: std::collections::HashMap<(u32, blake3::Hash), u32>,
+    /// `FstSource::shared_key` -> offset of file contents already written.
+    shared_contents: std::collections::HashMap<(u32, u32), u32>,
+    files: Vec<PlacedFile<S::File>>,
+}
+
+impl<'a, S: FstSource> FstWriter<'a, S> {
+    /// `entry_count` and `string_length` don't include the root. File contents are placed from `fs_end`.
+    fn new(source: S, options: &'a WriteOptions<'a>, entry_count: u32, string_length: u32, fs_end: u64) -> Self {
+        let string_start = 0xC*(entry_count+1);
+        let mut fst = vec![0u8; (string_start + string_length) as usize];
+        fst[0] = 1;
+        // entry_count technically includes this header, so we add 1 to it.
+        write_u32(&mut fst, 8, entry_count+1);
+
+        FstWriter {
+            source,
+            fst,
+            entry_offset: 0xC,
+            string_start,
+            string_offset: string_start,
+            fs_end,
+            data_end: fs_end,
+            layout: None,
+            alignment: Alignment::default(),
+            options,
+            iso_path: PathBuf::new(),
+            written_contents: std::collections::HashMap::new(),
+            shared_contents: std::collections::HashMap::new(),
+            files: Vec::with_capacity(entry_count as usize),
+        }
+    }
+
+    /// recursively called for each dir in root
+    fn write_dir(&mut self, dir: S::Dir, parent_dir_idx: u32) -> Result<(), WriteISOError> {
+        // (entry, index in the layout, recorded placement)
+        let mut entries = self.source.read_dir(dir)?.into_iter()
+            .map(|entry| {
+                let recorded = self.layout.as_ref().and_then(|l| l.entries.get(&self.iso_path.join(&entry.name)));
+                match recorded {
+                    Some(&(i, placement)) => (entry, Some(i), placement),
+                    None => (entry, None, None),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        // Entries recorded in the layout keep their original order, new entries go after them.
+        entries.sort_by(|(a, a_idx, _), (b, b_idx, _)| match (a_idx, b_idx) {
+            (Some(a), Some(b)) => a.cmp(b),
+            (Some(_), None) => std::cmp::Ordering::Less,
+            (None, Some(_)) => std::cmp::Ordering::Greater,
+            (None, None) => match self.options.sort_order {
+                SortOrder::CaseInsensitive => cmp_case_insensitive(&a.name, &b.name),
+                SortOrder::CaseSensitive => a.name.cmp(&b.name),
+                SortOrder::Unsorted => std::cmp::Ordering::Equal,
+                SortOrder::Modified => a.modified.cmp(&b.modified),
+            },
+        });
+
+        for (SourceEntry { name, kind, .. }, _, placement) in entries {
+            self.iso_path.push(&name);
+            if self.string_offset - self.string_start >= MAX_STRING_TABLE_SIZE {
+                return Err(WriteISOError::FilenameTooLong(self.source.error_path(&self.iso_path)));
+            }
+
+            let name_offset = self.string_offset - self.string_start;
+            let name_len = name.len() as u32;
+            self.fst[self.string_offset as usize..][..name_len as usize].copy_from_slice(name.as_bytes());
+            self.fst[(self.string_offset + name_len) as usize] = 0; // null terminator
+            self.string_offset += name_len + 1;
+
+            match kind {
+                SourceKind::File { file, size } => {
+                    // Keep the recorded offset if the file still fits in its original slot.
+                    let recorded = placement
+                        .filter(|&(offset, recorded_size)| size <= recorded_size && offset as u64 >= self.fs_end);
+
+                    let shared_key = self.source.shared_key(&file);
+                    let contents_key = match self.options.dedup {
+                        true => Some((size, self.source.hash(&file, size)?)),
+                        false => None,
+                    };
+                    let duplicate = shared_key.and_then(|k| self.shared_contents.get(&k).copied())
+                        .or_else(|| contents_key.and_then(|k| self.written_contents.get(&k).copied()));
+
+                    let contents_offset = match (recorded, duplicate) {
+                        (Some((offset, _)), _) => {
+                            // a hand edited layout can place a file where its end can't be addressed
+                            if offset as u64 + size as u64 > u32::MAX as u64 {
+                                return Err(WriteISOError::OffsetOverflow(self.source.error_path(&self.iso_path)));
+                            }
+                            offset
+                        }
+                        (None, Some(offset)) => offset,
+                        (None, None) => {
+                            let default_bits = if self.options.strip_junk { SECTOR_ALIGNMENT } else { FILE_CONTENTS_ALIGNMENT };
+                            let offset = align(self.data_end, self.alignment.bits(&self.iso_path).unwrap_or(default_bits));
+                            if offset + size as u64 > ROM_SIZE as u64 { return Err(WriteISOError::ISOTooLarge); }
+                            self.data_end = offset + size as u64;
+                            offset as u32
+                        }
+                    };
+
+                    if let Some(k) = shared_key {
+                        self.shared_contents.entry(k).or_insert(contents_offset);
+                    }
+                    if let Some(k) = contents_key {
+                        self.written_contents.entry(k).or_insert(contents_offset);
+                    }
+
+                    // entry data
+                    write_u32(&mut self.fst, self.entry_offset, name_offset);
+                    write_u32(&mut self.fst, self.entry_offset+4, contents_offset);
+                    write_u32(&mut self.fst, self.entry_offset+8, size);
+                    self.entry_offset += 0xC;
+
+                    if let Some(log) = self.options.log {
+                        log(LogEntry::File { path: &self.iso_path, offset: contents_offset, size });
+                    }
+
+                    self.files.push(PlacedFile {
+                        iso_path: self.iso_path.clone(),
+                        file,
+                        offset: contents_offset,
+                        size,
+                        shared: recorded.is_none() && duplicate.is_some(),
+                    });
+                }
+                SourceKind::Dir(sub_dir) => {
+                    // entry data
+                    let mut w0 = name_offset.to_be_bytes();
+                    w0[0] = 1; // directory flag
+                    self.fst[self.entry_offset as usize..][..4].copy_from_slice(&w0);
+                    write_u32(&mut self.fst, self.entry_offset+4, parent_dir_idx);
+                    // next idx written later
+                    let next_idx_offset = self.entry_offset + 8;
+                    self.entry_offset += 0xC;
+
+                    if let Some(log) = self.options.log {
+                        log(LogEntry::Dir { path: &self.iso_path });
+                    }
+                    let entry_index = self.entry_offset / 0xC - 1; // 1-based index, so compute after 12 byte increment was added.
+                    self.write_dir(sub_dir, entry_index)?;
+
+                    // Add 1 to fix off by one. These indices are a little weird.
+                    let next_idx = self.entry_offset / 0xC;
+                    write_u32(&mut self.fst, next_idx_offset, next_idx);
+                }
+            }
+            self.iso_path.pop();
+        }
+
+        Ok(())
+    }
+}
+
+fn hash_file(path: &Path, size: u32) -> Result<blake3::Hash, WriteISOError> {
+    use std::io::Read;
+
+    let file = std::fs::File::open(path).map_err(|e| WriteISOError::ReadFileError { path: path.into(), e })?;
+    let mut hasher = blake3::Hasher::new();
+    std::io::copy(&mut file.take(size as u64), &mut hasher).map_err(|e| WriteISOError::ReadFileError { path: path.into(), e })?;
+    Ok(hasher.finalize())
+}
+
+/// Metadata of the entry, or of its target if it is a symlink and `follow_symlinks` is set.
+fn entry_metadata(entry: &std::fs::DirEntry, follow_symlinks: bool) -> Result<std::fs::Metadata, WriteISOError> {
+    let file_type = entry.file_type().map_err(|e| WriteISOError::ReadDirError { path: entry.path(), e })?;
+    let metadata = match (file_type.is_symlink(), follow_symlinks) {
+        (true, true) => std::fs::metadata(entry.path()),
+        (true, false) => return Err(WriteISOError::UnsupportedSymlink(entry.path())),
+        (false, _) => entry.metadata(),
+    };
+    metadata.map_err(|e| WriteISOError::ReadDirError { path: entry.path(), e })
+}
+
+/// returns (entry count, total string length, total file size)
+fn count_entries(path: &Path, follow_symlinks: bool) -> Result<(u32, u32, u64), WriteISOError> {
+    let mut entry_count = 0;
+    let mut total_string_length = 0;
+    let mut total_file_size = 0;
+
+    for entry in std::fs::read_dir(path).map_err(|e| WriteISOError::ReadDirError { path: path.into(), e })? {
+        let entry = entry.map_err(|e| WriteISOError::ReadDirError { path: path.into(), e })?;
+        let metadata = entry_metadata(&entry, follow_symlinks)?;
+        if entry.file_name().len() > MAX_FILENAME_LENGTH && (metadata.is_file() || metadata.is_dir()) {
+            return Err(WriteISOError::FilenameTooLong(entry.path()));
+        }
+        if metadata.is_file() {
+            entry_count += 1;
+            total_string_length += entry.file_name().len() as u32 + 1;
+            total_file_size += metadata.len();
+        } else if metadata.is_dir() {
+            let file_name = entry.file_name();
+            if file_name == "&&systemdata" { continue; }
+
+            entry_count += 1;
+            total_string_length += file_name.len() as u32 + 1;
+            // must realloc due to borrowing issues. No big deal cuz we're IO bottlenecked anyways.
+            let new_path = path.join(&file_name);
+            let (ec, sl, fs) = count_entries(&new_path, follow_symlinks)?;
+            entry_count += ec;
+            total_string_length += sl;
+            total_file_size += fs;
+        }
+    }
+
+    Ok((entry_count, total_string_length, total_file_size))
+}
+
+fn cmp_case_insensitive(a: &str, b: &str) -> std::cmp::Ordering {
+    a.chars()
+        .map(|c| c.to_ascii_lowercase())
+        .cmp(b.chars().map(|c| c.to_ascii_lowercase()))
+}
+
+/// Builds an ISO from files held in memory, rather than a directory on disk.
+///
+/// ```no_run
+/// # fn main() -> Result<(), gc_fst::WriteISOError> {
+/// let mut builder = gc_fst::IsoBuilder::new();
+/// builder
+///     .add_file("audio/se.ssm", vec![0u8; 64])?
+///     .add_dir("empty")?
+///     .set_header(std::fs::read("ISO.hdr").unwrap());
+/// let iso = builder.build()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct IsoBuilder {
+    header: Vec<u8>,
+    apploader: Vec<u8>,
+    dol: Vec<u8>,
+    root: BuilderDir,
+}
+
+#[derive(Clone, Debug, Default)]
+struct BuilderDir {
+    entries: Vec<(String, BuilderEntry)>,
+}
+
+#[derive(Clone, Debug)]
+enum BuilderEntry {
+    File(Vec<u8>),
+    Dir(BuilderDir),
+}
+
+impl Default for IsoBuilder {
+    fn default() -> Self { Self::new() }
+}
+
+impl IsoBuilder {
+    /// Starts with a zeroed header, an apploader with no code, and an empty dol.
+    pub fn new() -> Self {
+        IsoBuilder {
+            header: vec![0u8; 0x2440],
+            apploader: vec![0u8; 0x20],
+            dol: Vec::new(),
+            root: BuilderDir::default(),
+        }
+    }
+
+    /// Sets the contents of 'ISO.hdr'. The dol and fst offsets are overwritten when building.
+    pub fn set_header(&mut self, header: impl Into<Vec<u8>>) -> &mut Self {
+        self.header = header.into();
+        self
+    }
+
+    /// Sets the contents of 'AppLoader.ldr'.
+    pub fn set_apploader(&mut self, apploader: impl Into<Vec<u8>>) -> &mut Self {
+        self.apploader = apploader.into();
+        self
+    }
+
+    /// Sets the contents of 'Start.dol'.
+    pub fn set_dol(&mut self, dol: impl Into<Vec<u8>>) -> &mut Self {
+        self.dol = dol.into();
+        self
+    }
+
+    /// Adds a file at `iso_path`, creating parent directories as needed.
+    ///
+    /// Returns `DuplicateFilename` if an entry with the same name, ignoring case, already exists.
+    pub fn add_file(&mut self, iso_path: impl AsRef<Path>, contents: impl Into<Vec<u8>>) -> Result<&mut Self, WriteISOError> {
+        self.insert(iso_path.as_ref(), BuilderEntry::File(contents.into()))
+    }
+
+    /// Adds an empty directory at `iso_path`, creating parent directories as needed.
+    pub fn add_dir(&mut self, iso_path: impl AsRef<Path>) -> Result<&mut Self, WriteISOError> {
+        self.insert(iso_path.as_ref(), BuilderEntry::Dir(BuilderDir::default()))
+    }
+
+    fn insert(&mut self, iso_path: &Path, entry: BuilderEntry) -> Result<&mut Self, WriteISOError> {
+        use std::path::Component;
+
+        let mut names = Vec::new();
+        for component in iso_path.components() {
+            match component {
+                Component::RootDir | Component::CurDir => (),
+                Component::Normal(name) => {
+                    let name = name.to_str()
+                        .filter(|name| !name.contains('\0'))
+                        .ok_or_else(|| WriteISOError::InvalidFilename(name.to_owned()))?;
+                    if name.len() > MAX_FILENAME_LENGTH {
+                        return Err(WriteISOError::FilenameTooLong(iso_path.to_path_buf()));
+                    }
+                    names.push(name);
+                }
+                _ => return Err(WriteISOError::InvalidFilename(iso_path.as_os_str().to_owned())),
+            }
+        }
+
+        let Some((file_name, parents)) = names.split_last() else {
+            return Err(WriteISOError::InvalidFilename(iso_path.as_os_str().to_owned()));
+        };
+
+        let mut dir = &mut self.root;
+        for (i, &name) in parents.iter().enumerate() {
+            let idx = match dir.entries.iter().position(|(n, _)| cmp_case_insensitive(n, name).is_eq()) {
+                Some(idx) if dir.entries[idx].0 != name => {
+                    return Err(WriteISOError::DuplicateFilename(names[..=i].iter().collect()));
+                }
+                Some(idx) => idx,
+                None => {
+                    dir.entries.push((name.to_string(), BuilderEntry::Dir(BuilderDir::default())));
+                    dir.entries.len() - 1
+                }
+            };
+            dir = match dir.entries[idx].1 {
+                BuilderEntry::Dir(ref mut sub_dir) => sub_dir,
+                BuilderEntry::File(_) => {
+                    return Err(WriteISOError::DuplicateFilename(names[..=i].iter().collect()));
+                }
+            };
+        }
+
+        if dir.entries.iter().any(|(n, _)| cmp_case_insensitive(n, file_name).is_eq()) {
+            return Err(WriteISOError::DuplicateFilename(names.iter().collect()));
+        }
+        dir.entries.push((file_name.to_string(), entry));
+
+        Ok(self)
+    }
+
+    /// Builds the ISO in memory, padded to `ROM_SIZE` like `write_iso`.
+    pub fn build(&self) -> Result<Vec<u8>, WriteISOError> {
+        let mut iso = std::io::Cursor::new(Vec::with_capacity(ROM_SIZE as usize));
+        self.build_to(&mut iso)?;
+        Ok(iso.into_inner())
+    }
+
+    /// Like `build`, but writes to `out`. See `write_iso_to`.
+    pub fn build_to<W: std::io::Write + std::io::Seek>(&self, out: &mut W) -> Result<(), WriteISOError> {
+        use std::io::SeekFrom;
+
+        check_apploader_size(&self.apploader)?;
+        if self.header.len() != 0x2440 { return Err(WriteISOError::InvalidHeaderSize(self.header.len() as u64)); }
+
+        out.seek(SeekFrom::Start(0)).map_err(WriteISOError::WriteFileError)?;
+        let mut pos = 0u64;
+
+        out.write_all(&self.header).map_err(WriteISOError::WriteFileError)?;
+        out.write_all(&self.apploader).map_err(WriteISOError::WriteFileError)?;
+        pos += (self.header.len() + self.apploader.len()) as u64;
+        let rounded_size = align(pos, SEGMENT_ALIGNMENT);
+        pad_to(out, &mut pos, rounded_size)?;
+
+        let dol_offset = pos;
+        out.write_all(&self.dol).map_err(WriteISOError::WriteFileError)?;
+        pos += self.dol.len() as u64;
+        let rounded_size = align(pos, SEGMENT_ALIGNMENT);
+        pad_to(out, &mut pos, rounded_size)?;
+
+        let fst_offset = pos;
+        let (entry_count, total_string_length) = self.root.count_entries();
+        let fs_end = fst_offset + (0xC*(entry_count+1) + total_string_length) as u64;
+        pad_to(out, &mut pos, fs_end)?;
+
+        let options = WriteOptions::default();
+        let mut writer = FstWriter::new(&self.root, &options, entry_count, total_string_length, fs_end);
+        writer.write_dir((PathBuf::new(), &self.root), 0)?;
+
+        let FstWriter { fst, data_end, files, .. } = writer;
+        for file in files.iter().filter(|f| !f.shared) {
+            out.seek(SeekFrom::Start(file.offset as u64)).map_err(WriteISOError::WriteFileError)?;
+            out.write_all(file.file).map_err(WriteISOError::WriteFileError)?;
+        }
+        finish_iso(out, &fst, dol_offset, fst_offset, data_end, true)
+    }
+}
+
+impl BuilderDir {
+    /// returns (entry count, total string length)
+    fn count_entries(&self) -> (u32, u32) {
+        let mut entry_count = 0;
+        let mut total_string_length = 0;
+        for (name, entry) in self.entries.iter() {
+            entry_count += 1;
+            total_string_length += name.len() as u32 + 1;
+            if let BuilderEntry::Dir(dir) = entry {
+                let (ec, sl) = dir.count_entries();
+                entry_count += ec;
+                total_string_length += sl;
+            }
+        }
+        (entry_count, total_string_length)
+    }
+}
+
+/// Like `write_iso`, but reads the root from a zip archive instead of a directory.
+///
+/// If '&&systemdata' is inside a folder in the archive rather than at the top level, that folder is used as the root.
+/// Only the header, apploader, and dol are read from '&&systemdata', so 'layout.txt' is ignored.
+#[cfg(feature = "zip")]
+pub fn write_iso_from_zip<R: std::io::Read + std::io::Seek>(zip: R) -> Result<Vec<u8>, WriteISOError> {
+    use std::io::Read;
+
+    let mut archive = zip::ZipArchive::new(zip).map_err(WriteISOError::ZipError)?;
+
+    let mut entries = Vec::with_capacity(archive.len());
+    for i in 0..archive.len() {
+        let entry = archive.by_index_raw(i).map_err(WriteISOError::ZipError)?;
+        let path = entry.enclosed_name()
+            .ok_or_else(|| WriteISOError::InvalidFilename(entry.name().into()))?;
+        entries.push((path, entry.is_dir(), i));
+    }
+    // parent directories sort before their contents
+    entries.sort_unstable();
+
+    let root = entries.iter()
+        .filter_map(|(path, _, _)| {
+            let systemdata_idx = path.components().position(|c| c.as_os_str() == "&&systemdata")?;
+            Some(path.components().take(systemdata_idx).collect::<PathBuf>())
+        })
+        .min_by_key(|root| root.components().count())
+        .unwrap_or_default();
+
+    let mut builder = IsoBuilder::new();
+    let mut system_files: [Option<Vec<u8>>; 5] = Default::default();
+    const SYSTEM_FILE_NAMES: [&str; 5] = ["ISO.hdr", "boot.bin", "bi2.bin", "AppLoader.ldr", "Start.dol"];
+
+    for (path, is_dir, i) in entries {
+        let Ok(iso_path) = path.strip_prefix(&root) else { continue };
+        if iso_path.as_os_str().is_empty() { continue }
+
+        let system_file_idx = match iso_path.starts_with("&&systemdata") {
+            true => match SYSTEM_FILE_NAMES.iter().position(|name| iso_path == Path::new("&&systemdata").join(name)) {
+                Some(idx) => Some(idx),
+                None => continue,
+            },
+            false => None,
+        };
+
+        if is_dir {
+            builder.add_dir(iso_path)?;
+            continue;
+        }
+
+        let mut file = archive.by_index(i).map_err(WriteISOError::ZipError)?;
+        let mut contents = Vec::with_capacity(file.size() as usize);
+        file.read_to_end(&mut contents)
+            .map_err(|e| WriteISOError::ReadFileError { path: path.clone(), e })?;
+
+        match system_file_idx {
+            Some(idx) => system_files[idx] = Some(contents),
+            None => { builder.add_file(iso_path, contents)?; }
+        }
+    }
+
+    let missing = WriteISOError::MissingSystemFile;
+    let [header, boot_bin, bi2_bin, apploader, dol] = system_files;
+
+    let header = match (header, boot_bin, bi2_bin) {
+        (Some(header), _, _) => header,
+        (None, Some(mut boot_bin), Some(bi2_bin)) => {
+            boot_bin.extend_from_slice(&bi2_bin);
+            boot_bin
+        }
+        (None, Some(_), None) => return Err(missing("bi2.bin")),
+        (None, None, _) => return Err(missing("ISO.hdr")),
+    };
+    builder.set_header(header);
+    builder.set_apploader(apploader.ok_or_else(|| missing("AppLoader.ldr"))?);
+    builder.set_dol(dol.ok_or_else(|| missing("Start.dol"))?);
+
+    builder.build()
+}
+
+/// The entries added to an `IsoBuilder` are read straight from its directories.
+/// Each directory is paired with its path in the ISO, for errors.
+impl<'a> FstSource for &'a BuilderDir {
+    type File = &'a [u8];
+    type Dir = (PathBuf, &'a BuilderDir);
+
+    fn read_dir(&mut self, (path, dir): (PathBuf, &'a BuilderDir)) -> Result<Vec<SourceEntry<Self>>, WriteISOError> {
+        dir.entries.iter()
+            .map(|(name, entry)| Ok(SourceEntry {
+                name: name.clone(),
+                kind: match entry {
+                    BuilderEntry::File(contents) => {
+                        let size = u32::try_from(contents.len()).map_err(|_| WriteISOError::OffsetOverflow(path.join(name)))?;
+                        SourceKind::File { file: contents.as_slice(), size }
+                    }
+                    BuilderEntry::Dir(sub_dir) => SourceKind::Dir((path.join(name), sub_dir)),
+                },
+                modified: None,
+            }))
+            .collect()
+    }
+
+    fn hash(&mut self, contents: &&'a [u8], _size: u32) -> Result<blake3::Hash, WriteISOError> {
+        Ok(blake3::hash(contents))
+    }
+}
+
+pub fn read_iso(iso: &[u8]) -> Result<(), ReadISOError> {
+    read_iso_with_progress(iso, &mut |_| {})
+}
+
+/// Like `read_iso`, but calls `progress` after each file is written.
+pub fn read_iso_with_progress(iso: &[u8], progress: &mut dyn FnMut(Progress)) -> Result<(), ReadISOError> {
+    read_iso_with_options(iso, &ExtractOptions::default(), progress)
+}
+
+#[derive(Clone, Default)]
+pub struct ExtractOptions<'a> {
+    /// Write `&&systemdata/layout.txt`, recording the original FST order and file offsets.
+    /// `write_iso` will preserve these when the file is present.
+    pub write_layout: bool,
+
+    /// Write the header as 'boot.bin' (0x440 bytes) and 'bi2.bin' (0x2000 bytes) instead of 'ISO.hdr',
+    /// matching the layout produced by GCRebuilder and Dolphin.
+    pub split_header: bool,
+
+    /// What to do if the root directory ('./root' for `read_iso`) already contains files.
+    pub mode: ExtractMode,
+
+    /// Whether directories in the FST are extracted as directories.
+    pub layout: ExtractLayout,
+
+    /// Write the FST and its string table unchanged to `&&systemdata/Game.toc`.
+    /// `write_iso` then writes it as is and keeps every file at its recorded offset,
+    /// as long as no files were added, removed, or resized.
+    /// Not written for tgc files, whose FST offsets are shifted.
+    pub extract_toc: bool,
+
+    /// Set the modification times of extracted files and directories one second apart in FST order,
+    /// so rebuilding with `SortOrder::Modified` or listing by date reproduces the original order.
+    /// This is a heuristic rather than part of the format, so anything that touches the files loses the order.
+    /// 'layout.txt' records the order reliably.
+    pub fst_order_mtimes: bool,
+
+    /// Called for each file and directory as it is read from the FST.
+    pub log: Option<&'a dyn Fn(LogEntry)>,
+}
+
+// `log` can't derive Debug
+impl std::fmt::Debug for ExtractOptions<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ExtractOptions")
+            .field("write_layout", &self.write_layout)
+            .field("split_header", &self.split_header)
+            .field("mode", &self.mode)
+            .field("layout", &self.layout)
+            .field("extract_toc", &self.extract_toc)
+            .field("fst_order_mtimes", &self.fst_order_mtimes)
+            .field("log", &self.log.is_some())
+            .finish()
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum ExtractLayout {
+    /// Directories in the FST are created as directories in './root'.
+    #[default]
+    Nested,
+    /// Every file is written directly into './root', named by its path in the FST with the components joined by `separator`,
+    /// such as 'audio__bgm__track01.ast'. Empty directories are not kept.
+    /// `separator` can't be empty, '.' or '..', or contain '/' or '\'.
+    Flat { separator: String },
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum ExtractMode {
+    /// Return `RootDirNotEmpty`.
+    #[default]
+    Fail,
+    /// Remove the root directory and everything in it first.
+    Overwrite,
+    /// Extract into the root directory, replacing files with the same paths and keeping the rest.
+    Merge,
+}
+
+pub fn read_iso_with_options(
+    iso: &[u8],
+    options: &ExtractOptions,
+    progress: &mut dyn FnMut(Progress),
+) -> Result<(), ReadISOError> {
+    read_iso_to(iso, Path::new("."), options, progress)
+}
+
+/// Like `read_iso_with_options`, but extracts into `dir/root` instead of './root'.
+///
+/// Nothing is written outside of 'root', which is created if it doesn't exist,
+/// so passing the wrong directory can't scatter files through it or remove anything else in it.
+/// If 'root' already contains files, `RootDirNotEmpty` is returned unless `options.mode` says otherwise.
+pub fn read_iso_to(
+    iso: &[u8],
+    dir: &Path,
+    options: &ExtractOptions,
+    progress: &mut dyn FnMut(Progress),
+) -> Result<(), ReadISOError> {
+    match detect_format(iso) {
+        IsoFormat::Raw | IsoFormat::Unknown => (),
+        format => return Err(ReadISOError::UnsupportedFormat(format)),
+    }
+
+    // Any size is accepted, since mex makes the iso smaller and some romhacks are larger than ROM_SIZE.
+    // The fst and file contents are checked against the actual length instead.
+    if iso.len() < 0x2440 { return Err(ReadISOError::InvalidISO); }
+
+    let regions = DiscRegions {
+        header_offset: 0,
+        dol_offset: read_u32(iso, HEADER_INFO_OFFSET).ok_or(ReadISOError::InvalidISO)?,
+        fst_offset: read_u32(iso, HEADER_INFO_OFFSET+4).ok_or(ReadISOError::InvalidISO)?,
+        file_offset_shift: 0,
+    };
+    extract_disc(iso, &dir.join("root"), &regions, options, progress)
+}
+
+/// First four bytes of a TGC file.
+pub const TGC_MAGIC: u32 = 0xAE0F38A2;
+
+/// Word at 0x1C of every GameCube disc header.
+pub const GAMECUBE_MAGIC: u32 = 0xC2339F3D;
+
+/// The kind of disc image, as guessed by `detect_format`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IsoFormat {
+    /// A raw GameCube disc image, usually named '.iso' or '.gcm'.
+    Raw,
+    Tgc,
+    Rvz,
+    Wia,
+    /// A raw image with junk data removed by NKit. File offsets don't match the original disc.
+    NKit,
+    /// A compact ISO, storing only the used blocks of the disc.
+    Ciso,
+    /// None of the known magic words were found.
+    Unknown,
+}
+
+/// Guesses the format of a disc image from its magic words. Only the first 0x204 bytes are needed.
+///
+/// ```
+/// use gc_fst::{detect_format, IsoFormat};
+///
+/// assert_eq!(detect_format(b"CISO\0\0\x20\0"), IsoFormat::Ciso);
+/// assert_eq!(detect_format(&[0u8; 0x440]), IsoFormat::Unknown);
+/// ```
+pub fn detect_format(data: &[u8]) -> IsoFormat {
+    if data.starts_with(b"RVZ\x01") { return IsoFormat::Rvz; }
+    if data.starts_with(b"WIA\x01") { return IsoFormat::Wia; }
+    if data.starts_with(b"CISO") { return IsoFormat::Ciso; }
+    if read_u32(data, 0) == Some(TGC_MAGIC) { return IsoFormat::Tgc; }
+
+    if read_u32(data, 0x1C) == Some(GAMECUBE_MAGIC) {
+        // NKit keeps the disc header, so its own magic has to be checked first
+        return match data.get(0x200..0x204) {
+            Some(b"NKIT") => IsoFormat::NKit,
+            _ => IsoFormat::Raw,
+        };
+    }
+
+    IsoFormat::Unknown
+}
+
+/// Extracts a TGC file into "./root", like `read_iso`.
+///
+/// TGCs are small disc images embedded in some GameCube discs, such as demo discs.
+pub fn read_tgc(data: &[u8]) -> Result<(), ReadISOError> {
+    read_tgc_with_options(data, &ExtractOptions::default(), &mut |_| {})
+}
+
+pub fn read_tgc_with_options(
+    data: &[u8],
+    options: &ExtractOptions,
+    progress: &mut dyn FnMut(Progress),
+) -> Result<(), ReadISOError> {
+    if read_u32(data, 0) != Some(TGC_MAGIC) { return Err(ReadISOError::InvalidISO); }
+
+    let word = |offset| read_u32(data, offset).ok_or(ReadISOError::InvalidISO);
+    let header_size = word(0x08)?;
+    let fst_offset = word(0x10)?;
+    let dol_offset = word(0x1C)?;
+    let file_area_offset = word(0x24)?;
+    let file_area_virtual_offset = word(0x34)?;
+
+    // file offsets in the fst are relative to the virtual file area
+    let regions = DiscRegions {
+        header_offset: header_size,
+        dol_offset,
+        fst_offset,
+        file_offset_shift: file_area_virtual_offset.wrapping_sub(file_area_offset),
+    };
+    extract_disc(data, Path::new("./root"), &regions, options, progress)
+}
+
+/// Decompresses a GameCube RVZ or WIA file, Dolphin's compressed disc formats, into the raw ISO.
+///
+/// The result can be passed to `read_iso`. Wii discs and the bzip2 and LZMA compression methods are not supported.
+#[cfg(feature = "rvz")]
+pub fn read_rvz(path: &Path) -> Result<Vec<u8>, ReadRVZError> {
+    let data = std::fs::read(path)?;
+    let data = data.as_slice();
+    let word = |data: &[u8], offset: u32| read_u32(data, offset).ok_or(ReadRVZError::InvalidRVZ);
+
+    let header = data.get(..0x48).ok_or(ReadRVZError::InvalidRVZ)?;
+    let is_rvz = match &header[0..4] {
+        b"RVZ\x01" => true,
+        b"WIA\x01" => false,
+        _ => return Err(ReadRVZError::InvalidRVZ),
+    };
+    let header_2_size = word(header, 0x0C)?;
+    let iso_size = u64::from_be_bytes(header[0x24..0x2C].try_into().unwrap());
+
+    let disc = data.get(0x48..)
+        .and_then(|d| d.get(..header_2_size as usize))
+        .filter(|d| d.len() >= 0xDC)
+        .ok_or(ReadRVZError::InvalidRVZ)?;
+
+    let disc_type = word(disc, 0x00)?;
+    let compression = word(disc, 0x04)?;
+    let chunk_size = word(disc, 0x0C)? as u64;
+    let partition_count = word(disc, 0x90)?;
+    let raw_data_count = word(disc, 0xB4)?;
+    let raw_data_offset = u64::from_be_bytes(disc[0xB8..0xC0].try_into().unwrap());
+    let raw_data_size = word(disc, 0xC0)?;
+    let group_count = word(disc, 0xC4)?;
+    let group_offset = u64::from_be_bytes(disc[0xC8..0xD0].try_into().unwrap());
+    let group_size = word(disc, 0xD0)?;
+
+    if disc_type != 1 || partition_count != 0 { return Err(ReadRVZError::UnsupportedDiscType(disc_type)); }
+    if compression != 0 && compression != 5 { return Err(ReadRVZError::UnsupportedCompression(compression)); }
+    if iso_size > ROM_SIZE as u64 * 2 || chunk_size == 0 { return Err(ReadRVZError::InvalidRVZ); }
+
+    let region = |offset: u64, size: u64| -> Result<&[u8], ReadRVZError> {
+        data.get(offset as usize..)
+            .and_then(|d| d.get(..size as usize))
+            .ok_or(ReadRVZError::InvalidRVZ)
+    };
+    let decompress = |compressed: &[u8]| -> Result<Vec<u8>, ReadRVZError> {
+        use std::io::Read;
+
+        if compression == 0 { return Ok(compressed.to_vec()); }
+        let mut decompressed = Vec::new();
+        ruzstd::decoding::StreamingDecoder::new(compressed)
+            .map_err(|_| ReadRVZError::InvalidRVZ)?
+            .read_to_end(&mut decompressed)
+            .map_err(|_| ReadRVZError::InvalidRVZ)?;
+        Ok(decompressed)
+    };
+
+    let raw_data = decompress(region(raw_data_offset, raw_data_size as u64)?)?;
+    let groups = decompress(region(group_offset, group_size as u64)?)?;
+    let group_entry_size = if is_rvz { 0xC } else { 0x8 };
+    if raw_data.len() < raw_data_count as usize * 0x18 { return Err(ReadRVZError::InvalidRVZ); }
+    if groups.len() < group_count as usize * group_entry_size { return Err(ReadRVZError::InvalidRVZ); }
+
+    let mut iso = vec![0u8; iso_size as usize];
+    let disc_header_size = iso.len().min(0x80);
+    iso[..disc_header_size].copy_from_slice(&disc[0x10..][..disc_header_size]);
+
+    for entry in raw_data.chunks_exact(0x18).take(raw_data_count as usize) {
+        let offset = u64::from_be_bytes(entry[0x00..0x08].try_into().unwrap());
+        let size = u64::from_be_bytes(entry[0x08..0x10].try_into().unwrap());
+        let first_group = word(entry, 0x10)?;
+        let entry_group_count = word(entry, 0x14)?;
+
+        // groups start at the preceding 0x8000 byte boundary
+        let skipped = offset % 0x8000;
+        let start = offset - skipped;
+        let end = offset + size;
+        if end > iso_size { return Err(ReadRVZError::InvalidRVZ); }
+
+        for i in 0..entry_group_count {
+            let group_start = start + i as u64 * chunk_size;
+            if group_start >= end { break; }
+            let group_end = end.min(group_start + chunk_size);
+            let out = &mut iso[group_start as usize..group_end as usize];
+
+            let group = groups.get((first_group as usize + i as usize) * group_entry_size..)
+                .and_then(|g| g.get(..group_entry_size))
+                .ok_or(ReadRVZError::InvalidRVZ)?;
+            let group_data_offset = word(group, 0)? as u64 * 4;
+            let group_data_size = word(group, 4)?;
+
+            // In RVZ, the top bit of the size marks whether this group is compressed
+            let (stored_size, compressed) = match is_rvz {
+                true => (group_data_size & 0x7FFFFFFF, group_data_size & 0x80000000 != 0),
+                false => (group_data_size, compression != 0),
+            };
+            let packed_size = if is_rvz { word(group, 8)? } else { 0 };
+
+            // groups without data are all zeros
+            if stored_size == 0 { out.fill(0); continue; }
+
+            let stored = region(group_data_offset, stored_size as u64)?;
+            let group_data = match compressed {
+                true => decompress(stored)?,
+                false => stored.to_vec(),
+            };
+
+            if packed_size != 0 {
+                unpack_rvz_group(&group_data[..(packed_size as usize).min(group_data.len())], out, group_start)?;
+            } else {
+                let group_data = group_data.get(..out.len()).ok_or(ReadRVZError::InvalidRVZ)?;
+                out.copy_from_slice(group_data);
+            }
+        }
+    }
+
+    // the first 0x80 bytes are stored separately, and take priority over any group containing them
+    iso[..disc_header_size].copy_from_slice(&disc[0x10..][..disc_header_size]);
+
+    Ok(iso)
+}
+
+/// RVZ groups can be packed as runs of stored data and junk data, which is regenerated from a seed.
+#[cfg(feature = "rvz")]
+fn unpack_rvz_group(mut packed: &[u8], out: &mut [u8], mut disc_offset: u64) -> Result<(), ReadRVZError> {
+    let mut written = 0;
+    while written < out.len() {
+        let size = packed.get(..4).ok_or(ReadRVZError::InvalidRVZ)?;
+        let size = u32::from_be_bytes(size.try_into().unwrap());
+        packed = &packed[4..];
+
+        let is_junk = size & 0x80000000 != 0;
+        let size = ((size & 0x7FFFFFFF) as usize).min(out.len() - written);
+        let run = &mut out[written..][..size];
+
+        if is_junk {
+            let seed = packed.get(..0x44).ok_or(ReadRVZError::InvalidRVZ)?;
+            packed = &packed[0x44..];
+            let mut junk = LaggedFibonacci::new(seed.try_into().unwrap());
+            junk.skip((disc_offset % 0x8000) as usize);
+            junk.fill(run);
+        } else {
+            let stored = packed.get(..size).ok_or(ReadRVZError::InvalidRVZ)?;
+            packed = &packed[size..];
+            run.copy_from_slice(stored);
+        }
+
+        written += size;
+        disc_offset += size as u64;
+    }
+
+    Ok(())
+}
+
+/// Generates the junk data used to pad discs, which RVZ stores as a seed.
+#[cfg(feature = "rvz")]
+struct LaggedFibonacci {
+    buffer: [u32; LaggedFibonacci::K],
+    /// byte position in `buffer`
+    position: usize,
+}
+
+#[cfg(feature = "rvz")]
+impl LaggedFibonacci {
+    const K: usize = 521;
+    const J: usize = 32;
+    const SEED_SIZE: usize = 17;
+    const BUFFER_BYTES: usize = Self::K * 4;
+
+    fn new(seed: &[u8; 0x44]) -> Self {
+        let mut buffer = [0u32; Self::K];
+        for (i, word) in seed.chunks_exact(4).enumerate() {
+            buffer[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in Self::SEED_SIZE..Self::K {
+            buffer[i] = (buffer[i - 17] << 23) ^ (buffer[i - 16] >> 9) ^ buffer[i - 1];
+        }
+        // the output skips two bits of each word
+        for x in buffer.iter_mut() {
+            *x = (*x & 0xFF00FFFF) | ((*x >> 2) & 0x00FF0000);
+        }
+
+        let mut lfg = LaggedFibonacci { buffer, position: 0 };
+        for _ in 0..4 { lfg.forward(); }
+        lfg
+    }
+
+    fn forward(&mut self) {
+        for i in 0..Self::J {
+            self.buffer[i] ^= self.buffer[i + Self::K - Self::J];
+        }
+        for i in Self::J..Self::K {
+            self.buffer[i] ^= self.buffer[i - Self::J];
+        }
+    }
+
+    fn skip(&mut self, count: usize) {
+        self.position += count;
+        while self.position >= Self::BUFFER_BYTES {
+            self.forward();
+            self.position -= Self::BUFFER_BYTES;
+        }
+    }
+
+    fn fill(&mut self, mut out: &mut [u8]) {
+        while !out.is_empty() {
+            let n = out.len().min(Self::BUFFER_BYTES - self.position);
+            for (i, b) in out[..n].iter_mut().enumerate() {
+                let p = self.position + i;
+                *b = self.buffer[p / 4].to_be_bytes()[p % 4];
+            }
+            out = &mut out[n..];
+            self.position += n;
+            if self.position == Self::BUFFER_BYTES {
+                self.forward();
+                self.position = 0;
+            }
+        }
+    }
+}
+
+/// Where each part of a disc image is in the data being extracted.
+struct DiscRegions {
+    /// offset of 'ISO.hdr', which is followed by the apploader
+    header_offset: u32,
+    dol_offset: u32,
+    fst_offset: u32,
+    /// subtracted from file offsets in the fst to find their contents
+    file_offset_shift: u32,
+}
+
+fn extract_disc(
+    iso: &[u8],
+    root: &Path,
+    regions: &DiscRegions,
+    options: &ExtractOptions,
+    progress: &mut dyn FnMut(Progress),
+) -> Result<(), ReadISOError> {
+    let fst_offset = regions.fst_offset;
+    let fst = iso.get(fst_offset as usize..).ok_or(ReadISOError::InvalidISO)?;
+    let fst_iter = FstIter::new(fst).map_err(|_| ReadISOError::InvalidISO)?;
+
+    if let ExtractLayout::Flat { ref separator } = options.layout {
+        if separator.is_empty() || separator == "." || separator == ".." || separator.contains(['/', '\\']) {
+            return Err(ReadISOError::InvalidSeparator(separator.clone()));
+        }
+    }
+
+    // write regular files ---------------------------------------------------
+
+    let mut path = root.to_path_buf();
+    
+    let root_not_empty = std::fs::read_dir(&path).is_ok_and(|p| p.count() != 0);
+    match options.mode {
+        ExtractMode::Fail if root_not_empty => return Err(ReadISOError::RootDirNotEmpty),
+        ExtractMode::Overwrite if root_not_empty => std::fs::remove_dir_all(&path).map_err(ReadISOError::CreateDirError)?,
+        _ => (),
+    }
+    std::fs::create_dir_all(&path).map_err(ReadISOError::CreateDirError)?;
+
+    let mut layout = String::new();
+
+    // Directories are created during the walk, files are written afterwards.
+    let mut files = Vec::with_capacity(fst_iter.entry_count() as usize);
+    // output paths already used when flattening
+    let mut flat_names = std::collections::HashSet::new();
+    // every output path in fst order, when setting modification times
+    let mut fst_order = Vec::new();
+
+    for event in fst_iter {
+        match event.map_err(|_| ReadISOError::InvalidISO)? {
+            FstEvent::File { path: iso_file_path, offset: file_offset, size: file_size, .. } => {
+                if !is_safe_fst_path(&iso_file_path) { return Err(ReadISOError::UnsafePath(iso_file_path)); }
+                let out_path = match options.layout {
+                    ExtractLayout::Nested => path.join(&iso_file_path),
+                    ExtractLayout::Flat { ref separator } => {
+                        let components = iso_file_path.iter().map(|c| c.to_string_lossy()).collect::<Vec<_>>();
+                        let out_path = path.join(components.join(separator));
+                        if !flat_names.insert(out_path.clone()) {
+                            return Err(ReadISOError::FlatNameCollision(iso_file_path));
+                        }
+                        out_path
+                    }
+                };
+                if options.write_layout {
+                    layout.push_str(&format!("0x{:x} {} {}\n", file_offset, file_size, layout_path(&iso_file_path)));
+                }
+                if let Some(log) = options.log {
+                    log(LogEntry::File { path: &iso_file_path, offset: file_offset, size: file_size });
+                }
+                let contents_offset = file_offset.wrapping_sub(regions.file_offset_shift);
+                if contents_offset as u64 + file_size as u64 > iso.len() as u64 { return Err(ReadISOError::InvalidISO); }
+                if options.fst_order_mtimes {
+                    fst_order.push((out_path.clone(), false));
+                }
+                files.push((contents_offset, file_size, out_path));
+            }
+            FstEvent::EnterDir { path: iso_dir_path, .. } => {
+                if !is_safe_fst_path(&iso_dir_path) { return Err(ReadISOError::UnsafePath(iso_dir_path)); }
+                if options.layout == ExtractLayout::Nested {
+                    let out_path = path.join(&iso_dir_path);
+                    std::fs::create_dir_all(&out_path).map_err(ReadISOError::CreateDirError)?;
+                    if options.fst_order_mtimes {
+                        fst_order.push((out_path, true));
+                    }
+                }
+                if options.write_layout {
+                    layout.push_str(&format!("- - {}/\n", layout_path(&iso_dir_path)));
+                }
+                if let Some(log) = options.log {
+                    log(LogEntry::Dir { path: &iso_dir_path });
+                }
+            }
+            FstEvent::ExitDir => (),
+        }
+    }
+
+    let bytes_total = files.iter().map(|(_, size, _)| *size as u64).sum();
+    let mut progress = ProgressTracker { callback: progress, bytes_done: 0, bytes_total };
+    write_extracted_files(iso, &files, &mut progress).map_err(ReadISOError::WriteFileError)?;
+
+    // Done once everything is written, since writing a file changes its directory's modification time.
+    // Fixed times starting at the GameCube's release keep extractions reproducible.
+    let first_mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1000425600);
+    for (i, (out_path, is_dir)) in fst_order.iter().enumerate() {
+        let mtime = first_mtime + std::time::Duration::from_secs(i as u64);
+        match is_dir {
+            true => {
+                // Directories can't be opened this way on every platform, so their times are best effort.
+                let _ = std::fs::File::open(out_path).and_then(|f| f.set_modified(mtime));
+            }
+            false => {
+                std::fs::File::options().write(true).open(out_path)
+                    .and_then(|f| f.set_modified(mtime))
+                    .map_err(ReadISOError::WriteFileError)?;
+            }
+        }
+    }
+    
+    // write special (&&systemdata) files ------------------------------------
+
+    path.clear();
+    path.push(root);
+    path.push("&&systemdata");
+    std::fs::create_dir_all(&path).map_err(ReadISOError::CreateDirError)?;
+
+    let header = iso.get(regions.header_offset as usize..)
+        .filter(|header| header.len() >= 0x2460)
+        .ok_or(ReadISOError::InvalidISO)?;
+
+    if options.split_header {
+        path.push("boot.bin");
+        std::fs::write(&path, &header[0..0x440])
+            .map_err(ReadISOError::WriteFileError)?;
+        path.pop();
+
+        path.push("bi2.bin");
+        std::fs::write(&path, &header[0x440..0x2440])
+            .map_err(ReadISOError::WriteFileError)?;
+        path.pop();
+    } else {
+        path.push("ISO.hdr");
+        std::fs::write(&path, &header[0..0x2440])
+            .map_err(ReadISOError::WriteFileError)?;
+        path.pop();
+    }
+
+    if options.write_layout {
+        path.push("layout.txt");
+        std::fs::write(&path, &layout)
+            .map_err(ReadISOError::WriteFileError)?;
+        path.pop();
+    }
+
+    if options.extract_toc && regions.file_offset_shift == 0 {
+        let fs_size = u32::from_be_bytes(header[0x428..0x42C].try_into().unwrap());
+        let toc = fst.get(..fs_size as usize).ok_or(ReadISOError::InvalidISO)?;
+        path.push("Game.toc");
+        std::fs::write(&path, toc)
+            .map_err(ReadISOError::WriteFileError)?;
+        path.pop();
+    }
+
+    path.push("AppLoader.ldr");
+    let apploader_total_size = header.get(0x2440..)
+        .and_then(AppLoader::parse)
+        .ok_or(ReadISOError::InvalidISO)?
+        .total_size();
+    let apploader = header.get(0x2440..)
+        .and_then(|apploader| apploader.get(..apploader_total_size as usize))
+        .ok_or(ReadISOError::InvalidISO)?;
+    std::fs::write(&path, apploader)
+        .map_err(ReadISOError::WriteFileError)?;
+    path.pop();
+
+    path.push("Start.dol");
+    let dol_offset = regions.dol_offset;
+    let dol = iso.get(dol_offset as usize..).and_then(Dol::parse).ok_or(ReadISOError::InvalidISO)?;
+    let dol_bytes = iso.get(dol_offset as usize..)
+        .and_then(|dol_bytes| dol_bytes.get(..dol.total_size() as usize))
+        .ok_or(ReadISOError::InvalidISO)?;
+    std::fs::write(&path, dol_bytes)
+        .map_err(ReadISOError::WriteFileError)?;
+    path.pop();
+
+    // Record the space the apploader and dol took up, so padding after them is kept when rebuilding.
+    let sizes = SystemSizes::from_offsets(
+        regions.header_offset + 0x2440,
+        dol_offset,
+        regions.fst_offset,
+        apploader_total_size,
+        dol.total_size(),
+    );
+    path.push("sizes.txt");
+    std::fs::write(&path, sizes.write())
+        .map_err(ReadISOError::WriteFileError)?;
+    path.pop();
+
+    // Game.toc is only written when asked for.
+    // The point of exporting the fs is to modify, add, and remove files,
+    // which means we usually have to recreate the table of contents anyways when rebuilding the iso.
+
+    Ok(())
+}
+
+#[cfg(not(feature = "parallel"))]
+fn write_extracted_files(
+    iso: &[u8],
+    files: &[(u32, u32, PathBuf)],
+    progress: &mut ProgressTracker,
+) -> std::io::Result<()> {
+    for (offset, size, path) in files {
+        std::fs::write(path, &iso[*offset as usize..][..*size as usize])?;
+        progress.advance(*size as u64, path);
+    }
+
+    Ok(())
+}
+
+/// Output paths are distinct, so files can be written concurrently.
+/// Progress is reported from the calling thread as files complete.
+#[cfg(feature = "parallel")]
+fn write_extracted_files(
+    iso: &[u8],
+    files: &[(u32, u32, PathBuf)],
+    progress: &mut ProgressTracker,
+) -> std::io::Result<()> {
+    use rayon::prelude::*;
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::scope(|s| {
+        let writer = s.spawn(move || {
+            files.par_iter().try_for_each_with(tx, |tx, (offset, size, path)| {
+                std::fs::write(path, &iso[*offset as usize..][..*size as usize])?;
+                // the receiver only goes away once every file is written
+                let _ = tx.send((*size, path));
+                Ok(())
+            })
+        });
+
+        for (size, path) in rx {
+            progress.advance(size as u64, path);
+        }
+
+        writer.join().unwrap()
+    })
+}
+
+/// Whether every component of a path from the FST is a plain name, so joining it to a directory stays inside that directory.
+/// Names in the FST are arbitrary bytes, so they can start with '/' or be '..'.
+fn is_safe_fst_path(path: &Path) -> bool {
+    path.components().all(|c| matches!(c, std::path::Component::Normal(_) | std::path::Component::CurDir))
+}
+
+/// path in the extracted root, '/' separated
+fn layout_path(path: &Path) -> String {
+    let path = path.strip_prefix("./root/").unwrap_or(path);
+    let components = path.iter().map(|c| c.to_string_lossy()).collect::<Vec<_>>();
+    components.join("/")
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FstError {
+    /// The FST is smaller than its root entry.
+    TooSmall,
+    /// The root entry's count doesn't fit in the FST.
+    EntryCountOutOfRange { entry_count: u32 },
+    /// An entry is out of bounds, or its name is out of bounds, unterminated, not valid UTF-8,
+    /// empty, or contains '/' or '\'.
+    InvalidEntry { entry_index: u32 },
+    /// A directory's end index is not after the directory, or is past the end of its parent directory.
+    InvalidNextIndex { entry_index: u32, next_idx: u32 },
+}
+
+/// An entry encountered while walking the FST with `FstIter`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FstEvent<'a> {
+    File { path: PathBuf, name: &'a str, offset: u32, size: u32 },
+    EnterDir { path: PathBuf, name: &'a str },
+    /// The most recently entered directory has ended.
+    ExitDir,
+}
+
+/// Walks the entries of an FST in order, tracking the current directory.
+///
+/// Every `EnterDir` is matched by an `ExitDir`, including directories at the end of the FST.
+/// Iteration stops after the first error.
+///
+/// ```
+/// use gc_fst::{FstIter, FstEvent, FstError};
+/// use std::path::PathBuf;
+///
+/// // root (3 entries), "a/" containing "b", then "c"
+/// let mut fst = vec![
+///     1, 0, 0, 0,  0, 0, 0, 0,  0, 0, 0, 4,
+///     1, 0, 0, 0,  0, 0, 0, 0,  0, 0, 0, 3,
+///     0, 0, 0, 2,  0, 0, 0x80, 0,  0, 0, 0, 5,
+///     0, 0, 0, 4,  0, 0, 0x90, 0,  0, 0, 0, 6,
+/// ];
+/// fst.extend_from_slice(b"a\0b\0c\0");
+///
+/// let events = FstIter::new(&fst).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+/// assert_eq!(events, [
+///     FstEvent::EnterDir { path: PathBuf::from("a"), name: "a" },
+///     FstEvent::File { path: PathBuf::from("a/b"), name: "b", offset: 0x8000, size: 5 },
+///     FstEvent::ExitDir,
+///     FstEvent::File { path: PathBuf::from("c"), name: "c", offset: 0x9000, size: 6 },
+/// ]);
+///
+/// // names must be a single path component
+/// for bad_name in [b"/", b"\\", b"\0"] {
+///     let mut bad_fst = fst.clone();
+///     bad_fst[0x30] = bad_name[0];
+///     let events = FstIter::new(&bad_fst).unwrap().collect::<Vec<_>>();
+///     assert_eq!(events[0], Err(FstError::InvalidEntry { entry_index: 1 }));
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct FstIter<'a> {
+    fst: &'a [u8],
+    entry_count: u32,
+    string_table_offset: u32,
+    entry_index: u32,
+    dir_end_indices: Vec<u32>,
+    path: PathBuf,
+    done: bool,
+}
+
+impl<'a> FstIter<'a> {
+    /// `fst` starts at the root entry and includes the string table.
+    pub fn new(fst: &'a [u8]) -> Result<Self, FstError> {
+        let entry_count = read_u32(fst, 8).ok_or(FstError::TooSmall)?;
+        if entry_count == 0 || entry_count as u64 * 0xC > fst.len() as u64 {
+            return Err(FstError::EntryCountOutOfRange { entry_count });
+        }
+
+        Ok(FstIter {
+            fst,
+            entry_count,
+            string_table_offset: entry_count * 0xC,
+            entry_index: 1,
+            dir_end_indices: Vec::with_capacity(8),
+            path: PathBuf::with_capacity(64),
+            done: false,
+        })
+    }
+
+    /// Finds the FST using the offset in the header of `iso`.
+    pub fn from_iso(iso: &'a [u8]) -> Result<Self, FstError> {
+        let fst_offset = read_u32(iso, HEADER_INFO_OFFSET+4).ok_or(FstError::TooSmall)?;
+        Self::new(iso.get(fst_offset as usize..).ok_or(FstError::TooSmall)?)
+    }
+
+    /// Number of entries in the FST, including the root.
+    pub fn entry_count(&self) -> u32 { self.entry_count }
+
+    /// Returns whether the entry is a file, its name, and its other two words,
+    /// or `None` if the entry or its name is out of bounds, or the name is not valid UTF-8 or not a single path component.
+    fn entry(&self, entry_index: u32) -> Option<(bool, &'a str, u32, u32)> {
+        let offset = entry_index.checked_mul(0xC)?;
+        let w0 = read_u32(self.fst, offset)?;
+        let w1 = read_u32(self.fst, offset+4)?;
+        let w2 = read_u32(self.fst, offset+8)?;
+
+        let is_file = w0 >> 24 == 0;
+        let name = read_filename(self.fst, self.string_table_offset.checked_add(w0 & 0xFFFFFF)?)?;
+        if name.is_empty() || name.contains(['/', '\\']) { return None; }
+        Some((is_file, name, w1, w2))
+    }
+}
+
+impl<'a> Iterator for FstIter<'a> {
+    type Item = Result<FstEvent<'a>, FstError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done { return None; }
+
+        if let Some(&dir_end) = self.dir_end_indices.last() {
+            if dir_end == self.entry_index {
+                self.dir_end_indices.pop();
+                self.path.pop();
+                return Some(Ok(FstEvent::ExitDir));
+            }
+        }
+        if self.entry_index >= self.entry_count {
+            self.done = true;
+            return None;
+        }
+
+        let entry_index = self.entry_index;
+        self.entry_index += 1;
+
+        let (is_file, name, w1, w2) = match self.entry(entry_index) {
+            Some(entry) => entry,
+            None => {
+                self.done = true;
+                return Some(Err(FstError::InvalidEntry { entry_index }));
+            }
+        };
+
+        self.path.push(name);
+
+        if is_file {
+            let event = FstEvent::File {
+                path: self.path.clone(),
+                name,
+                offset: w1,
+                size: w2,
+            };
+            self.path.pop();
+            Some(Ok(event))
+        } else {
+            // w1 is the parent index, which is unused
+            let parent_end = self.dir_end_indices.last().copied().unwrap_or(self.entry_count);
+            if w2 <= entry_index || w2 > parent_end {
+                self.done = true;
+                return Some(Err(FstError::InvalidNextIndex { entry_index, next_idx: w2 }));
+            }
+            self.dir_end_indices.push(w2);
+            Some(Ok(FstEvent::EnterDir { path: self.path.clone(), name }))
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum IsoOp<'a> {
+    Insert { iso_path: &'a Path, input_path: &'a Path },
+    /// Like `Insert`, but the contents come from memory instead of a file.
+    InsertBytes { iso_path: &'a Path, data: &'a [u8] },
+    /// Like `Insert`, but the contents are placed at `offset`, for mods which read from hardcoded disc offsets.
+    /// Returns `OffsetOccupied` if the file would overlap another file, the header, apploader, dol, or fst.
+    InsertAt { iso_path: &'a Path, input_path: &'a Path, offset: u32 },
+    Delete { iso_path: &'a Path },
+    /// Moves a file within the ISO. The file contents are not moved.
+    /// A file already at `to` is replaced, like `Insert`.
+    Rename { from: &'a Path, to: &'a Path },
+    /// Creates a directory and any missing parents.
+    /// GameCube FSTs can legitimately contain empty directories, and these are kept.
+    Mkdir { iso_path: &'a Path },
+    /// Inserts every file and directory in `input_dir` into the directory `iso_path`, like `Insert` and `Mkdir`.
+    /// Directories which already exist in the ISO are merged, and files with the same path are replaced.
+    InsertDir { iso_path: &'a Path, input_dir: &'a Path },
+}
+
+/// Where the contents of an inserted file come from.
+#[derive(Copy, Clone, Debug)]
+enum InsertionSource<'a> {
+    File(&'a Path),
+    Bytes(&'a [u8]),
+}
+
+impl InsertionSource<'_> {
+    fn len(&self) -> Result<u64, OperateISOError> {
+        match *self {
+            InsertionSource::File(path) => Ok(path.metadata()?.len()),
+            InsertionSource::Bytes(data) => Ok(data.len() as u64),
+        }
+    }
+
+    fn to_planned(self) -> PlannedSource {
+        match self {
+            InsertionSource::File(path) => PlannedSource::File(path.to_path_buf()),
+            InsertionSource::Bytes(data) => PlannedSource::Bytes(data.to_vec()),
+        }
+    }
+}
+
+/// An `InsertionSource` kept in an `OperationPlan` until it is written.
+#[derive(Clone, Debug)]
+enum PlannedSource {
+    File(PathBuf),
+    Bytes(Vec<u8>),
+}
+
+impl PlannedSource {
+    /// Opens the contents for reading. Done before anything is written, so a missing file doesn't leave the ISO half written.
+    fn open(&self) -> Result<Box<dyn std::io::Read + '_>, OperateISOError> {
+        match self {
+            PlannedSource::File(path) => {
+                let f = std::fs::File::options()
+                    .read(true)
+                    .open(path)
+                    .map_err(|e| OperateISOError::OpenError { path: path.into(), e })?;
+                Ok(Box::new(f))
+            }
+            PlannedSource::Bytes(data) => Ok(Box::new(data.as_slice())),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+enum FsEntry<'a> {
+    PushDir { name: &'a str },
+    PopDir,
+    File {
         name: &'a str,
         offset: u32,
         size: u32,
     }
 }
 
+// Searches the directory starting at fs[0], stopping at the end of that directory.
 fn find_dir(fs: &[FsEntry], entry: &std::ffi::OsStr) -> Option<usize> {
     let mut i = 0;
     while i < fs.len() {
         match fs[i] {
             FsEntry::PushDir { name } if name == entry => return Some(i),
+            FsEntry::PopDir => return None,
             FsEntry::PushDir { .. } => {
                 let mut depth = 1;
 
@@ -553,17 +3365,38 @@ fn find_dir(fs: &[FsEntry], entry: &std::ffi::OsStr) -> Option<usize> {
 }
 
 // returns index to insert file at
+/// Whether a file named `entry` is directly in the directory whose entries start at the start of `fs`.
+fn has_file(fs: &[FsEntry], entry: &std::ffi::OsStr) -> bool {
+    let mut depth = 0;
+    for e in fs {
+        match *e {
+            FsEntry::File { name, .. } if depth == 0 && name == entry => return true,
+            FsEntry::File { .. } => (),
+            FsEntry::PushDir { .. } => depth += 1,
+            FsEntry::PopDir if depth == 0 => return false,
+            FsEntry::PopDir => depth -= 1,
+        }
+    }
+
+    false
+}
+
 fn mkdir_all<'a>(fs: &mut Vec<FsEntry<'a>>, dir_path: &'a Path) -> Result<usize, OperateISOError> {
     let mut folder_insert_idx = 0;
 
     let mut components = dir_path.components();
-    while let Some(component) = components.next() {
+    for component in components.by_ref() {
         let dir_name = match component {
             std::path::Component::Normal(dir) => dir,
             std::path::Component::RootDir => continue,
             _ => return Err(OperateISOError::InvalidISOPath(dir_path.to_path_buf())),
         };
 
+        // a directory can't have the same name as a file beside it
+        if has_file(&fs[folder_insert_idx..], dir_name) {
+            return Err(OperateISOError::InvalidISOPath(dir_path.to_path_buf()));
+        }
+
         if let Some(i) = find_dir(&fs[folder_insert_idx..], dir_name) {
             folder_insert_idx += i + 1;
         } else {
@@ -591,12 +3424,45 @@ fn mkdir_all<'a>(fs: &mut Vec<FsEntry<'a>>, dir_path: &'a Path) -> Result<usize,
     Ok(folder_insert_idx)
 }
 
-struct FilePortion<'a> {
-    iso: &'a mut std::fs::File,
+/// Collects the directories and files beneath `input_dir` for `IsoOp::InsertDir`, in sorted order.
+fn walk_insert_dir(
+    input_dir: &Path,
+    iso_dir: &Path,
+    dirs: &mut Vec<PathBuf>,
+    files: &mut Vec<(PathBuf, PathBuf)>,
+) -> Result<(), OperateISOError> {
+    let open_error = |e| OperateISOError::OpenError { path: input_dir.into(), e };
+
+    let mut entries = std::fs::read_dir(input_dir)
+        .map_err(open_error)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(open_error)?;
+    entries.sort();
+
+    for input_path in entries {
+        let name = input_path.file_name()
+            .filter(|name| name.to_str().is_some())
+            .ok_or_else(|| OperateISOError::InvalidFSPath(input_path.clone()))?;
+        let iso_path = iso_dir.join(name);
+
+        if input_path.metadata()?.is_dir() {
+            dirs.push(iso_path.clone());
+            walk_insert_dir(&input_path, &iso_path, dirs, files)?;
+        } else {
+            files.push((iso_path, input_path));
+        }
+    }
+
+    Ok(())
+}
+
+struct FilePortion<R> {
+    iso: R,
     size: usize,
 }
 
-impl<'a> std::io::Read for FilePortion<'a> {
+impl<R: std::io::Read> std::io::Read for FilePortion<R> {
     fn read(&mut self, mut buf: &mut [u8]) -> std::io::Result<usize> {
         if self.size == 0 { return Ok(0); }
 
@@ -611,8 +3477,112 @@ impl<'a> std::io::Read for FilePortion<'a> {
     }
 }
 
-pub fn read_iso_files(iso_path: &Path, files: &[(&Path, &Path)]) -> Result<(), ReadISOFilesError> {
+/// Maps the whole ISO into memory so file portions can be sliced directly.
+#[cfg(feature = "mmap")]
+fn map_iso(iso: &std::fs::File) -> Option<memmap2::Mmap> {
+    // SAFETY: the mapping is only read, and only for the duration of the call that created it.
+    // Another process truncating the ISO at the same time is not something we can guard against.
+    unsafe { memmap2::Mmap::map(iso) }.ok()
+}
+
+#[cfg(not(feature = "mmap"))]
+fn map_iso(_iso: &std::fs::File) -> Option<&'static [u8]> { None }
+
+/// Returns the offset and size of 'ISO.hdr', 'boot.bin', 'bi2.bin', 'AppLoader.ldr', or 'Start.dol'.
+fn special_file_portion(
+    iso: &mut std::fs::File,
+    iso_file_path: &Path,
+    dol_offset: u32,
+    fst_offset: u32,
+) -> Result<Option<(u32, u32)>, ReadISOFilesError> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    match iso_file_path.to_str() {
+        Some("ISO.hdr") => Ok(Some((0, 0x2440))),
+        Some("boot.bin") => Ok(Some((0, 0x440))),
+        Some("bi2.bin") => Ok(Some((0x440, 0x2000))),
+        Some("AppLoader.ldr") => {
+            iso.seek(SeekFrom::Start(0x2440))?;
+            let mut buf = [0u8; AppLoader::HEADER_SIZE];
+            iso.read_exact(&mut buf)?;
+            let size = AppLoader::parse(&buf).unwrap().total_size();
+            let size = u32::try_from(size).map_err(|_| ReadISOFilesError::InvalidISO)?;
+            Ok(Some((0x2440, size)))
+        }
+        Some("Start.dol") => {
+            if (fst_offset as u64) < dol_offset as u64 + Dol::HEADER_SIZE as u64 { return Err(ReadISOFilesError::InvalidISO); }
+
+            iso.seek(SeekFrom::Start(dol_offset as _))?;
+            let mut buf = [0u8; Dol::HEADER_SIZE];
+            iso.read_exact(&mut buf)?;
+
+            let dol = Dol::parse(&buf).ok_or(ReadISOFilesError::InvalidISO)?;
+            let size = dol.total_size();
+            if size > fst_offset - dol_offset { return Err(ReadISOFilesError::InvalidISO); }
+
+            Ok(Some((dol_offset, size)))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Writes 'ISO.hdr', 'AppLoader.ldr', 'Start.dol', and 'sizes.txt' into `out_dir/&&systemdata`, like `read_iso`,
+/// without extracting the rest of the filesystem.
+pub fn extract_system_files(iso_path: &Path, out_dir: &Path) -> Result<(), ReadISOFilesError> {
+    extract_system_files_with_options(iso_path, out_dir, &ExtractOptions::default())
+}
+
+/// Like `extract_system_files`, writing 'boot.bin' and 'bi2.bin' instead of 'ISO.hdr' if `options.split_header` is set.
+/// `options.write_layout` and `options.extract_toc` are ignored.
+pub fn extract_system_files_with_options(iso_path: &Path, out_dir: &Path, options: &ExtractOptions) -> Result<(), ReadISOFilesError> {
     use std::io::{Read, Seek, SeekFrom};
+
+    let mut iso = std::fs::File::open(iso_path)?;
+
+    let mut buf = [0u8; 8];
+    iso.seek(SeekFrom::Start(HEADER_INFO_OFFSET as _))?;
+    iso.read_exact(&mut buf)?;
+    let dol_offset = u32::from_be_bytes(buf[0..4].try_into().unwrap());
+    let fst_offset = u32::from_be_bytes(buf[4..8].try_into().unwrap());
+
+    let path = out_dir.join("&&systemdata");
+    std::fs::create_dir_all(&path)?;
+
+    let header_files: &[&str] = match options.split_header {
+        true => &["boot.bin", "bi2.bin"],
+        false => &["ISO.hdr"],
+    };
+
+    // returns the size of the file written
+    let mut copy = |name: &str| -> Result<u32, ReadISOFilesError> {
+        let (offset, size) = special_file_portion(&mut iso, Path::new(name), dol_offset, fst_offset)?
+            .ok_or(ReadISOFilesError::InvalidISO)?;
+        iso.seek(SeekFrom::Start(offset as _))?;
+        let mut contents = vec![0u8; size as usize];
+        iso.read_exact(&mut contents).map_err(|_| ReadISOFilesError::InvalidISO)?;
+        std::fs::write(path.join(name), &contents)?;
+        Ok(size)
+    };
+
+    for name in header_files { copy(name)?; }
+    let apploader_size = copy("AppLoader.ldr")?;
+    let dol_size = copy("Start.dol")?;
+
+    let sizes = SystemSizes::from_offsets(0x2440, dol_offset, fst_offset, apploader_size as u64, dol_size);
+    std::fs::write(path.join("sizes.txt"), sizes.write())?;
+
+    Ok(())
+}
+
+/// Extracts each `(path in iso, output path)` pair.
+///
+/// If the path in the iso is a directory, every file beneath it is extracted, recreating the subtree under the output path.
+/// '/' is the root directory, so it extracts the whole filesystem.
+///
+/// Returns each requested path in the iso with whether it was found.
+/// Paths which were not found are skipped, and nothing is written for them.
+pub fn read_iso_files(iso_path: &Path, files: &[(&Path, &Path)]) -> Result<Vec<(PathBuf, bool)>, ReadISOFilesError> {
+    use std::io::{Read, Write, Seek, SeekFrom};
     let mut iso = std::fs::File::options()
         .read(true)
         .open(iso_path)?;
@@ -626,126 +3596,620 @@ pub fn read_iso_files(iso_path: &Path, files: &[(&Path, &Path)]) -> Result<(), R
     let fst_offset = u32::from_be_bytes(buf[4..8].try_into().unwrap());
     let fs_size = u32::from_be_bytes(buf[8..12].try_into().unwrap());
 
-    let mut u32_buf = [0u8; 4];
-    iso.seek(SeekFrom::Start((fst_offset + 8) as _))?;
-    iso.read_exact(&mut u32_buf)?;
-    let entry_count = u32::from_be_bytes(u32_buf);
-
-    let string_table_offset = fst_offset + entry_count * 0xC;
-    let entry_start_offset = fst_offset + 0xC;
+    let mut found = vec![false; files.len()];
 
     // read special files ---------------------------------------------------
 
-    for (iso_file_path, out_path) in files.iter() {
-        if *iso_file_path == Path::new("ISO.hdr") {
+    for (i, (iso_file_path, out_path)) in files.iter().enumerate() {
+        if let Some((offset, size)) = special_file_portion(&mut iso, iso_file_path, dol_offset, fst_offset)? {
+            found[i] = true;
             let mut f = std::fs::File::options()
                 .create(true)
                 .write(true)
+                .truncate(true)
                 .open(out_path)?;
-            iso.seek(SeekFrom::Start(0))?;
-            let mut portion = FilePortion { iso: &mut iso, size: 0x2440 };
+            iso.seek(SeekFrom::Start(offset as _))?;
+            let mut portion = FilePortion { iso: &mut iso, size: size as _ };
             std::io::copy(&mut portion, &mut f)?;
         }
+    }
 
-        if *iso_file_path == Path::new("AppLoader.ldr") {
-            iso.seek(SeekFrom::Start(0x2454))?;
-            let mut buf = [0u8; 8];
-            iso.read_exact(&mut buf)?;
-            let apploader_code_size = u32::from_be_bytes(buf[0..4].try_into().unwrap());
-            let apploader_trailer_size = u32::from_be_bytes(buf[4..8].try_into().unwrap());
-            let size = align(apploader_code_size + apploader_trailer_size, 5) as usize;
+    // read iso fs ------------------------------------------------------------
 
-            let mut f = std::fs::File::options()
-                .create(true)
-                .write(true)
-                .open(out_path)?;
-            iso.seek(SeekFrom::Start(0x2440))?;
-            let mut portion = FilePortion { iso: &mut iso, size };
-            std::io::copy(&mut portion, &mut f)?;
+    iso.seek(SeekFrom::Start(fst_offset as _))?;
+    let mut fst = vec![0u8; fs_size as usize];
+    iso.read_exact(&mut fst)?;
+
+    // falls back to seeking and copying if the mmap feature is disabled or mapping fails
+    let map = map_iso(&iso);
+
+    let fst_iter = FstIter::new(&fst).map_err(|_| ReadISOFilesError::InvalidISO)?;
+
+    // the root directory has no `EnterDir` event
+    for (i, (iso_file_path, out_path)) in files.iter().enumerate() {
+        if iso_file_path.strip_prefix("/").is_ok_and(|p| p.as_os_str().is_empty()) {
+            found[i] = true;
+            std::fs::create_dir_all(out_path)?;
+        }
+    }
+
+    for event in fst_iter {
+        match event.map_err(|_| ReadISOFilesError::InvalidISO)? {
+            FstEvent::EnterDir { path, .. } => {
+                // requested directories and the directories beneath them are created even if they are empty
+                for (i, (iso_file_path, out_path)) in files.iter().enumerate() {
+                    let iso_file_path = iso_file_path.strip_prefix("/").unwrap_or(iso_file_path);
+                    if let Ok(rel) = path.strip_prefix(iso_file_path) {
+                        found[i] = true;
+                        std::fs::create_dir_all(out_path.join(rel))?;
+                    }
+                }
+            }
+            FstEvent::File { path, offset: file_offset, size: file_size, .. } => {
+                for (i, (iso_file_path, out_path)) in files.iter().enumerate() {
+                    // a leading '/' refers to the root of the iso filesystem
+                    let iso_file_path = iso_file_path.strip_prefix("/").unwrap_or(iso_file_path);
+
+                    // if a directory was requested, every file beneath it is extracted into out_path
+                    let out_path = match path.strip_prefix(iso_file_path) {
+                        Ok(rel) if rel.as_os_str().is_empty() => out_path.to_path_buf(),
+                        Ok(rel) => out_path.join(rel),
+                        Err(_) => continue,
+                    };
+                    found[i] = true;
+
+                    if let Some(dirs) = out_path.ancestors().nth(1) {
+                        std::fs::create_dir_all(dirs)?;
+                    }
+                    let mut f = std::fs::File::options()
+                        .create(true)
+                        .write(true)
+                        .truncate(true)
+                        .open(&out_path)?;
+
+                    let mapped = map.as_ref().and_then(|m| m.get(file_offset as usize..)?.get(..file_size as usize));
+                    if let Some(contents) = mapped {
+                        f.write_all(contents)?;
+                    } else {
+                        iso.seek(SeekFrom::Start(file_offset as _))?;
+                        let mut portion = FilePortion { iso: &mut iso, size: file_size as _ };
+                        std::io::copy(&mut portion, &mut f)?;
+                    }
+                }
+            }
+            FstEvent::ExitDir => (),
+        }
+    }
+
+    Ok(files.iter().zip(found).map(|((iso_file_path, _), found)| (iso_file_path.to_path_buf(), found)).collect())
+}
+
+/// Extracts every file whose path in the iso matches `pattern` into `out_dir`, keeping its path below `out_dir`.
+///
+/// In the pattern, `*` matches any part of a name, `?` matches any single character, and a `**` component matches any number of directories,
+/// so `audio/*.ast` matches files directly in 'audio' and `**/*.tpl` matches every '.tpl' file.
+/// Matching is case sensitive, and a leading '/' is ignored like in `read_iso_files`.
+/// Only files in the filesystem are matched, not 'ISO.hdr', 'AppLoader.ldr', or 'Start.dol'.
+///
+/// Returns the paths in the iso of the extracted files, in fst order.
+pub fn read_iso_glob(iso_path: &Path, pattern: &str, out_dir: &Path) -> Result<Vec<PathBuf>, ReadISOFilesError> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let mut iso = std::fs::File::open(iso_path)?;
+
+    let mut buf = [0u8; 12];
+    iso.seek(SeekFrom::Start(HEADER_INFO_OFFSET as _))?;
+    iso.read_exact(&mut buf)?;
+    let fst_offset = u32::from_be_bytes(buf[4..8].try_into().unwrap());
+    let fs_size = u32::from_be_bytes(buf[8..12].try_into().unwrap());
+
+    let pattern = pattern.strip_prefix('/').unwrap_or(pattern).split('/').collect::<Vec<_>>();
+
+    let mut extracted = Vec::new();
+    for (path, offset, size) in read_fst_files(&mut iso, fst_offset, fs_size)? {
+        let components = path.iter().map(|c| c.to_string_lossy()).collect::<Vec<_>>();
+        let components = components.iter().map(|c| c.as_ref()).collect::<Vec<_>>();
+        if !glob_matches(&pattern, &components) { continue; }
+
+        let out_path = out_dir.join(&path);
+        if let Some(dirs) = out_path.parent() {
+            std::fs::create_dir_all(dirs)?;
+        }
+        let mut f = std::fs::File::create(&out_path)?;
+        iso.seek(SeekFrom::Start(offset as _))?;
+        let mut portion = FilePortion { iso: &mut iso, size: size as _ };
+        std::io::copy(&mut portion, &mut f)?;
+        extracted.push(path);
+    }
+
+    Ok(extracted)
+}
+
+/// Whether the path components match the pattern components, each name matched with `glob_match`.
+/// A `**` pattern component matches any number of path components, backtracking the same way as `glob_match` does for '*'.
+fn glob_matches(pattern: &[&str], path: &[&str]) -> bool {
+    let mut p = 0;
+    let mut i = 0;
+    // position after the last '**' and the path component it is currently matching up to
+    let mut backtrack = None;
+
+    while i < path.len() {
+        match pattern.get(p) {
+            Some(&"**") => {
+                p += 1;
+                backtrack = Some((p, i));
+            }
+            Some(name) if glob_match(name.as_bytes(), path[i].as_bytes()) => {
+                p += 1;
+                i += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_i)) => {
+                    p = star_p;
+                    i = star_i + 1;
+                    backtrack = Some((star_p, star_i + 1));
+                }
+                None => return false,
+            }
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == "**")
+}
+
+/// Returns the path, offset, and size of every file in the fst, in fst order.
+fn read_fst_files(iso: &mut std::fs::File, fst_offset: u32, fs_size: u32) -> Result<Vec<(PathBuf, u32, u32)>, ReadISOFilesError> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    if fs_size < 0xC { return Err(ReadISOFilesError::InvalidISO); }
+
+    let mut fst = vec![0u8; fs_size as usize];
+    iso.seek(SeekFrom::Start(fst_offset as _))?;
+    iso.read_exact(&mut fst)?;
+
+    let iter = FstIter::new(&fst).map_err(|_| ReadISOFilesError::InvalidISO)?;
+    let mut files = Vec::with_capacity(iter.entry_count() as usize);
+    for event in iter {
+        match event.map_err(|_| ReadISOFilesError::InvalidISO)? {
+            FstEvent::File { path, offset, size, .. } => files.push((path, offset, size)),
+            FstEvent::EnterDir { .. } | FstEvent::ExitDir => (),
+        }
+    }
+
+    Ok(files)
+}
+
+/// Returns the offset of the first 'opening.bnr' in the filesystem, searching subdirectories if it isn't in the root.
+///
+/// Returns `InvalidISO` if the file is too small or doesn't start with 'BNR1' or 'BNR2', so it is never written to by mistake.
+fn find_banner(iso: &mut std::fs::File) -> Result<u32, ReadISOFilesError> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let mut buf = [0u8; 12];
+    iso.seek(SeekFrom::Start(HEADER_INFO_OFFSET as _))?;
+    iso.read_exact(&mut buf)?;
+    let fst_offset = u32::from_be_bytes(buf[4..8].try_into().unwrap());
+    let fs_size = u32::from_be_bytes(buf[8..12].try_into().unwrap());
+
+    let (_, offset, size) = read_fst_files(iso, fst_offset, fs_size)?
+        .into_iter()
+        .filter(|(path, _, _)| path.file_name().is_some_and(|name| name.eq_ignore_ascii_case("opening.bnr")))
+        .min_by_key(|(path, _, _)| path.components().count())
+        .ok_or_else(|| ReadISOFilesError::InvalidFSPath(PathBuf::from("opening.bnr")))?;
+    if size < 0x1960 { return Err(ReadISOFilesError::InvalidISO); }
+
+    let mut magic = [0u8; 4];
+    iso.seek(SeekFrom::Start(offset as _))?;
+    iso.read_exact(&mut magic)?;
+    if &magic != b"BNR1" && &magic != b"BNR2" { return Err(ReadISOFilesError::InvalidISO); }
+
+    Ok(offset)
+}
+
+/// Reads the banner image from the first 'opening.bnr' in the filesystem, searching subdirectories if it isn't in the root.
+pub fn extract_banner(iso_path: &Path) -> Result<RGB5A1Image, ReadISOFilesError> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let mut iso = std::fs::File::open(iso_path)?;
+    let offset = find_banner(&mut iso)?;
+
+    let mut image = Box::new([0u8; 0x1800]);
+    iso.seek(SeekFrom::Start(offset as u64 + 0x20))?;
+    iso.read_exact(&mut *image)?;
+    Ok(RGB5A1Image(image))
+}
+
+/// Replaces the banner image in the same 'opening.bnr' that `extract_banner` reads, keeping its titles and descriptions.
+///
+/// The image is always the same size, so it is written in place.
+/// Returns `InvalidISO` without writing anything if the file is not a valid banner.
+pub fn replace_banner_image(iso_path: &Path, image: &RGB5A1Image) -> Result<(), OperateISOError> {
+    use std::io::{Write, Seek, SeekFrom};
+
+    let mut iso = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .open(iso_path)
+        .map_err(|e| OperateISOError::OpenError { path: iso_path.into(), e })?;
+
+    let offset = find_banner(&mut iso).map_err(|e| match e {
+        ReadISOFilesError::IOError(e) => OperateISOError::IOError(e),
+        ReadISOFilesError::InvalidISO => OperateISOError::InvalidISO,
+        ReadISOFilesError::InvalidFSPath(path) => OperateISOError::InvalidFSPath(path),
+    })?;
+
+    iso.seek(SeekFrom::Start(offset as u64 + 0x20))?;
+    iso.write_all(&*image.0)?;
+    Ok(())
+}
+
+/// Computes the blake3 hash of each file without extracting it.
+///
+/// The special files 'ISO.hdr', 'boot.bin', 'bi2.bin', 'AppLoader.ldr', and 'Start.dol' are also accepted.
+/// If `files` is empty, every file in the filesystem is hashed.
+pub fn hash_iso_files(iso_path: &Path, files: &[&Path]) -> Result<Vec<(PathBuf, [u8; 32])>, ReadISOFilesError> {
+    use std::io::{Read, Seek, SeekFrom};
+    let mut iso = std::fs::File::options()
+        .read(true)
+        .open(iso_path)?;
+
+    // read header ---------------------------------------------------------
+
+    let mut buf = [0u8; 12];
+    iso.seek(SeekFrom::Start(HEADER_INFO_OFFSET as _))?;
+    iso.read_exact(&mut buf)?;
+    let dol_offset = u32::from_be_bytes(buf[0..4].try_into().unwrap());
+    let fst_offset = u32::from_be_bytes(buf[4..8].try_into().unwrap());
+    let fs_size = u32::from_be_bytes(buf[8..12].try_into().unwrap());
+
+    let fst_files = read_fst_files(&mut iso, fst_offset, fs_size)?;
+
+    let mut portions = Vec::with_capacity(files.len());
+    for iso_file in files.iter() {
+        let portion = match special_file_portion(&mut iso, iso_file, dol_offset, fst_offset)? {
+            Some(portion) => portion,
+            None => {
+                // a leading '/' refers to the root of the iso filesystem
+                let iso_file_path = iso_file.strip_prefix("/").unwrap_or(iso_file);
+                fst_files.iter()
+                    .find(|(path, _, _)| path == iso_file_path)
+                    .map(|&(_, offset, size)| (offset, size))
+                    .ok_or_else(|| ReadISOFilesError::InvalidFSPath(iso_file.into()))?
+            }
+        };
+        portions.push((iso_file.to_path_buf(), portion.0, portion.1));
+    }
+    if files.is_empty() { portions = fst_files; }
+
+    // hash ------------------------------------------------------------------
+
+    let mut hashes = Vec::with_capacity(portions.len());
+    for (path, offset, size) in portions {
+        let hash = hash_portion(&mut iso, offset, size)?;
+        hashes.push((path, *hash.as_bytes()));
+    }
+
+    Ok(hashes)
+}
+
+fn hash_portion(iso: &mut std::fs::File, offset: u32, size: u32) -> Result<blake3::Hash, ReadISOFilesError> {
+    use std::io::{Seek, SeekFrom};
+
+    iso.seek(SeekFrom::Start(offset as _))?;
+    let mut hasher = blake3::Hasher::new();
+    let hashed = std::io::copy(&mut FilePortion { iso, size: size as _ }, &mut hasher)?;
+    if hashed != size as u64 { return Err(ReadISOFilesError::InvalidISO); }
+    Ok(hasher.finalize())
+}
+
+/// Files which differ between two ISOs, in FST order.
+#[derive(Clone, Debug, Default)]
+pub struct IsoDiff {
+    /// files only in the second ISO
+    pub added: Vec<PathBuf>,
+    /// files only in the first ISO
+    pub removed: Vec<PathBuf>,
+    /// files in both ISOs with different sizes or contents
+    pub changed: Vec<PathBuf>,
+}
+
+/// Compares the files in two ISOs by path, including 'ISO.hdr', 'AppLoader.ldr', and 'Start.dol'.
+///
+/// File offsets are ignored, so rebuilt ISOs with the same files compare equal.
+pub fn diff_isos(a: &Path, b: &Path) -> Result<IsoDiff, ReadISOFilesError> {
+    let mut a_iso = std::fs::File::open(a)?;
+    let mut b_iso = std::fs::File::open(b)?;
+
+    let a_files = read_all_portions(&mut a_iso)?;
+    let b_files = read_all_portions(&mut b_iso)?;
+
+    let a_paths = a_files.iter().map(|(path, ..)| path).collect::<std::collections::HashSet<_>>();
+    let b_portions = b_files.iter()
+        .map(|(path, offset, size)| (path, (*offset, *size)))
+        .collect::<std::collections::HashMap<_, _>>();
+
+    let mut diff = IsoDiff::default();
+    for (path, a_offset, a_size) in a_files.iter() {
+        match b_portions.get(path) {
+            None => diff.removed.push(path.clone()),
+            Some(&(_, b_size)) if b_size != *a_size => diff.changed.push(path.clone()),
+            Some(&(b_offset, b_size)) => {
+                if hash_portion(&mut a_iso, *a_offset, *a_size)? != hash_portion(&mut b_iso, b_offset, b_size)? {
+                    diff.changed.push(path.clone());
+                }
+            }
+        }
+    }
+    diff.added = b_files.iter()
+        .map(|(path, ..)| path)
+        .filter(|path| !a_paths.contains(path))
+        .cloned()
+        .collect();
+
+    Ok(diff)
+}
+
+/// Returns the path, offset, and size of 'ISO.hdr', 'AppLoader.ldr', 'Start.dol', and every file in the fst.
+fn read_all_portions(iso: &mut std::fs::File) -> Result<Vec<(PathBuf, u32, u32)>, ReadISOFilesError> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let mut buf = [0u8; 12];
+    iso.seek(SeekFrom::Start(HEADER_INFO_OFFSET as _))?;
+    iso.read_exact(&mut buf)?;
+    let dol_offset = u32::from_be_bytes(buf[0..4].try_into().unwrap());
+    let fst_offset = u32::from_be_bytes(buf[4..8].try_into().unwrap());
+    let fs_size = u32::from_be_bytes(buf[8..12].try_into().unwrap());
+
+    let mut portions = Vec::new();
+    for name in ["ISO.hdr", "AppLoader.ldr", "Start.dol"] {
+        if let Some((offset, size)) = special_file_portion(iso, Path::new(name), dol_offset, fst_offset)? {
+            portions.push((PathBuf::from(name), offset, size));
         }
+    }
+    portions.extend(read_fst_files(iso, fst_offset, fs_size)?);
+
+    Ok(portions)
+}
+
+/// Whole-disc checksums, in the formats used by Redump and Dolphin.
+#[cfg(feature = "hashes")]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct IsoHashes {
+    pub crc32: u32,
+    pub md5: [u8; 16],
+    pub sha1: [u8; 20],
+}
+
+/// Computes the CRC32, MD5, and SHA-1 of an entire file in a single pass, without reading it all into memory.
+#[cfg(feature = "hashes")]
+pub fn iso_hashes(path: &Path) -> Result<IsoHashes, std::io::Error> {
+    use std::io::Read;
+    use md5::Digest;
+
+    let mut file = std::fs::File::open(path)?;
+    let mut crc32 = crc32fast::Hasher::new();
+    let mut md5 = md5::Md5::new();
+    let mut sha1 = sha1::Sha1::new();
+
+    let mut buf = vec![0u8; 1 << 20];
+    loop {
+        let n = match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        crc32.update(&buf[..n]);
+        md5.update(&buf[..n]);
+        sha1.update(&buf[..n]);
+    }
+
+    Ok(IsoHashes {
+        crc32: crc32.finalize(),
+        md5: md5.finalize().into(),
+        sha1: sha1.finalize().into(),
+    })
+}
+
+/// Computes `iso_hashes` for each ISO, such as to check a library of rebuilt ISOs against a datfile.
+///
+/// Returns the results in the same order as `paths`.
+/// With the `parallel` feature, up to `LIBRARY_HASH_THREADS` ISOs are hashed at once.
+#[cfg(feature = "hashes")]
+pub fn verify_library(paths: &[&Path]) -> Vec<(PathBuf, Result<IsoHashes, std::io::Error>)> {
+    hash_library(paths)
+}
+
+/// Maximum number of ISOs hashed at once by `verify_library`.
+/// Hashing is fast enough that more threads would mostly fight over the disk.
+#[cfg(feature = "hashes")]
+pub const LIBRARY_HASH_THREADS: usize = 4;
 
-        if *iso_file_path == Path::new("Start.dol") {
-            iso.seek(SeekFrom::Start(dol_offset as _))?;
-            let mut buf = vec![0u8; (fst_offset-dol_offset) as usize];
-            iso.read_exact(&mut buf)?;
+#[cfg(all(feature = "hashes", not(feature = "parallel")))]
+fn hash_library(paths: &[&Path]) -> Vec<(PathBuf, Result<IsoHashes, std::io::Error>)> {
+    paths.iter().map(|path| (path.to_path_buf(), iso_hashes(path))).collect()
+}
 
-            let mut size = 0usize;
-            for i in 0..18 {
-                let segment_offset = read_u32(&buf, i*4);
-                let segment_size = read_u32(&buf, 0x90 + i*4);
-                let seg_end = segment_offset+segment_size;
-                size = size.max(seg_end as usize);
-            }
+#[cfg(all(feature = "hashes", feature = "parallel"))]
+fn hash_library(paths: &[&Path]) -> Vec<(PathBuf, Result<IsoHashes, std::io::Error>)> {
+    use rayon::prelude::*;
 
-            std::fs::write(out_path, &buf[..size])?;
-        }
+    let hash = |path: &&Path| (path.to_path_buf(), iso_hashes(path));
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get()).min(LIBRARY_HASH_THREADS);
+    match rayon::ThreadPoolBuilder::new().num_threads(threads).build() {
+        Ok(pool) => pool.install(|| paths.par_iter().map(hash).collect()),
+        Err(_) => paths.iter().map(hash).collect(),
     }
+}
 
-    // read iso fs ------------------------------------------------------------
+/// A summary of an ISO, returned by `iso_info`.
+#[derive(Clone, Debug)]
+pub struct IsoInfo {
+    pub game_id: [u8; 6],
+    /// The whole name field from the header, like `IsoHeader::full_title`.
+    pub title: String,
+    /// The region value in bi2.bin (0 = Japan, 1 = USA, 2 = PAL, 4 = Korea).
+    pub region: u32,
+    pub file_count: u32,
+    /// The header, apploader, dol, and fst, plus the contents of every file.
+    pub used_bytes: u64,
+    /// The path and size of the largest file, or `None` if the ISO has no files.
+    pub largest_file: Option<(PathBuf, u32)>,
+    pub fs_size: u32,
+    /// `ROM_SIZE` minus `used_bytes`.
+    pub free_bytes: u64,
+}
 
-    let string_table_offset_in_buf = string_table_offset - entry_start_offset; 
-    iso.seek(SeekFrom::Start(entry_start_offset as _))?;
-    let mut buf = vec![0u8; fs_size as usize];
-    iso.read_exact(&mut buf)?;
+/// Reads the header and walks the fst to summarize an ISO without extracting it.
+pub fn iso_info(iso_path: &Path) -> Result<IsoInfo, ReadISOFilesError> {
+    use std::io::Read;
+
+    let mut iso = std::fs::File::open(iso_path)?;
+
+    let mut header = [0u8; 0x45C];
+    iso.read_exact(&mut header).map_err(|_| ReadISOFilesError::InvalidISO)?;
+    let fst_offset = read_u32(&header, HEADER_INFO_OFFSET+4).ok_or(ReadISOFilesError::InvalidISO)?;
+    let fs_size = read_u32(&header, HEADER_INFO_OFFSET+8).ok_or(ReadISOFilesError::InvalidISO)?;
+    let IsoHeader { game_id, full_title: title, region_code: region, .. } = IsoHeader::parse(&header);
+
+    let files = read_fst_files(&mut iso, fst_offset, fs_size)?;
+    let data_bytes = files.iter().map(|(_, _, size)| *size as u64).sum::<u64>();
+    let used_bytes = fst_offset as u64 + fs_size as u64 + data_bytes;
+    let largest_file = files.iter()
+        .max_by_key(|(_, _, size)| *size)
+        .map(|(path, _, size)| (path.clone(), *size));
+
+    Ok(IsoInfo {
+        game_id,
+        title,
+        region,
+        file_count: files.len() as u32,
+        used_bytes,
+        largest_file,
+        fs_size,
+        free_bytes: (ROM_SIZE as u64).saturating_sub(used_bytes),
+    })
+}
 
-    let mut dir_end_indices = Vec::with_capacity(8);
-    let mut offset = 0;
-    let mut entry_index = 1;
+/// Reads a single file from the ISO into memory.
+///
+/// The special files 'ISO.hdr', 'boot.bin', 'bi2.bin', 'AppLoader.ldr', and 'Start.dol' are also accepted.
+pub fn read_iso_file_bytes(iso_path: &Path, iso_file: &Path) -> Result<Vec<u8>, ReadISOFilesError> {
+    use std::io::{Read, Seek, SeekFrom};
+    let mut iso = std::fs::File::options()
+        .read(true)
+        .open(iso_path)?;
 
-    let mut string_table_end = 0;
+    let (offset, size) = find_iso_file(&mut iso, iso_file)?;
+    let mut contents = Vec::with_capacity(size as usize);
+    iso.seek(SeekFrom::Start(offset as _))?;
+    FilePortion { iso: &mut iso, size: size as _ }.read_to_end(&mut contents)?;
+    if contents.len() != size as usize { return Err(ReadISOFilesError::InvalidISO); }
 
-    let mut path = PathBuf::with_capacity(32);
+    Ok(contents)
+}
 
-    while offset < string_table_offset_in_buf {
-        while Some(entry_index) == dir_end_indices.last().copied() {
-            // dir has ended
-            dir_end_indices.pop();
-            path.pop();
-        }
+/// Opens a single file in the ISO for streaming, without reading the whole FST or the file into memory.
+///
+/// The special files 'ISO.hdr', 'boot.bin', 'bi2.bin', 'AppLoader.ldr', and 'Start.dol' are also accepted.
+/// The reader ends early if the ISO is truncated.
+pub fn open_iso_file(iso_path: &Path, iso_file: &Path) -> Result<impl std::io::Read, ReadISOFilesError> {
+    use std::io::{Seek, SeekFrom};
+    let mut iso = std::fs::File::options()
+        .read(true)
+        .open(iso_path)?;
 
-        let is_file = buf[offset as usize] == 0;
+    let (offset, size) = find_iso_file(&mut iso, iso_file)?;
+    iso.seek(SeekFrom::Start(offset as _))?;
+    Ok(FilePortion { iso, size: size as _ })
+}
 
-        let mut name_offset_buf = [0; 4];
-        name_offset_buf[1] = buf[offset as usize+1];
-        name_offset_buf[2] = buf[offset as usize+2];
-        name_offset_buf[3] = buf[offset as usize+3];
-        let name_offset = u32::from_be_bytes(name_offset_buf);
-        let name = read_filename(&buf, string_table_offset_in_buf + name_offset)
-            .ok_or(ReadISOFilesError::InvalidISO)?;
+/// Returns the offset and size of a file or special file in the ISO,
+/// seeking to each FST entry along the path instead of reading the whole FST.
+fn find_iso_file(iso: &mut std::fs::File, iso_file: &Path) -> Result<(u32, u32), ReadISOFilesError> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let mut buf = [0u8; 12];
+    iso.seek(SeekFrom::Start(HEADER_INFO_OFFSET as _))?;
+    iso.read_exact(&mut buf)?;
+    let dol_offset = u32::from_be_bytes(buf[0..4].try_into().unwrap());
+    let fst_offset = u32::from_be_bytes(buf[4..8].try_into().unwrap());
+    let fs_size = u32::from_be_bytes(buf[8..12].try_into().unwrap());
 
-        let string_len = name.len() as u32 + 1;
-        string_table_end = string_table_end.max(name_offset+string_len);
+    if let Some(portion) = special_file_portion(iso, iso_file, dol_offset, fst_offset)? {
+        return Ok(portion);
+    }
 
-        path.push(name);
-        if is_file {
-            let file_offset = read_u32(&buf, offset+4);
-            let file_size = read_u32(&buf, offset+8);
+    let not_found = || ReadISOFilesError::InvalidFSPath(iso_file.into());
+
+    let mut read_entry = |iso: &mut std::fs::File, entry_index: u32| -> Result<(u32, u32, u32), ReadISOFilesError> {
+        let entry_offset = entry_index as u64 * 0xC;
+        if entry_offset + 0xC > fs_size as u64 { return Err(ReadISOFilesError::InvalidISO); }
+        iso.seek(SeekFrom::Start(fst_offset as u64 + entry_offset))?;
+        iso.read_exact(&mut buf)?;
+        Ok((
+            u32::from_be_bytes(buf[0..4].try_into().unwrap()),
+            u32::from_be_bytes(buf[4..8].try_into().unwrap()),
+            u32::from_be_bytes(buf[8..12].try_into().unwrap()),
+        ))
+    };
 
-            for (iso_file_path, out_path) in files {
-                if *iso_file_path == path.as_path() {
-                    if let Some(dirs) = out_path.ancestors().nth(1) {
-                        std::fs::create_dir_all(dirs)?;
-                    }
-                    let mut f = std::fs::File::options()
-                        .create(true)
-                        .write(true)
-                        .open(out_path)?;
-                    iso.seek(SeekFrom::Start(file_offset as _))?;
-                    let mut portion = FilePortion { iso: &mut iso, size: file_size as _ };
-                    std::io::copy(&mut portion, &mut f)?;
+    let (_, _, entry_count) = read_entry(iso, 0)?;
+    if entry_count as u64 * 0xC > fs_size as u64 { return Err(ReadISOFilesError::InvalidISO); }
+    let string_table_offset = fst_offset as u64 + entry_count as u64 * 0xC;
+    let string_table_end = fst_offset as u64 + fs_size as u64;
+
+    // a leading '/' refers to the root of the iso filesystem
+    let mut components = iso_file.components()
+        .filter(|c| !matches!(c, std::path::Component::RootDir))
+        .peekable();
+
+    // the entries of the directory being searched
+    let mut entry_index = 1;
+    let mut dir_end = entry_count;
+
+    while let Some(component) = components.next() {
+        let name = match component {
+            std::path::Component::Normal(name) => name.to_str().ok_or_else(not_found)?,
+            _ => return Err(not_found()),
+        };
+        let is_last = components.peek().is_none();
+
+        loop {
+            if entry_index >= dir_end { return Err(not_found()); }
+
+            let (w0, w1, w2) = read_entry(iso, entry_index)?;
+            let is_file = w0 >> 24 == 0;
+
+            let name_offset = string_table_offset + (w0 & 0xFFFFFF) as u64;
+            let name_len = name.len() as u64 + 1;
+            let matches = name_offset + name_len <= string_table_end && {
+                let mut entry_name = vec![0u8; name_len as usize];
+                iso.seek(SeekFrom::Start(name_offset))?;
+                iso.read_exact(&mut entry_name)?;
+                entry_name[..name.len()] == *name.as_bytes() && entry_name[name.len()] == 0
+            };
+
+            match (matches, is_file) {
+                (true, true) if is_last => return Ok((w1, w2)),
+                (true, false) if !is_last => {
+                    // w2 is the index after the last entry in this directory
+                    if w2 <= entry_index || w2 > dir_end { return Err(ReadISOFilesError::InvalidISO); }
+                    entry_index += 1;
+                    dir_end = w2;
+                    break;
+                }
+                (true, _) => return Err(not_found()),
+                (false, true) => entry_index += 1,
+                (false, false) => {
+                    if w2 <= entry_index || w2 > dir_end { return Err(ReadISOFilesError::InvalidISO); }
+                    entry_index = w2;
                 }
             }
-            path.pop();
-        } else {
-            let next_idx = read_u32(&buf, offset+8);
-            dir_end_indices.push(next_idx);
         }
-
-        offset += 0xC;
-        entry_index += 1;
     }
 
-    Ok(())
+    Err(not_found())
 }
 
 #[derive(Debug)]
@@ -765,10 +4229,6 @@ pub fn tree_iso(
 ) -> Result<(), TreeISOError> {
     use std::io::{Read, Seek, SeekFrom};
 
-    let iso_meta = iso_path.metadata()?;
-
-    if iso_meta.len() > ROM_SIZE as _ { return Err(TreeISOError::InvalidISO); }
-
     let mut iso = std::fs::File::options()
         .read(true)
         .open(iso_path)
@@ -782,132 +4242,224 @@ pub fn tree_iso(
     let fst_offset = u32::from_be_bytes(buf[4..8].try_into().unwrap());
     let fs_size = u32::from_be_bytes(buf[8..12].try_into().unwrap());
 
-    let mut u32_buf = [0u8; 4];
-    iso.seek(SeekFrom::Start((fst_offset + 8) as _))?;
-    iso.read_exact(&mut u32_buf)?;
-    let entry_count = u32::from_be_bytes(u32_buf);
-
-    let string_table_offset = fst_offset + entry_count * 0xC;
-    let entry_start_offset = fst_offset + 0xC;
-
     // read iso fs ------------------------------------------------------------
 
-    let string_table_offset_in_buf = string_table_offset - entry_start_offset; 
-    iso.seek(SeekFrom::Start(entry_start_offset as _))?;
-    let mut buf = vec![0u8; fs_size as usize];
-    iso.read_exact(&mut buf)?;
-
-    let mut dir_end_indices = Vec::with_capacity(8);
-    let mut offset = 0;
-    let mut entry_index = 1;
-    let mut path_in_iso = PathBuf::with_capacity(64);
-
-    while offset < string_table_offset_in_buf {
-        while Some(entry_index) == dir_end_indices.last().copied() {
-            // dir has ended
-            dir_end_indices.pop();
-            path_in_iso.pop();
-        }
+    iso.seek(SeekFrom::Start(fst_offset as _))?;
+    let mut fst = vec![0u8; fs_size as usize];
+    iso.read_exact(&mut fst)?;
 
-        let is_file = buf[offset as usize] == 0;
+    let mut depth = 0;
 
-        let mut name_offset_buf = [0; 4];
-        name_offset_buf[1] = buf[offset as usize+1];
-        name_offset_buf[2] = buf[offset as usize+2];
-        name_offset_buf[3] = buf[offset as usize+3];
-        let name_offset = u32::from_be_bytes(name_offset_buf);
-        let name = read_filename(&buf, string_table_offset_in_buf + name_offset)
-            .ok_or(TreeISOError::InvalidISO)?;
+    for event in FstIter::new(&fst).map_err(|_| TreeISOError::InvalidISO)? {
+        match event.map_err(|_| TreeISOError::InvalidISO)? {
+            FstEvent::File { path, name, offset: file_offset, size: file_size } => {
+                if options.print_files {
+                    if options.print_full_paths {
+                        print!("{}", path.display());
+                    } else {
+                        print!("{}", name);
+                    }
 
-        if is_file {
-            if options.print_files {
-                let file_offset = read_u32(&buf, offset+4);
-                let file_size = read_u32(&buf, offset+8);
-
-                if options.print_full_paths {
-                    path_in_iso.push(name);
-                    print!("{}", path_in_iso.display());
-                    path_in_iso.pop();
-                } else {
-                    print!("{}", name);
-                }
+                    if options.print_file_offsets {
+                        if options.print_hex {
+                            print!("\t0x{:x}", file_offset);
+                        } else {
+                            print!("\t{}", file_offset);
+                        }
+                    }
 
-                if options.print_file_offsets {
-                    if options.print_hex {
-                        print!("\t0x{:x}", file_offset);
-                    } else {
-                        print!("\t{}", file_offset);
+                    if options.print_file_sizes {
+                        if options.print_hex {
+                            print!("\t0x{:x}", file_size);
+                        } else {
+                            print!("\t{}", file_size);
+                        }
                     }
+
+                    println!();
                 }
+            }
+            FstEvent::EnterDir { path, name } => {
+                if options.print_directories {
+                    print!("{:s<1$}", "", depth * 2);
 
-                if options.print_file_sizes {
-                    if options.print_hex {
-                        print!("\t0x{:x}", file_size);
+                    if options.print_full_paths {
+                        println!("{}{}", path.display(), std::path::MAIN_SEPARATOR);
                     } else {
-                        print!("\t{}", file_size);
+                        println!("{}{}", name, std::path::MAIN_SEPARATOR);
                     }
                 }
 
-                println!();
+                depth += 1;
             }
-        } else {
-            path_in_iso.push(name);
+            FstEvent::ExitDir => depth -= 1,
+        }
+    }
 
-            if options.print_directories {
-                print!("{:s<1$}", "", dir_end_indices.len() * 2);
+    Ok(())
+}
 
-                if options.print_full_paths {
-                    println!("{}{}", path_in_iso.display(), std::path::MAIN_SEPARATOR);
-                } else {
-                    println!("{}{}", name, std::path::MAIN_SEPARATOR);
+#[derive(Clone, Debug)]
+pub enum VerifyWarning {
+    DolOutOfBounds { dol_offset: u32 },
+    FstOutOfBounds { fst_offset: u32, fs_size: u32 },
+    EntryCountOutOfRange { entry_count: u32 },
+    FileOutOfBounds { path: PathBuf, offset: u32, size: u32 },
+    FilesOverlap { a: PathBuf, b: PathBuf },
+    NextIndexOutOfRange { path: PathBuf, next_idx: u32 },
+    /// The entry or its name is out of bounds, or the name is unterminated, not valid UTF-8, empty, or contains a path separator.
+    UnterminatedName { entry_index: u32 },
+}
+
+impl VerifyWarning {
+    /// Overlapping files can still be read, everything else means the ISO is corrupt.
+    pub fn is_fatal(&self) -> bool {
+        !matches!(self, VerifyWarning::FilesOverlap { .. })
+    }
+}
+
+/// Checks that the FST is consistent with itself and the size of the ISO.
+///
+/// Checking stops at the first invalid entry or directory, as the rest of the FST can't be walked reliably.
+pub fn verify_iso(iso_path: &Path) -> Result<Vec<VerifyWarning>, ReadISOFilesError> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let mut warnings = Vec::new();
+
+    let iso_len = iso_path.metadata()?.len();
+    if iso_len < 0x2440 { return Err(ReadISOFilesError::InvalidISO); }
+
+    let mut iso = std::fs::File::options()
+        .read(true)
+        .open(iso_path)?;
+
+    // read header ---------------------------------------------------------
+
+    let mut buf = [0u8; 12];
+    iso.seek(SeekFrom::Start(HEADER_INFO_OFFSET as _))?;
+    iso.read_exact(&mut buf)?;
+    let dol_offset = u32::from_be_bytes(buf[0..4].try_into().unwrap());
+    let fst_offset = u32::from_be_bytes(buf[4..8].try_into().unwrap());
+    let fs_size = u32::from_be_bytes(buf[8..12].try_into().unwrap());
+
+    if dol_offset as u64 + Dol::HEADER_SIZE as u64 > iso_len {
+        warnings.push(VerifyWarning::DolOutOfBounds { dol_offset });
+    }
+
+    if fst_offset as u64 + fs_size as u64 > iso_len || fs_size < 0xC {
+        warnings.push(VerifyWarning::FstOutOfBounds { fst_offset, fs_size });
+        return Ok(warnings);
+    }
+
+    let mut fst = vec![0u8; fs_size as usize];
+    iso.seek(SeekFrom::Start(fst_offset as _))?;
+    iso.read_exact(&mut fst)?;
+
+    let iter = match FstIter::new(&fst) {
+        Ok(iter) => iter,
+        Err(FstError::EntryCountOutOfRange { entry_count }) => {
+            warnings.push(VerifyWarning::EntryCountOutOfRange { entry_count });
+            return Ok(warnings);
+        }
+        Err(_) => return Err(ReadISOFilesError::InvalidISO),
+    };
+    let string_table_offset = iter.entry_count() * 0xC;
+
+    // walk fst ---------------------------------------------------------------
+
+    let mut files = Vec::with_capacity(iter.entry_count() as usize);
+    // the directory containing the current entry, for naming a directory with a bad next index
+    let mut dir_path = PathBuf::with_capacity(64);
+
+    // the walk stops at the first error, as the entries after it can't be trusted
+    for event in iter {
+        match event {
+            Ok(FstEvent::File { path, offset, size, .. }) => {
+                if offset as u64 + size as u64 > iso_len {
+                    warnings.push(VerifyWarning::FileOutOfBounds { path, offset, size });
+                } else if size != 0 {
+                    files.push((offset, size, path));
                 }
             }
+            Ok(FstEvent::EnterDir { path, .. }) => dir_path = path,
+            Ok(FstEvent::ExitDir) => { dir_path.pop(); }
+            Err(FstError::InvalidNextIndex { entry_index, next_idx }) => {
+                let name = read_u32(&fst, entry_index * 0xC)
+                    .and_then(|w0| read_filename(&fst, string_table_offset + (w0 & 0xFFFFFF)))
+                    .unwrap_or("?");
+                warnings.push(VerifyWarning::NextIndexOutOfRange { path: dir_path.join(name), next_idx });
+            }
+            Err(FstError::InvalidEntry { entry_index }) => warnings.push(VerifyWarning::UnterminatedName { entry_index }),
+            Err(FstError::TooSmall | FstError::EntryCountOutOfRange { .. }) => return Err(ReadISOFilesError::InvalidISO),
+        }
+    }
 
-            //let parent_idx = read_u32(&buf, offset+4); // unused
-            let next_idx = read_u32(&buf, offset+8);
-            dir_end_indices.push(next_idx);
+    // check for overlaps -------------------------------------------------------
+
+    files.sort_unstable_by_key(|(offset, _, _)| *offset);
+    let mut furthest: Option<&(u32, u32, PathBuf)> = None;
+    for file in files.iter() {
+        if let Some(prev) = furthest {
+            // files sharing exactly the same region were deduplicated, not corrupted
+            let shared = file.0 == prev.0 && file.1 == prev.1;
+            if file.0 < prev.0 + prev.1 && !shared {
+                warnings.push(VerifyWarning::FilesOverlap { a: prev.2.clone(), b: file.2.clone() });
+            }
         }
 
-        offset += 0xC;
-        entry_index += 1;
+        if furthest.is_none_or(|prev| file.0 + file.1 > prev.0 + prev.1) {
+            furthest = Some(file);
+        }
     }
 
-    Ok(())
+    Ok(warnings)
 }
 
-/// Tries to do as little IO as possible. 
-///
-/// Pass "ISO.hdr", "AppLoader.ldr", and "Start.dol" insertions to modify the ISO headers.
-pub fn operate_on_iso(iso_path: &Path, ops: &[IsoOp]) -> Result<(), OperateISOError> {
-    use std::io::{Read, Write, Seek, SeekFrom};
-
-    if ops.len() == 0 { return Ok(()) }
-    let iso_meta = iso_path.metadata()?;
+#[derive(Copy, Clone, Default)]
+pub struct OperateOptions<'a> {
+    /// When there is no free space left, extend the ISO past `ROM_SIZE` instead of returning `ISOTooLarge`.
+    pub allow_grow: bool,
 
-    if iso_meta.len() > ROM_SIZE as _ { return Err(OperateISOError::InvalidISO); }
+    /// Only place inserted files after the end of all existing file data, including deleted files,
+    /// instead of reusing gaps between files.
+    /// Existing file data is never overwritten, so each operation only appends to the ISO.
+    pub append_only: bool,
 
-    let mut iso_file_deletions = Vec::new();
-    let mut iso_file_insertions = Vec::new();
+    /// Overwrite the contents of deleted and replaced files with zeros,
+    /// so old data is not left in the ISO and it compresses better.
+    /// Contents still used by another file are kept.
+    pub scrub_deleted: bool,
 
-    let mut iso_hdr = None;
-    let mut apploader = None;
-    let mut start_dol = None;
+    /// Called for each file and directory as the new FST is built.
+    pub log: Option<&'a dyn Fn(LogEntry)>,
+}
 
-    for op in ops {
-        match op {
-            IsoOp::Insert { iso_path, input_path } if *iso_path == Path::new("ISO.hdr")       => iso_hdr   = Some(input_path),
-            IsoOp::Insert { iso_path, input_path } if *iso_path == Path::new("AppLoader.ldr") => apploader = Some(input_path),
-            IsoOp::Insert { iso_path, input_path } if *iso_path == Path::new("Start.dol")     => start_dol = Some(input_path),
-
-            IsoOp::Insert { iso_path, input_path } => {
-                iso_file_deletions.push(*iso_path);
-                iso_file_insertions.push((iso_path, input_path));
-            },
-            IsoOp::Delete { iso_path } => {
-                iso_file_deletions.push(*iso_path);
-            }
-        }
+// `log` can't derive Debug
+impl std::fmt::Debug for OperateOptions<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OperateOptions")
+            .field("allow_grow", &self.allow_grow)
+            .field("append_only", &self.append_only)
+            .field("scrub_deleted", &self.scrub_deleted)
+            .field("log", &self.log.is_some())
+            .finish()
     }
+}
+
+/// Tries to do as little IO as possible. 
+///
+/// Pass "ISO.hdr", "boot.bin", "bi2.bin", "AppLoader.ldr", and "Start.dol" insertions to modify the ISO headers.
+///
+/// Every operation is planned and every input file is opened before anything is written,
+/// so errors such as `TOCTooLarge`, `ISOTooLarge`, or a missing input file leave the ISO untouched.
+/// The ISO is modified in place, though, so an IO error while writing can still leave it partially modified.
+/// Copy it first if that matters.
+pub fn operate_on_iso(iso_path: &Path, ops: &[IsoOp]) -> Result<(), OperateISOError> {
+    operate_on_iso_with_options(iso_path, ops, &OperateOptions::default())
+}
+
+pub fn operate_on_iso_with_options(iso_path: &Path, ops: &[IsoOp], options: &OperateOptions) -> Result<(), OperateISOError> {
+    if ops.is_empty() { return Ok(()) }
 
     let mut iso = std::fs::File::options()
         .read(true)
@@ -915,78 +4467,207 @@ pub fn operate_on_iso(iso_path: &Path, ops: &[IsoOp]) -> Result<(), OperateISOEr
         .open(iso_path)
         .map_err(|e| OperateISOError::OpenError { path: iso_path.into(), e })?;
 
+    let plan = plan_operations_in(&mut iso, iso_path, ops, options)?;
+    if !plan.fits { return Err(OperateISOError::TOCTooLarge); }
+    execute_plan(&mut iso, &plan)
+}
 
-    // read header ---------------------------------------------------------
+/// Builds an 'opening.bnr' file from `info` and inserts it at the root of the ISO, replacing any existing banner.
+pub fn set_banner(iso_path: &Path, info: GameInfo) -> Result<(), OperateISOError> {
+    let banner = create_opening_bnr(info).map_err(OperateISOError::CreateOpeningBnrError)?;
+    operate_on_iso(iso_path, &[IsoOp::InsertBytes { iso_path: Path::new("opening.bnr"), data: &*banner }])
+}
 
-    let mut buf = [0u8; 12];
-    iso.seek(SeekFrom::Start(HEADER_INFO_OFFSET as _))?;
-    iso.read_exact(&mut buf)?;
-    let dol_offset = u32::from_be_bytes(buf[0..4].try_into().unwrap());
-    let fst_offset = u32::from_be_bytes(buf[4..8].try_into().unwrap());
-    let fs_size = u32::from_be_bytes(buf[8..12].try_into().unwrap());
+/// The result of `plan_operations`: where inserted files will be placed, and whether the new FST fits.
+#[derive(Clone, Debug)]
+pub struct OperationPlan {
+    /// size of the new FST, including the string table
+    pub fs_size: u32,
+    /// space between the start of the FST and the first file's contents
+    pub fs_capacity: u32,
+    /// whether the new FST fits before the first file's contents.
+    /// `operate_on_iso` returns `TOCTooLarge` if this is false.
+    pub fits: bool,
+    pub insertions: Vec<PlannedInsertion>,
+
+    dol_offset: u32,
+    fst_offset: u32,
+    /// contents of deleted files to overwrite with zeros, if `scrub_deleted` was set
+    scrub: Vec<std::ops::Range<u64>>,
+    /// The size the game reserves in memory for the fst. Kept unless the new fst is larger.
+    max_fs_size: u32,
+    /// free space before any insertions were placed
+    free_space: Vec<std::ops::Range<u64>>,
+    toc: Vec<u8>,
+    /// ISO.hdr, boot.bin, bi2.bin, AppLoader.ldr, Start.dol
+    special_files: [Option<PlannedSource>; 5],
+}
 
-    let mut u32_buf = [0u8; 4];
-    iso.seek(SeekFrom::Start((fst_offset + 8) as _))?;
-    iso.read_exact(&mut u32_buf)?;
-    let entry_count = u32::from_be_bytes(u32_buf);
+#[derive(Clone, Debug)]
+pub struct PlannedInsertion {
+    pub iso_path: PathBuf,
+    /// Empty if the contents are inserted from memory.
+    pub input_path: PathBuf,
+    pub offset: u32,
+    pub size: u32,
+
+    source: PlannedSource,
+}
 
-    let string_table_offset = fst_offset + entry_count * 0xC;
-    let entry_start_offset = fst_offset + 0xC;
+/// Does all the work of `operate_on_iso` without writing to the ISO.
+pub fn plan_operations(iso_path: &Path, ops: &[IsoOp]) -> Result<OperationPlan, OperateISOError> {
+    plan_operations_with_options(iso_path, ops, &OperateOptions::default())
+}
 
-    // read iso fs ------------------------------------------------------------
+pub fn plan_operations_with_options(iso_path: &Path, ops: &[IsoOp], options: &OperateOptions) -> Result<OperationPlan, OperateISOError> {
+    let mut iso = std::fs::File::options()
+        .read(true)
+        .open(iso_path)
+        .map_err(|e| OperateISOError::OpenError { path: iso_path.into(), e })?;
 
-    let string_table_offset_in_buf = string_table_offset - entry_start_offset; 
-    iso.seek(SeekFrom::Start(entry_start_offset as _))?;
-    let mut buf = vec![0u8; fs_size as usize];
-    iso.read_exact(&mut buf)?;
+    plan_operations_in(&mut iso, iso_path, ops, options)
+}
 
-    let mut dir_end_indices = Vec::with_capacity(8);
-    let mut offset = 0;
-    let mut entry_index = 1;
+/// Returns how many bytes the table of contents can grow before `operate_on_iso` returns `TOCTooLarge`.
+///
+/// Each inserted file or created directory takes 0xC bytes, plus the length of its name and a null terminator.
+pub fn remaining_toc_bytes(iso_path: &Path) -> Result<u32, OperateISOError> {
+    let plan = plan_operations(iso_path, &[])?;
+    Ok(plan.fs_capacity.saturating_sub(plan.fs_size))
+}
+
+/// Returns the regions of the ISO that `operate_on_iso` can place inserted files in, sorted by offset.
+///
+/// These are the gaps between files, aligned to 32KiB, and the space after the last file up to `ROM_SIZE`.
+/// An insertion returns `ISOTooLarge` if no single region is large enough, even if their total is.
+pub fn free_regions(iso_path: &Path) -> Result<Vec<std::ops::Range<u32>>, OperateISOError> {
+    let plan = plan_operations(iso_path, &[])?;
+    Ok(plan.free_space.iter().map(|r| r.start as u32..r.end as u32).collect())
+}
+
+fn plan_operations_in(
+    iso: &mut std::fs::File,
+    iso_path: &Path,
+    ops: &[IsoOp],
+    options: &OperateOptions,
+) -> Result<OperationPlan, OperateISOError> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let iso_meta = iso_path.metadata()?;
 
-    let mut string_table_end = 0;
-    let mut fs = Vec::new();
+    if iso_meta.len() > ROM_SIZE as _ && !options.allow_grow { return Err(OperateISOError::InvalidISO); }
 
-    while offset < string_table_offset_in_buf {
-        while Some(entry_index) == dir_end_indices.last().copied() {
-            // dir has ended
-            dir_end_indices.pop();
-            fs.push(FsEntry::PopDir);
+    let mut iso_file_deletions = Vec::new();
+    let mut iso_file_insertions = Vec::new();
+    let mut iso_file_renames = Vec::new();
+    let mut iso_dir_creations = Vec::new();
+
+    // directories and (iso path, input path) of files found in InsertDir input directories
+    let mut walked_dirs = Vec::new();
+    let mut walked_files = Vec::new();
+    for op in ops {
+        if let IsoOp::InsertDir { iso_path, input_dir } = *op {
+            let iso_path = iso_path.strip_prefix("/").unwrap_or(iso_path);
+            if !input_dir.metadata()?.is_dir() { return Err(OperateISOError::InvalidFSPath(input_dir.to_path_buf())); }
+            walked_dirs.push(iso_path.to_path_buf());
+            walk_insert_dir(input_dir, iso_path, &mut walked_dirs, &mut walked_files)?;
         }
+    }
+    iso_dir_creations.extend(walked_dirs.iter().map(PathBuf::as_path));
+
+    const SPECIAL_FILES: [&str; 5] = ["ISO.hdr", "boot.bin", "bi2.bin", "AppLoader.ldr", "Start.dol"];
+    let mut special_files = [None; 5];
+
+    let walked_ops = walked_files.iter()
+        .map(|(iso_path, input_path)| IsoOp::Insert { iso_path, input_path });
+    for op in ops.iter().copied().chain(walked_ops) {
+        let (iso_path, source, at) = match op {
+            IsoOp::Insert { iso_path, input_path } => (iso_path, InsertionSource::File(input_path), None),
+            IsoOp::InsertBytes { iso_path, data } => (iso_path, InsertionSource::Bytes(data), None),
+            IsoOp::InsertAt { iso_path, input_path, offset } => (iso_path, InsertionSource::File(input_path), Some(offset)),
+            IsoOp::Delete { iso_path } => {
+                iso_file_deletions.push(iso_path);
+                continue;
+            }
+            IsoOp::Rename { from, to } => {
+                iso_file_renames.push((from, to, None));
+                // replace any file already at the new path, the same way insertions do
+                if from != to { iso_file_deletions.push(to); }
+                continue;
+            }
+            IsoOp::Mkdir { iso_path } => {
+                iso_dir_creations.push(iso_path);
+                continue;
+            }
+            IsoOp::InsertDir { .. } => continue,
+        };
 
-        let is_file = buf[offset as usize] == 0;
+        match SPECIAL_FILES.iter().position(|name| iso_path == Path::new(name)) {
+            // special files are always written in place
+            Some(_) if at.is_some() => return Err(OperateISOError::InvalidISOPath(iso_path.to_path_buf())),
+            Some(i) => special_files[i] = Some(source),
+            None => {
+                iso_file_deletions.push(iso_path);
+                iso_file_insertions.push((iso_path, source, at));
+            }
+        }
+    }
+    let [_, _, _, apploader, start_dol] = special_files;
 
-        let mut name_offset_buf = [0; 4];
-        name_offset_buf[1] = buf[offset as usize+1];
-        name_offset_buf[2] = buf[offset as usize+2];
-        name_offset_buf[3] = buf[offset as usize+3];
-        let name_offset = u32::from_be_bytes(name_offset_buf);
-        let name = read_filename(&buf, string_table_offset_in_buf + name_offset)
-            .ok_or(OperateISOError::InvalidISO)?;
+    // read header ---------------------------------------------------------
 
-        let string_len = name.len() as u32 + 1;
-        string_table_end = string_table_end.max(name_offset+string_len);
+    let mut buf = [0u8; 16];
+    iso.seek(SeekFrom::Start(HEADER_INFO_OFFSET as _))?;
+    iso.read_exact(&mut buf)?;
+    let dol_offset = u32::from_be_bytes(buf[0..4].try_into().unwrap());
+    let fst_offset = u32::from_be_bytes(buf[4..8].try_into().unwrap());
+    let fs_size = u32::from_be_bytes(buf[8..12].try_into().unwrap());
+    let max_fs_size = u32::from_be_bytes(buf[12..16].try_into().unwrap());
 
-        if is_file {
-            let file_offset = read_u32(&buf, offset+4);
-            let file_size = read_u32(&buf, offset+8);
+    // the apploader is overwritten in place, so it must fit before the dol
+    if let Some(apploader) = apploader {
+        let required = apploader.len()?;
+        let available = dol_offset.saturating_sub(0x2440);
+        if required > available as u64 {
+            return Err(OperateISOError::ApploaderTooLarge { available, required });
+        }
+    }
 
-            fs.push(FsEntry::File { name, offset: file_offset, size: file_size });
-        } else {
-            //let parent_idx = read_u32(&buf, offset+4); // unused
-            let next_idx = read_u32(&buf, offset+8);
-            dir_end_indices.push(next_idx);
-            fs.push(FsEntry::PushDir { name });
+    // the dol is overwritten in place, so it must fit before the fst
+    if let Some(start_dol) = start_dol {
+        let required = start_dol.len()?;
+        let available = fst_offset.saturating_sub(dol_offset);
+        if required > available as u64 {
+            return Err(OperateISOError::DolTooLarge { available, required });
         }
+    }
+
+    // read iso fs ------------------------------------------------------------
 
-        offset += 0xC;
-        entry_index += 1;
+    iso.seek(SeekFrom::Start(fst_offset as _))?;
+    let mut fst = vec![0u8; fs_size as usize];
+    iso.read_exact(&mut fst)?;
+
+    let iter = FstIter::new(&fst).map_err(|_| OperateISOError::InvalidISO)?;
+    let entry_count = iter.entry_count();
+    let mut fs = Vec::with_capacity(entry_count as usize);
+
+    for event in iter {
+        fs.push(match event.map_err(|_| OperateISOError::InvalidISO)? {
+            FstEvent::File { name, offset, size, .. } => FsEntry::File { name, offset, size },
+            FstEvent::EnterDir { name, .. } => FsEntry::PushDir { name },
+            FstEvent::ExitDir => FsEntry::PopDir,
+        });
     }
 
     // operate on fs -----------------------------------------------------------
 
-    let mut data_start = u32::MAX;
-    let mut data_end = 0;
+    // Positions in the iso are u64, so the ends of files near the 4GiB limit don't wrap.
+    let mut data_start = u64::MAX;
+    let mut data_end = 0u64;
+    let mut original_data_end = 0u64;
+    // contents of deleted files, which may still be shared with other files
+    let mut deleted = Vec::new();
 
     // deletions
 
@@ -997,8 +4678,21 @@ pub fn operate_on_iso(iso_path: &Path, ops: &[IsoOp]) -> Result<(), OperateISOEr
         match fs[i] {
             FsEntry::File { name, size, offset } => {
                 path.push(name);
+                let end = offset as u64 + size as u64;
+                original_data_end = original_data_end.max(end);
 
                 let mut kept = true;
+
+                // renamed files are removed here and reinserted at their new path later
+                let mut renamed = false;
+                for (from, _, found) in iso_file_renames.iter_mut() {
+                    if found.is_none() && path == *from {
+                        *found = Some((offset, size));
+                        renamed = true;
+                        break;
+                    }
+                }
+
                 let mut d = 0;
                 while d < iso_file_deletions.len() {
                     if path == iso_file_deletions[d] {
@@ -1011,8 +4705,13 @@ pub fn operate_on_iso(iso_path: &Path, ops: &[IsoOp]) -> Result<(), OperateISOEr
                 }
 
                 if kept { 
-                    data_start = data_start.min(offset);
-                    data_end = data_end.max(size+offset);
+                    data_start = data_start.min(offset as u64);
+                    data_end = data_end.max(end);
+                } else {
+                    deleted.push(offset as u64..end);
+                }
+
+                if kept && !renamed {
                     i += 1; 
                 } else {
                     fs.remove(i);
@@ -1043,33 +4742,154 @@ pub fn operate_on_iso(iso_path: &Path, ops: &[IsoOp]) -> Result<(), OperateISOEr
         }
     }
 
-    // find free space 
+    // find free space 
+
+    let mut used: Vec<std::ops::Range<u64>> = Vec::with_capacity(entry_count as usize);
+    for e in fs.iter() {
+        match *e {
+            FsEntry::File { offset, size, .. } => used.push(offset as u64..(offset as u64 + size as u64)),
+            _ => continue,
+        };
+    }
+    for (_, _, found) in iso_file_renames.iter() {
+        if let Some((offset, size)) = *found { used.push(offset as u64..(offset as u64 + size as u64)); }
+    }
+    used.sort_unstable_by_key(|r| r.start);
+
+    let scrub = match options.scrub_deleted {
+        true => subtract_ranges(&deleted, &used),
+        false => Vec::new(),
+    };
+    let mut free_space = if options.append_only { Vec::new() } else { used.windows(2)
+        .filter_map(|r| {
+            let a = r[0].clone();
+            let b = r[1].clone();
+            let new_start = align(a.end, FILE_CONTENTS_ALIGNMENT);
+            if new_start >= b.start { None }
+            else { Some(new_start..b.start) }
+        }).collect::<Vec<_>>() };
+
+    let data_end_start = match options.append_only {
+        true => align(original_data_end, FILE_CONTENTS_ALIGNMENT),
+        false => align(data_end, FILE_CONTENTS_ALIGNMENT),
+    };
+    // file offsets in the fst are u32, so even a growing iso can't place files past 4GiB
+    let free_space_end = if options.allow_grow { 1 << 32 } else { ROM_SIZE as u64 };
+    if data_end_start < free_space_end { free_space.push(data_end_start..free_space_end) }
+
+    // Never place files over the header, apploader, dol, or fst.
+    // Gaps between files or the space after them can only overlap these on unusual layouts,
+    // or when every file was deleted.
+
+    let (dol_offset, fst_offset, fs_size) = (dol_offset as u64, fst_offset as u64, fs_size as u64);
+    let dol_end = if fst_offset > dol_offset {
+        fst_offset
+    } else {
+        // the fst comes first, so the dol's size is all we have
+        let mut dol_header = [0u8; Dol::HEADER_SIZE];
+        iso.seek(SeekFrom::Start(dol_offset))?;
+        iso.read_exact(&mut dol_header)?;
+        let dol_size = Dol::parse(&dol_header).map_or(0, |dol| dol.total_size());
+        dol_offset + dol_size as u64
+    };
+    // the fst may grow up to the first file
+    let fst_end = match data_start {
+        u64::MAX => align(fst_offset + fs_size, FILE_CONTENTS_ALIGNMENT),
+        _ => data_start.max(fst_offset + fs_size),
+    };
+
+    for reserved in [0..dol_offset.max(0x2440), dol_offset..dol_end, fst_offset..fst_end] {
+        free_space = free_space.into_iter()
+            .flat_map(|free| {
+                let before = free.start..free.end.min(reserved.start);
+                let after = align(free.start.max(reserved.end), FILE_CONTENTS_ALIGNMENT)..free.end;
+                [before, after].into_iter().filter(|r| r.start < r.end)
+            })
+            .collect();
+    }
+
+    let original_free_space = free_space.clone();
+
+    // Regions files inserted at a given offset may not overlap, named for `OffsetOccupied`.
+    // Only the current fst is reserved. If it needs to grow past a file inserted after it, `fits` is false.
+    let mut occupied = vec![
+        (0..0x2440, PathBuf::from("ISO.hdr")),
+        (0x2440..dol_offset, PathBuf::from("AppLoader.ldr")),
+        (dol_offset..dol_end, PathBuf::from("Start.dol")),
+        (fst_offset..fst_offset + fs_size, PathBuf::from("Game.toc")),
+    ];
+    if iso_file_insertions.iter().any(|&(_, _, at)| at.is_some()) {
+        path.clear();
+        for e in fs.iter() {
+            match *e {
+                FsEntry::File { name, offset, size } => occupied.push((offset as u64..offset as u64 + size as u64, path.join(name))),
+                FsEntry::PushDir { name } => path.push(name),
+                FsEntry::PopDir => { path.pop(); }
+            }
+        }
+        for &(_, to, found) in iso_file_renames.iter() {
+            if let Some((offset, size)) = found { occupied.push((offset as u64..offset as u64 + size as u64, to.to_path_buf())); }
+        }
+    }
+
+    // directory creations
+    // Done after removing empty directories, so requested directories are kept even if empty.
+
+    for dir_path in iso_dir_creations.iter() {
+        mkdir_all(&mut fs, dir_path)?;
+    }
+
+    // renames
+
+    for (from, to, found) in iso_file_renames.iter() {
+        let (offset, size) = found.ok_or_else(|| OperateISOError::InvalidISOPath(from.to_path_buf()))?;
+
+        let insert_idx = match to.ancestors().nth(1) {
+            Some(dir_path) => mkdir_all(&mut fs, dir_path)?,
+            None => 0,
+        };
+
+        let file_name = to.file_name()
+            .and_then(|os_str| os_str.to_str())
+            .ok_or_else(|| OperateISOError::InvalidISOPath(to.to_path_buf()))?;
+
+        if find_dir(&fs[insert_idx..], std::ffi::OsStr::new(file_name)).is_some() {
+            return Err(OperateISOError::FileInsertionReplicatesFolder(to.to_path_buf()));
+        }
+
+        fs.insert(insert_idx, FsEntry::File { 
+            name: file_name,
+            size,
+            offset,
+        });
+    }
+
+    // files inserted at a given offset are placed first, so other insertions go around them
 
-    let mut used: Vec<std::ops::Range<u32>> = Vec::with_capacity(entry_count as usize);
-    for e in fs.iter() {
-        match *e {
-            FsEntry::File { offset, size, .. } => used.push(offset..(offset+size)),
-            _ => continue,
-        };
-    }
-    used.sort_unstable_by_key(|r| r.start);
-    let mut free_space = used.windows(2)
-        .filter_map(|r| {
-            let a = r[0].clone();
-            let b = r[1].clone();
-            let new_start = align(a.end, FILE_CONTENTS_ALIGNMENT);
-            if new_start >= b.start { None }
-            else { Some(new_start..b.start) }
-        }).collect::<Vec<_>>();
+    for &(iso_path, source, at) in iso_file_insertions.iter() {
+        let Some(offset) = at else { continue };
+        if offset % (1 << MIN_FILE_ALIGNMENT) != 0 { return Err(OperateISOError::MisalignedOffset(offset)); }
+        let size = u32::try_from(source.len()?).map_err(|_| OperateISOError::OffsetOverflow(iso_path.to_path_buf()))?;
+
+        let range = offset as u64..offset as u64 + size as u64;
+        if range.end > free_space_end { return Err(OperateISOError::ISOTooLarge); }
+        // empty files can't overlap anything
+        if let Some((_, conflict)) = occupied.iter().find(|(r, _)| r.start < range.end && range.start < r.end) {
+            return Err(OperateISOError::OffsetOccupied { offset, size, conflict: conflict.clone() });
+        }
 
-    let data_end_start = align(data_end, FILE_CONTENTS_ALIGNMENT);
-    if data_end_start < ROM_SIZE { free_space.push(data_end_start..ROM_SIZE) }
+        free_space = subtract_ranges(&free_space, std::slice::from_ref(&range)).into_iter()
+            .map(|free| align(free.start, FILE_CONTENTS_ALIGNMENT)..free.end)
+            .filter(|free| free.start < free.end)
+            .collect();
+        occupied.push((range, iso_path.to_path_buf()));
+    }
 
     // insertions
 
     let mut write_locs = Vec::with_capacity(iso_file_insertions.len());
 
-    for (iso_path, fs_path) in iso_file_insertions.iter() {
+    for &(iso_path, source, at) in iso_file_insertions.iter() {
         let insert_idx = match iso_path.ancestors().nth(1) {
             Some(dir_path) => mkdir_all(&mut fs, dir_path)?,
             None => 0,
@@ -1079,49 +4899,42 @@ pub fn operate_on_iso(iso_path: &Path, ops: &[IsoOp]) -> Result<(), OperateISOEr
             .and_then(|os_str| os_str.to_str())
             .ok_or_else(|| OperateISOError::InvalidISOPath(iso_path.to_path_buf()))?;
 
-        let meta = fs_path.metadata()?;
-        if !meta.is_file() { return Err(OperateISOError::InvalidFSPath(fs_path.to_path_buf())); }
-        let size = meta.len() as u32;
-
-        let mut offset = None;
-        for free in free_space.iter_mut() {
-            let free_size = free.end.saturating_sub(free.start);
-            if free_size >= size {
-                offset = Some(free.start);
-                free.start = align(free.start+size, FILE_CONTENTS_ALIGNMENT);
-                break;
+        if find_dir(&fs[insert_idx..], std::ffi::OsStr::new(file_name)).is_some() {
+            return Err(OperateISOError::FileInsertionReplicatesFolder(iso_path.to_path_buf()));
+        }
+
+        if let InsertionSource::File(fs_path) = source {
+            if !fs_path.metadata()?.is_file() { return Err(OperateISOError::InvalidFSPath(fs_path.to_path_buf())); }
+        }
+        let size = u32::try_from(source.len()?).map_err(|_| OperateISOError::OffsetOverflow(iso_path.to_path_buf()))?;
+
+        let mut offset = at.map(u64::from);
+        if offset.is_none() {
+            for free in free_space.iter_mut() {
+                let free_size = free.end.saturating_sub(free.start);
+                if free_size >= size as u64 {
+                    offset = Some(free.start);
+                    free.start = align(free.start + size as u64, FILE_CONTENTS_ALIGNMENT);
+                    break;
+                }
             }
         }
 
+        // free space ends at or before 4GiB, so the offset fits in the fst
         let offset = match offset {
-            Some(o) => o,
+            Some(o) => o as u32,
             None => return Err(OperateISOError::ISOTooLarge),
         };
 
-        write_locs.push(offset);
-        fs.insert(insert_idx as usize, FsEntry::File { 
+        write_locs.push((offset, size));
+        fs.insert(insert_idx, FsEntry::File { 
             name: file_name,
             size,
             offset,
         });
     }
 
-    // new fs was created and is valid, start writing ----------------------------
-
-    // write inserted files
-
-    for (offset, (_, fs_path)) in write_locs.into_iter().zip(iso_file_insertions) {
-        iso.seek(SeekFrom::Start(offset as _))?;
-
-        let mut file = std::fs::File::options()
-            .read(true)
-            .open(fs_path)
-            .map_err(|e| OperateISOError::OpenError { path: fs_path.into(), e })?;
-
-        std::io::copy(&mut file, &mut iso)?;
-    }
-
-    // write table of contents
+    // build table of contents
 
     let entry_count = fs.iter()
         .filter(|e| matches!(e, FsEntry::File { .. } | FsEntry::PushDir { .. }))
@@ -1135,16 +4948,20 @@ pub fn operate_on_iso(iso_path: &Path, ops: &[IsoOp]) -> Result<(), OperateISOEr
     toc_bytes[8..12].copy_from_slice(&(entry_count as u32 + 1).to_be_bytes());
 
     let mut i = 1u32;
-    let mut dir_start_indices = dir_end_indices;
-    dir_start_indices.clear();
+    let mut dir_start_indices = Vec::with_capacity(8);
     dir_start_indices.push(0u32);
+    path.clear();
 
     for entry in fs.iter() {
         match entry {
             FsEntry::File { name, size, offset } => {
+                if let Some(log) = options.log {
+                    log(LogEntry::File { path: &path.join(name), offset: *offset, size: *size });
+                }
+
                 let entry_offset = (i * 0xC) as usize;
                 let string_i = toc_bytes.len() as u32 - string_start;
-                toc_bytes[entry_offset+0..][..4].copy_from_slice(&string_i.to_be_bytes());
+                toc_bytes[entry_offset..][..4].copy_from_slice(&string_i.to_be_bytes());
                 toc_bytes[entry_offset+4..][..4].copy_from_slice(&offset.to_be_bytes());
                 toc_bytes[entry_offset+8..][..4].copy_from_slice(&size.to_be_bytes());
 
@@ -1153,13 +4970,18 @@ pub fn operate_on_iso(iso_path: &Path, ops: &[IsoOp]) -> Result<(), OperateISOEr
                 i += 1;
             },
             FsEntry::PushDir { name } => {
+                path.push(name);
+                if let Some(log) = options.log {
+                    log(LogEntry::Dir { path: &path });
+                }
+
                 let parent_idx = *dir_start_indices.last().unwrap();
                 dir_start_indices.push(i);
 
                 let entry_offset = (i * 0xC) as usize;
                 let string_i = toc_bytes.len() as u32 - string_start;
-                toc_bytes[entry_offset+0..][..4].copy_from_slice(&string_i.to_be_bytes());
-                toc_bytes[entry_offset+0] = 1; // directory flag
+                toc_bytes[entry_offset..][..4].copy_from_slice(&string_i.to_be_bytes());
+                toc_bytes[entry_offset] = 1; // directory flag
                 toc_bytes[entry_offset+4..][..4].copy_from_slice(&parent_idx.to_be_bytes());
                 // next_idx written later
 
@@ -1168,6 +4990,7 @@ pub fn operate_on_iso(iso_path: &Path, ops: &[IsoOp]) -> Result<(), OperateISOEr
                 i += 1;
             }
             FsEntry::PopDir => {
+                path.pop();
                 let dir_idx = dir_start_indices.pop().unwrap();
                 let next_idx = i;
                 toc_bytes[(dir_idx*0xC+8) as usize..][..4].copy_from_slice(&next_idx.to_be_bytes());
@@ -1175,72 +4998,618 @@ pub fn operate_on_iso(iso_path: &Path, ops: &[IsoOp]) -> Result<(), OperateISOEr
         }
     }
 
-    if toc_bytes.len() as u32 > data_start - fst_offset {
-        return Err(OperateISOError::TOCTooLarge);
+    // inserted files are included, since every file was deleted if `data_start` is still unset
+    let first_offset = write_locs.iter().map(|&(offset, _)| offset as u64).fold(data_start, u64::min);
+    let fs_capacity = first_offset.saturating_sub(fst_offset).min(u32::MAX as u64) as u32;
+
+    // Deleted files are scrubbed after everything else is written, so skip the new fst and inserted files placed over them.
+    let mut written = write_locs.iter()
+        .map(|&(offset, size)| offset as u64..offset as u64 + size as u64)
+        .chain(std::iter::once(fst_offset..fst_offset + toc_bytes.len() as u64))
+        .collect::<Vec<_>>();
+    written.sort_unstable_by_key(|r| r.start);
+    let scrub = subtract_ranges(&scrub, &written);
+
+    let insertions = iso_file_insertions.iter()
+        .zip(write_locs)
+        .map(|((iso_path, source, _), (offset, size))| {
+            let input_path = match *source {
+                InsertionSource::File(input_path) => input_path.to_path_buf(),
+                InsertionSource::Bytes(_) => PathBuf::new(),
+            };
+            PlannedInsertion { iso_path: iso_path.to_path_buf(), input_path, offset, size, source: source.to_planned() }
+        })
+        .collect();
+
+    Ok(OperationPlan {
+        fs_size: toc_bytes.len() as u32,
+        fs_capacity,
+        fits: toc_bytes.len() as u32 <= fs_capacity,
+        insertions,
+        dol_offset: dol_offset as u32,
+        fst_offset: fst_offset as u32,
+        scrub,
+        max_fs_size,
+        free_space: original_free_space,
+        toc: toc_bytes,
+        special_files: special_files.map(|f| f.map(InsertionSource::to_planned)),
+    })
+}
+
+/// Removes the parts of `ranges` covered by `remove`, which must be sorted by start.
+fn subtract_ranges(ranges: &[std::ops::Range<u64>], remove: &[std::ops::Range<u64>]) -> Vec<std::ops::Range<u64>> {
+    let mut out = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        let mut start = range.start;
+        for r in remove.iter() {
+            if r.end <= start { continue; }
+            if r.start >= range.end { break; }
+            if r.start > start { out.push(start..r.start); }
+            start = start.max(r.end);
+        }
+        if start < range.end { out.push(start..range.end); }
+    }
+    out
+}
+
+/// Writes the inserted files, fst, and special files planned by `plan_operations_in`.
+fn execute_plan<'a>(iso: &mut std::fs::File, plan: &'a OperationPlan) -> Result<(), OperateISOError> {
+    use std::io::{Read, Write, Seek, SeekFrom};
+
+    let OperationPlan { dol_offset, fst_offset, max_fs_size, ref toc, ref special_files, .. } = *plan;
+
+    let mut insertions = Vec::with_capacity(plan.insertions.len());
+    for insertion in plan.insertions.iter() {
+        insertions.push((insertion.offset, insertion.size, insertion.source.open()?));
+    }
+    let open = |f: &'a Option<PlannedSource>| f.as_ref().map(PlannedSource::open).transpose();
+    let [iso_hdr, boot_bin, bi2_bin, apploader, start_dol] = special_files;
+    let (iso_hdr, boot_bin, bi2_bin) = (open(iso_hdr)?, open(boot_bin)?, open(bi2_bin)?);
+    let (apploader, start_dol) = (open(apploader)?, open(start_dol)?);
+
+    // write inserted files
+    // Only the planned size is copied, in case the file grew since planning.
+
+    for (offset, size, source) in insertions.iter_mut() {
+        iso.seek(SeekFrom::Start(*offset as _))?;
+        std::io::copy(&mut source.take(*size as u64), iso)?;
     }
 
+    // write table of contents
+
     iso.seek(SeekFrom::Start(fst_offset as _))?;
-    iso.write_all(toc_bytes.as_slice())?;
+    iso.write_all(toc)?;
 
     // write special (&&systemdata) files
 
-    if let Some(iso_hdr) = iso_hdr {
-        iso.seek(SeekFrom::Start(0))?;
-
-        let mut f = std::fs::File::options()
-            .read(true)
-            .open(iso_hdr)
-            .map_err(|e| OperateISOError::OpenError { path: iso_hdr.into(), e })?;
-        std::io::copy(&mut f, &mut iso)?;
+    for (header_file, offset) in [(iso_hdr, 0), (boot_bin, 0), (bi2_bin, 0x440)] {
+        if let Some(mut header_file) = header_file {
+            iso.seek(SeekFrom::Start(offset))?;
+            std::io::copy(&mut header_file, iso)?;
+        }
     }
 
     // overwrite necessary values in header
-    let fs_size = toc_bytes.len() as u32;
+    let fs_size = toc.len() as u32;
     let mut buf = [0u8; 16];
     buf[ 0..][..4].copy_from_slice(&dol_offset.to_be_bytes());
     buf[ 4..][..4].copy_from_slice(&fst_offset.to_be_bytes());
     buf[ 8..][..4].copy_from_slice(&fs_size.to_be_bytes());
-    buf[12..][..4].copy_from_slice(&fs_size.to_be_bytes());
+    buf[12..][..4].copy_from_slice(&fs_size.max(max_fs_size).to_be_bytes());
     iso.seek(SeekFrom::Start(HEADER_INFO_OFFSET as _))?;
     iso.write_all(&buf)?;
 
-    if let Some(apploader) = apploader {
+    if let Some(mut apploader) = apploader {
         iso.seek(SeekFrom::Start(0x2440))?;
-
-        let mut f = std::fs::File::options()
-            .read(true)
-            .open(apploader)
-            .map_err(|e| OperateISOError::OpenError { path: apploader.into(), e })?;
-        std::io::copy(&mut f, &mut iso)?;
+        std::io::copy(&mut apploader, iso)?;
     }
 
-    if let Some(start_dol) = start_dol {
+    if let Some(mut start_dol) = start_dol {
         iso.seek(SeekFrom::Start(dol_offset as _))?;
+        std::io::copy(&mut start_dol, iso)?;
+    }
+
+    // scrub deleted files
+    // Done last, since the old fst refers to them until the new one is written.
+
+    for range in plan.scrub.iter() {
+        iso.seek(SeekFrom::Start(range.start))?;
+        std::io::copy(&mut std::io::repeat(0).take(range.end - range.start), iso)?;
+    }
+
+    Ok(())
+}
+
+/// Moves file contents together in FST order, starting at the first file, then truncates the ISO after the last file.
+///
+/// This merges fragmented free space left by repeated insertions and deletions.
+/// Files sharing contents continue to share them.
+pub fn defragment_iso(iso_path: &Path) -> Result<(), OperateISOError> {
+    use std::io::{Read, Write, Seek, SeekFrom};
+
+    let mut iso = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .open(iso_path)
+        .map_err(|e| OperateISOError::OpenError { path: iso_path.into(), e })?;
+
+    // read header ---------------------------------------------------------
+
+    let mut buf = [0u8; 12];
+    iso.seek(SeekFrom::Start(HEADER_INFO_OFFSET as _))?;
+    iso.read_exact(&mut buf)?;
+    let fst_offset = u32::from_be_bytes(buf[4..8].try_into().unwrap());
+    let fs_size = u32::from_be_bytes(buf[8..12].try_into().unwrap());
+
+    let mut fst = vec![0u8; fs_size as usize];
+    iso.seek(SeekFrom::Start(fst_offset as _))?;
+    iso.read_exact(&mut fst)?;
+
+    let entry_count = read_u32(&fst, 8).ok_or(OperateISOError::InvalidISO)?;
+    if entry_count as u64 * 0xC > fs_size as u64 { return Err(OperateISOError::InvalidISO); }
+
+    // plan new offsets -------------------------------------------------------
+
+    // (fst entry offsets, old offset, size, new offset), in fst order.
+    // Entries sharing contents are moved together.
+    let mut moves: Vec<(Vec<u32>, u32, u32, u32)> = Vec::new();
+    for entry_index in 1..entry_count {
+        let offset = entry_index * 0xC;
+        if fst[offset as usize] != 0 { continue; }
+
+        let file_offset = read_u32(&fst, offset+4).ok_or(OperateISOError::InvalidISO)?;
+        let file_size = read_u32(&fst, offset+8).ok_or(OperateISOError::InvalidISO)?;
+        match moves.iter_mut().find(|m| m.1 == file_offset && m.2 == file_size) {
+            Some(m) => m.0.push(offset),
+            None => moves.push((vec![offset], file_offset, file_size, 0)),
+        }
+    }
+
+    // empty files have no contents, so their offsets can be anything, such as 0
+    let fst_end = fst_offset as u64 + fs_size as u64;
+    let data_start = moves.iter().filter(|m| m.2 != 0).map(|m| m.1 as u64).min()
+        .unwrap_or(align(fst_end, FILE_CONTENTS_ALIGNMENT));
+    if data_start < fst_end { return Err(OperateISOError::InvalidISO); }
+
+    // files placed with less than the default alignment may end up further along
+    let mut data_end = data_start;
+    for m in moves.iter_mut() {
+        if m.2 == 0 {
+            // kept inside the truncated ISO without taking up any space
+            m.3 = data_end as u32;
+            continue;
+        }
+        let new_offset = align(data_end, FILE_CONTENTS_ALIGNMENT);
+        data_end = new_offset + m.2 as u64;
+        if data_end > 1 << 32 { return Err(OperateISOError::ISOTooLarge); }
+        m.3 = new_offset as u32;
+    }
+
+    // move contents ----------------------------------------------------------
+
+    // A file can only be written once its destination no longer overlaps contents that haven't moved yet.
+    // If every remaining file is blocked, one is held in memory to break the cycle.
+    let overlaps = |a: u32, a_size: u32, b: u32, b_size: u32| {
+        (a as u64) < b as u64 + b_size as u64 && (b as u64) < a as u64 + a_size as u64
+    };
+    let mut pending = (0..moves.len()).filter(|&i| moves[i].2 != 0 && moves[i].1 != moves[i].3).collect::<Vec<_>>();
+    let mut held: Vec<(usize, Vec<u8>)> = Vec::new();
+
+    while !pending.is_empty() || !held.is_empty() {
+        let blocked = |i: usize| {
+            let (_, _, size, new_offset) = moves[i];
+            pending.iter()
+                .filter(|&&j| j != i)
+                .any(|&j| overlaps(new_offset, size, moves[j].1, moves[j].2))
+        };
+
+        if let Some(h) = held.iter().position(|&(i, _)| !blocked(i)) {
+            let (i, contents) = held.remove(h);
+            iso.seek(SeekFrom::Start(moves[i].3 as _))?;
+            iso.write_all(&contents)?;
+        } else if let Some(p) = pending.iter().position(|&i| !blocked(i)) {
+            let i = pending.remove(p);
+            let (_, offset, size, new_offset) = moves[i];
+            let mut contents = vec![0u8; size as usize];
+            iso.seek(SeekFrom::Start(offset as _))?;
+            iso.read_exact(&mut contents)?;
+            iso.seek(SeekFrom::Start(new_offset as _))?;
+            iso.write_all(&contents)?;
+        } else {
+            let i = pending.remove(0);
+            let (_, offset, size, _) = moves[i];
+            let mut contents = vec![0u8; size as usize];
+            iso.seek(SeekFrom::Start(offset as _))?;
+            iso.read_exact(&mut contents)?;
+            held.push((i, contents));
+        }
+    }
+
+    // write fst --------------------------------------------------------------
+
+    for (entry_offsets, _, _, new_offset) in moves.iter() {
+        for &entry_offset in entry_offsets {
+            write_u32(&mut fst, entry_offset+4, *new_offset);
+        }
+    }
+
+    iso.seek(SeekFrom::Start(fst_offset as _))?;
+    iso.write_all(&fst)?;
+    iso.set_len(data_end)?;
+
+    Ok(())
+}
+
+/// Replaces 'Start.dol', moving the FST after it if the new DOL is larger than the space before the FST.
+///
+/// File contents are never moved. If the FST can't be moved without overlapping them, returns `DolTooLarge`
+/// with the space available for the DOL.
+pub fn replace_dol(iso_path: &Path, dol: &Path) -> Result<(), OperateISOError> {
+    use std::io::{Read, Write, Seek, SeekFrom};
+
+    let mut iso = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .open(iso_path)
+        .map_err(|e| OperateISOError::OpenError { path: iso_path.into(), e })?;
+
+    let dol_bytes = std::fs::read(dol)
+        .map_err(|e| OperateISOError::OpenError { path: dol.into(), e })?;
+
+    let mut buf = [0u8; 12];
+    iso.seek(SeekFrom::Start(HEADER_INFO_OFFSET as _))?;
+    iso.read_exact(&mut buf)?;
+    let dol_offset = u32::from_be_bytes(buf[0..4].try_into().unwrap());
+    let fst_offset = u32::from_be_bytes(buf[4..8].try_into().unwrap());
+    let fs_size = u32::from_be_bytes(buf[8..12].try_into().unwrap());
+
+    if fst_offset < dol_offset || fs_size < 0xC { return Err(OperateISOError::InvalidISO); }
+
+    let dol_end = dol_offset as u64 + dol_bytes.len() as u64;
+    if dol_end > fst_offset as u64 {
+        // the fst needs to move, which is only possible if it stays before the file contents
+        let mut fst = vec![0u8; fs_size as usize];
+        iso.seek(SeekFrom::Start(fst_offset as _))?;
+        iso.read_exact(&mut fst)?;
+
+        let entry_count = read_u32(&fst, 8).ok_or(OperateISOError::InvalidISO)?;
+        if entry_count as u64 * 0xC > fs_size as u64 { return Err(OperateISOError::InvalidISO); }
+        let mut data_start = ROM_SIZE;
+        for offset in (1..entry_count).map(|i| i * 0xC) {
+            if fst[offset as usize] != 0 { continue; }
+            // empty files have no contents for the fst to overlap, and their offsets can be anything, such as 0
+            if read_u32(&fst, offset+8).ok_or(OperateISOError::InvalidISO)? == 0 { continue; }
+            data_start = data_start.min(read_u32(&fst, offset+4).ok_or(OperateISOError::InvalidISO)?);
+        }
+
+        let new_fst_offset = align(dol_end, SEGMENT_ALIGNMENT);
+        if new_fst_offset + fs_size as u64 > data_start as u64 {
+            let available = data_start.saturating_sub(fs_size).saturating_sub(dol_offset);
+            return Err(OperateISOError::DolTooLarge { available, required: dol_bytes.len() as u64 });
+        }
+
+        iso.seek(SeekFrom::Start(new_fst_offset as _))?;
+        iso.write_all(&fst)?;
+        iso.seek(SeekFrom::Start((HEADER_INFO_OFFSET + 4) as _))?;
+        iso.write_all(&(new_fst_offset as u32).to_be_bytes())?;
+    }
+
+    iso.seek(SeekFrom::Start(dol_offset as _))?;
+    iso.write_all(&dol_bytes)?;
+
+    Ok(())
+}
+
+/// Replaces the contents of a file in the ISO.
+///
+/// If `data` is no larger than the existing file, it is written over the existing contents and only the size in the FST is changed.
+/// Otherwise, or if another file shares the existing contents, this falls back to inserting it with `operate_on_iso`.
+/// Returns `InvalidISOPath` if the file doesn't exist, rather than creating it.
+pub fn patch_file(iso_path: &Path, iso_file: &Path, data: &[u8]) -> Result<(), OperateISOError> {
+    use std::io::{Read, Write, Seek, SeekFrom};
+
+    let mut iso = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .open(iso_path)
+        .map_err(|e| OperateISOError::OpenError { path: iso_path.into(), e })?;
+
+    let mut buf = [0u8; 12];
+    iso.seek(SeekFrom::Start(HEADER_INFO_OFFSET as _))?;
+    iso.read_exact(&mut buf)?;
+    let fst_offset = u32::from_be_bytes(buf[4..8].try_into().unwrap());
+    let fs_size = u32::from_be_bytes(buf[8..12].try_into().unwrap());
+
+    let mut fst = vec![0u8; fs_size as usize];
+    iso.seek(SeekFrom::Start(fst_offset as _))?;
+    iso.read_exact(&mut fst)?;
+
+    // fst index, offset, and size of the file to patch, and the portions of every other file
+    let mut found = None;
+    let mut others = Vec::new();
+    let mut entry_index = 0u32;
+    for event in FstIter::new(&fst).map_err(|_| OperateISOError::InvalidISO)? {
+        match event.map_err(|_| OperateISOError::InvalidISO)? {
+            FstEvent::File { path, offset, size, .. } => {
+                entry_index += 1;
+                if found.is_none() && path == iso_file {
+                    found = Some((entry_index, offset, size));
+                } else {
+                    others.push(offset..offset.saturating_add(size));
+                }
+            }
+            FstEvent::EnterDir { .. } => entry_index += 1,
+            FstEvent::ExitDir => (),
+        }
+    }
+
+    let (entry_index, offset, size) = found.ok_or_else(|| OperateISOError::InvalidISOPath(iso_file.to_path_buf()))?;
+    let in_place = data.len() as u64 <= size as u64
+        && !others.iter().any(|other| other.start < offset.saturating_add(size) && offset < other.end);
+    if !in_place {
+        drop(iso);
+        return operate_on_iso(iso_path, &[IsoOp::InsertBytes { iso_path: iso_file, data }]);
+    }
+
+    iso.seek(SeekFrom::Start(offset as _))?;
+    iso.write_all(data)?;
+    iso.seek(SeekFrom::Start(fst_offset as u64 + entry_index as u64 * 0xC + 8))?;
+    iso.write_all(&(data.len() as u32).to_be_bytes())?;
+
+    Ok(())
+}
+
+/// Copies `iso_path` to `out_path`, dropping the padding after the last file.
+///
+/// The output is aligned to 0x800 bytes. The header does not record the disc size, so it is copied unchanged.
+pub fn shrink_iso(iso_path: &Path, out_path: &Path) -> Result<(), ShrinkISOError> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let iso_len = iso_path.metadata()?.len();
+    if iso_len < 0x2460 { return Err(ShrinkISOError::InvalidISO); }
+
+    let mut iso = std::fs::File::options()
+        .read(true)
+        .open(iso_path)
+        .map_err(|e| ShrinkISOError::OpenError { path: iso_path.into(), e })?;
+
+    // read header ---------------------------------------------------------
+
+    let mut buf = [0u8; 12];
+    iso.seek(SeekFrom::Start(HEADER_INFO_OFFSET as _))?;
+    iso.read_exact(&mut buf)?;
+    let dol_offset = u32::from_be_bytes(buf[0..4].try_into().unwrap());
+    let fst_offset = u32::from_be_bytes(buf[4..8].try_into().unwrap());
+    let fs_size = u32::from_be_bytes(buf[8..12].try_into().unwrap());
+
+    if fst_offset as u64 + fs_size as u64 > iso_len || fs_size < 0xC {
+        return Err(ShrinkISOError::InvalidISO);
+    }
+
+    let mut apploader_header = [0u8; AppLoader::HEADER_SIZE];
+    iso.seek(SeekFrom::Start(0x2440))?;
+    iso.read_exact(&mut apploader_header)?;
+    let mut end = 0x2440 + AppLoader::parse(&apploader_header).unwrap().total_size();
+
+    let mut dol_header = [0u8; Dol::HEADER_SIZE];
+    iso.seek(SeekFrom::Start(dol_offset as _))?;
+    iso.read_exact(&mut dol_header)?;
+    let dol = Dol::parse(&dol_header).ok_or(ShrinkISOError::InvalidISO)?;
+    end = end.max(dol_offset as u64 + dol.total_size() as u64);
+
+    end = end.max(fst_offset as u64 + fs_size as u64);
+
+    // find end of file contents -----------------------------------------------
+
+    let mut fst = vec![0u8; fs_size as usize];
+    iso.seek(SeekFrom::Start(fst_offset as _))?;
+    iso.read_exact(&mut fst)?;
 
-        let mut f = std::fs::File::options()
-            .read(true)
-            .open(start_dol)
-            .map_err(|e| OperateISOError::OpenError { path: start_dol.into(), e })?;
-        std::io::copy(&mut f, &mut iso)?;
+    for event in FstIter::new(&fst).map_err(|_| ShrinkISOError::InvalidISO)? {
+        if let FstEvent::File { offset, size, .. } = event.map_err(|_| ShrinkISOError::InvalidISO)? {
+            end = end.max(offset as u64 + size as u64);
+        }
     }
 
+    let end = (end + 0x7FF) & !0x7FF;
+    if end > iso_len { return Err(ShrinkISOError::InvalidISO); }
+
+    // copy ------------------------------------------------------------------
+
+    let mut out = std::fs::File::create(out_path)
+        .map_err(|e| ShrinkISOError::OpenError { path: out_path.into(), e })?;
+    iso.seek(SeekFrom::Start(0))?;
+    std::io::copy(&mut iso.take(end), &mut out)?;
+
     Ok(())
 }
 
-fn read_u32(iso: &[u8], offset: u32) -> u32 {
-    u32::from_be_bytes(iso[offset as usize..][..4].try_into().unwrap())
+/// Copies `iso_path` to `out_path` as if it were extracted and rebuilt, without writing any files to disk.
+///
+/// The FST is rebuilt and file contents are copied directly from `iso_path`, one after another at the usual alignment,
+/// dropping any gaps and padding between them. Files sharing contents continue to share them.
+/// Only `dedup`, `sort_order`, `strip_junk`, and `log` in `options` apply, since there is no root directory.
+pub fn repack_iso(iso_path: &Path, out_path: &Path, options: &WriteOptions) -> Result<(), RepackISOError> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let mut iso = std::fs::File::options()
+        .read(true)
+        .open(iso_path)
+        .map_err(|e| RepackISOError::OpenError { path: iso_path.into(), e })?;
+    let iso_len = iso.metadata()?.len();
+
+    // read header ---------------------------------------------------------
+
+    let mut buf = [0u8; 12];
+    iso.seek(SeekFrom::Start(HEADER_INFO_OFFSET as _))?;
+    iso.read_exact(&mut buf)?;
+    let dol_offset = u32::from_be_bytes(buf[0..4].try_into().unwrap());
+    let fst_offset = u32::from_be_bytes(buf[4..8].try_into().unwrap());
+    let fs_size = u32::from_be_bytes(buf[8..12].try_into().unwrap());
+
+    if fst_offset as u64 + fs_size as u64 > iso_len || fs_size < 0xC {
+        return Err(RepackISOError::InvalidISO);
+    }
+
+    let mut system_files = [(0, 0); 3];
+    for (portion, name) in system_files.iter_mut().zip(["ISO.hdr", "AppLoader.ldr", "Start.dol"]) {
+        *portion = special_file_portion(&mut iso, Path::new(name), dol_offset, fst_offset)
+            .map_err(|e| match e {
+                ReadISOFilesError::IOError(e) => RepackISOError::IOError(e),
+                _ => RepackISOError::InvalidISO,
+            })?
+            .ok_or(RepackISOError::InvalidISO)?;
+    }
+
+    // read fst ------------------------------------------------------------
+
+    let mut fst = vec![0u8; fs_size as usize];
+    iso.seek(SeekFrom::Start(fst_offset as _))?;
+    iso.read_exact(&mut fst)?;
+
+    // directories being read, innermost last
+    let mut dirs: Vec<Vec<(String, RepackEntry)>> = vec![Vec::new()];
+    let mut dir_names = Vec::new();
+    let mut entry_count = 0;
+    let mut string_length = 0;
+    for event in FstIter::new(&fst).map_err(|_| RepackISOError::InvalidISO)? {
+        let event = event.map_err(|_| RepackISOError::InvalidISO)?;
+        if !matches!(event, FstEvent::ExitDir) { entry_count += 1; }
+        match event {
+            FstEvent::File { name, offset, size, .. } => {
+                if offset as u64 + size as u64 > iso_len { return Err(RepackISOError::InvalidISO); }
+                string_length += name.len() as u32 + 1;
+                dirs.last_mut().unwrap().push((name.to_string(), RepackEntry::File { offset, size }));
+            }
+            FstEvent::EnterDir { name, .. } => {
+                string_length += name.len() as u32 + 1;
+                dirs.push(Vec::new());
+                dir_names.push(name.to_string());
+            }
+            FstEvent::ExitDir => {
+                let dir = dirs.pop().unwrap();
+                dirs.last_mut().unwrap().push((dir_names.pop().unwrap(), RepackEntry::Dir(dir)));
+            }
+        }
+    }
+    let root = dirs.pop().unwrap();
+
+    // write system files ----------------------------------------------------
+
+    let mut out = std::fs::File::create(out_path)
+        .map_err(|e| RepackISOError::OpenError { path: out_path.into(), e })?;
+    let write_error = |e: WriteISOError| match e {
+        WriteISOError::WriteFileError(e) | WriteISOError::ReadFileError { e, .. } => RepackISOError::IOError(e),
+        WriteISOError::FilenameTooLong(path) => RepackISOError::FilenameTooLong(path),
+        _ => RepackISOError::ISOTooLarge,
+    };
+
+    // header, apploader, and dol, aligned like `write_iso` does. The apploader directly follows the header.
+    let mut pos = 0u64;
+    let mut new_dol_offset = 0;
+    for (i, &(offset, size)) in system_files.iter().enumerate() {
+        if i == 2 { new_dol_offset = pos; }
+        iso.seek(SeekFrom::Start(offset as _))?;
+        let copied = std::io::copy(&mut FilePortion { iso: &mut iso, size: size as _ }, &mut out)?;
+        if copied != size as u64 { return Err(RepackISOError::InvalidISO); }
+        pos += copied;
+        if i == 0 { continue; }
+        let rounded_size = align(pos, SEGMENT_ALIGNMENT);
+        pad_to(&mut out, &mut pos, rounded_size).map_err(write_error)?;
+    }
+
+    // write fst and contents ------------------------------------------------
+
+    let new_fst_offset = pos;
+    let fs_end = new_fst_offset + (0xC*(entry_count+1) + string_length) as u64;
+    pad_to(&mut out, &mut pos, fs_end).map_err(write_error)?;
+
+    let source = RepackSource { iso: &mut iso, iso_path };
+    let mut writer = FstWriter::new(source, options, entry_count, string_length, fs_end);
+    writer.write_dir(root, 0).map_err(write_error)?;
+    let FstWriter { fst: new_fst, data_end, files, .. } = writer;
+
+    for file in files.iter().filter(|f| !f.shared) {
+        let (offset, size) = file.file;
+        iso.seek(SeekFrom::Start(offset as _))?;
+        out.seek(SeekFrom::Start(file.offset as _))?;
+        let copied = std::io::copy(&mut FilePortion { iso: &mut iso, size: size as _ }, &mut out)?;
+        if copied != size as u64 { return Err(RepackISOError::InvalidISO); }
+    }
+
+    finish_iso(&mut out, &new_fst, new_dol_offset, new_fst_offset, data_end, !options.strip_junk).map_err(write_error)
+}
+
+enum RepackEntry {
+    File { offset: u32, size: u32 },
+    Dir(Vec<(String, RepackEntry)>),
 }
 
-fn write_u32(iso: &mut Vec<u8>, offset: u32, n: u32) {
+/// Reads the FST of the ISO being repacked. Files are identified by their offset and size in it.
+struct RepackSource<'a> {
+    iso: &'a mut std::fs::File,
+    iso_path: &'a Path,
+}
+
+impl FstSource for RepackSource<'_> {
+    type File = (u32, u32);
+    type Dir = Vec<(String, RepackEntry)>;
+
+    fn read_dir(&mut self, entries: Vec<(String, RepackEntry)>) -> Result<Vec<SourceEntry<Self>>, WriteISOError> {
+        Ok(entries.into_iter()
+            .map(|(name, entry)| SourceEntry {
+                name,
+                kind: match entry {
+                    RepackEntry::File { offset, size } => SourceKind::File { file: (offset, size), size },
+                    RepackEntry::Dir(sub_dir) => SourceKind::Dir(sub_dir),
+                },
+                // there are no modification times in an iso, so `SortOrder::Modified` keeps the original order
+                modified: None,
+            })
+            .collect())
+    }
+
+    fn hash(&mut self, &(offset, size): &(u32, u32), _size: u32) -> Result<blake3::Hash, WriteISOError> {
+        use std::io::{Seek, SeekFrom};
+
+        let read_error = |e| WriteISOError::ReadFileError { path: self.iso_path.to_path_buf(), e };
+        self.iso.seek(SeekFrom::Start(offset as _)).map_err(read_error)?;
+        let mut hasher = blake3::Hasher::new();
+        std::io::copy(&mut FilePortion { iso: &mut *self.iso, size: size as _ }, &mut hasher).map_err(read_error)?;
+        Ok(hasher.finalize())
+    }
+
+    /// Files which already shared contents keep sharing them.
+    fn shared_key(&self, &region: &(u32, u32)) -> Option<(u32, u32)> {
+        Some(region)
+    }
+}
+
+/// Returns None if the read would go past the end of `iso`.
+fn read_u32(iso: &[u8], offset: u32) -> Option<u32> {
+    let bytes = iso.get(offset as usize..)?.get(..4)?;
+    Some(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn write_u32(iso: &mut [u8], offset: u32, n: u32) {
     iso[offset as usize..][..4].copy_from_slice(&n.to_be_bytes());
 }
 
+/// Returns None if the name is out of bounds, unterminated, or not valid UTF-8.
 fn read_filename(iso: &[u8], offset: u32) -> Option<&str> {
-    std::ffi::CStr::from_bytes_until_nul(&iso[offset as usize..]).ok()?.to_str().ok()
+    std::ffi::CStr::from_bytes_until_nul(iso.get(offset as usize..)?).ok()?.to_str().ok()
 }
 
-/// rounds up to nearest multiple of 1<<bits
-fn align(n: u32, bits: u32) -> u32 { 
-    let mask = (1 << bits) - 1;
-    (n + mask) & !mask
+/// Rounds `n` up to the nearest multiple of `1 << bits`.
+///
+/// Positions in the ISO are `u64`, so rounding up near the 4GiB limit of FST offsets doesn't wrap.
+///
+/// ```
+/// assert_eq!(gc_fst::align(0x1234, 15), 0x8000);
+/// assert_eq!(gc_fst::align(0x8000, 15), 0x8000);
+/// assert_eq!(gc_fst::align(0xFFFF_FFF0, 15), 0x1_0000_0000);
+/// ```
+pub fn align(n: u64, bits: u32) -> u64 {
+    let mask = (1u64 << bits) - 1;
+    n.saturating_add(mask) & !mask
 }
@@ -1,15 +1,34 @@
 use gc_fst::*;
 
-const HELP: &'static str = 
-"Usage: gc_fst extract <iso path>
-       gc_fst rebuild <root path> [iso path]
+const HELP: &str = 
+"Usage: gc_fst extract <iso, tgc, or rvz path> [--layout|-l] [--toc] [--mtimes] [--split-header] [--force|-f] [--flat <separator>] [--verbose|-v]
+       gc_fst rebuild <root path or zip> [iso path] [--dedup] [--strip-junk] [--follow-symlinks] [--dry-run] [--self-check] [--verbose|-v]
+           [--case-sensitive | --unsorted | --by-mtime] [--segment-alignment <bits>]
+           [--header <path>] [--boot-bin <path>] [--bi2-bin <path>] [--apploader <path>] [--dol <path>]
        gc_fst get-header <ISO.hdr path | iso path>
-       gc_fst set-header <ISO.hdr path | iso path> <game ID> [game title]
+       gc_fst set-header <ISO.hdr path | iso path> <game ID> [game title] [--full-title <title>] [--disc <n>] [--region <n>] [--sync-banner]
+       gc_fst info <iso path>
        gc_fst read <iso path> [ <path in iso> <path to file> ] * n
+       gc_fst read-glob <iso path> <pattern> <out dir>
+       gc_fst diff <iso path> <iso path>
+       gc_fst banner <iso path> <png path>
+       gc_fst set-banner-image <iso path> <png path>
+       gc_fst checksum <iso path> [iso path] * n
+       gc_fst hash <iso path> [path in iso] * n
+       gc_fst replace-dol <iso path> <dol path>
+       gc_fst shrink <iso path> <out path>
+       gc_fst repack <iso path> <out path> [--dedup] [--strip-junk] [--case-sensitive | --unsorted]
+       gc_fst defrag <iso path>
+       gc_fst freespace <iso path>
+       gc_fst verify <iso path>
        gc_fst tree <iso path> [--size|-s] [--offset|-o] [--hex|-x] [--directories|-d] [--filename|-f]
-       gc_fst fs <iso path> [
+       gc_fst fs <iso path> [--allow-grow] [--append-only] [--scrub] [--dry-run] [--verbose|-v] [
            insert <path in iso> <path to file>
+           insert-at <path in iso> <path to file> <offset>
+           insert-dir <path in iso> <path to directory>
            delete <path in iso>
+           rename <old path in iso> <new path in iso>
+           mkdir <path in iso>
        ] * n";
 
 fn usage() -> ! {
@@ -26,6 +45,14 @@ macro_rules! unwrap_usage {
     }
 }
 
+/// Passed as the `log` option with `--verbose`.
+fn log_entry(entry: LogEntry) {
+    match entry {
+        LogEntry::File { path, offset, size } => eprintln!("{:#010x}\t{}\t{}", offset, size, path.display()),
+        LogEntry::Dir { path } => eprintln!("\t\t{}/", path.display()),
+    }
+}
+
 fn main() {
     let args = std::env::args().collect::<Vec<_>>();
     match args.get(1).map(|s| s.as_str()) {
@@ -87,20 +114,474 @@ fn main() {
                 },
             }
         }
+        Some("replace-dol") => {
+            let iso = unwrap_usage!(args.get(2).map(|s| s.as_str()));
+            let dol = unwrap_usage!(args.get(3).map(|s| s.as_str()));
+
+            match replace_dol(std::path::Path::new(iso), std::path::Path::new(dol)) {
+                Ok(()) => (),
+                Err(OperateISOError::IOError(e)) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                },
+                Err(OperateISOError::OpenError { path, e }) => {
+                    eprintln!("Error: could not open file '{}': {}", path.display(), e);
+                    std::process::exit(1);
+                },
+                Err(OperateISOError::InvalidISO) => {
+                    eprintln!("Error: file is not an iso or is corrupted");
+                    std::process::exit(1);
+                }
+                Err(OperateISOError::DolTooLarge { available, required }) => {
+                    eprintln!("Error: Start.dol is too large. {} bytes available, {} bytes required.", available, required);
+                    std::process::exit(1);
+                }
+                Err(e) => {
+                    eprintln!("Error: {:?}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some("defrag") => {
+            let iso = unwrap_usage!(args.get(2).map(|s| s.as_str()));
+
+            match defragment_iso(std::path::Path::new(iso)) {
+                Ok(()) => (),
+                Err(OperateISOError::IOError(e)) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                },
+                Err(OperateISOError::OpenError { path, e }) => {
+                    eprintln!("Error: could not open file '{}': {}", path.display(), e);
+                    std::process::exit(1);
+                },
+                Err(OperateISOError::InvalidISO) => {
+                    eprintln!("Error: file is not an iso or is corrupted");
+                    std::process::exit(1);
+                }
+                Err(e) => {
+                    eprintln!("Error: {:?}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some("freespace") => {
+            let iso = unwrap_usage!(args.get(2).map(|s| s.as_str()));
+
+            match free_regions(std::path::Path::new(iso)) {
+                Ok(regions) => {
+                    for region in regions.iter() {
+                        println!("{:#010x}..{:#010x} {}", region.start, region.end, region.end - region.start);
+                    }
+                    let total = regions.iter().map(|r| (r.end - r.start) as u64).sum::<u64>();
+                    println!("total: {}", total);
+                },
+                Err(OperateISOError::IOError(e)) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                },
+                Err(OperateISOError::OpenError { path, e }) => {
+                    eprintln!("Error: could not open file '{}': {}", path.display(), e);
+                    std::process::exit(1);
+                },
+                Err(OperateISOError::InvalidISO) => {
+                    eprintln!("Error: file is not an iso or is corrupted");
+                    std::process::exit(1);
+                }
+                Err(e) => {
+                    eprintln!("Error: {:?}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some("shrink") => {
+            let iso = unwrap_usage!(args.get(2).map(|s| s.as_str()));
+            let out = unwrap_usage!(args.get(3).map(|s| s.as_str()));
+
+            match shrink_iso(std::path::Path::new(iso), std::path::Path::new(out)) {
+                Ok(()) => (),
+                Err(ShrinkISOError::IOError(e)) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                },
+                Err(ShrinkISOError::OpenError { path, e }) => {
+                    eprintln!("Error: could not open file '{}': {}", path.display(), e);
+                    std::process::exit(1);
+                },
+                Err(ShrinkISOError::InvalidISO) => {
+                    eprintln!("Error: file is not an iso or is corrupted");
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some("repack") => {
+            let iso = unwrap_usage!(args.get(2).map(|s| s.as_str()));
+            let out = unwrap_usage!(args.get(3).map(|s| s.as_str()));
+
+            let mut options = WriteOptions::default();
+            for arg in &args[4..] {
+                match arg.as_str() {
+                    "--dedup" => options.dedup = true,
+                    "--strip-junk" => options.strip_junk = true,
+                    "--case-sensitive" => options.sort_order = SortOrder::CaseSensitive,
+                    "--unsorted" => options.sort_order = SortOrder::Unsorted,
+                    _ => {
+                        eprintln!("Error: unknown argument '{}'\n\n{}", arg, HELP);
+                        std::process::exit(1);
+                    }
+                }
+            }
+
+            match repack_iso(std::path::Path::new(iso), std::path::Path::new(out), &options) {
+                Ok(()) => (),
+                Err(RepackISOError::IOError(e)) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                },
+                Err(RepackISOError::OpenError { path, e }) => {
+                    eprintln!("Error: could not open file '{}': {}", path.display(), e);
+                    std::process::exit(1);
+                },
+                Err(RepackISOError::InvalidISO) => {
+                    eprintln!("Error: file is not an iso or is corrupted");
+                    std::process::exit(1);
+                }
+                Err(RepackISOError::ISOTooLarge) => {
+                    eprintln!("Error: Resulting ISO is too large");
+                    std::process::exit(1);
+                }
+                Err(RepackISOError::FilenameTooLong(path)) => {
+                    eprintln!("Error: '{}' does not fit in the table of contents. The names in the iso are too long", path.display());
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some("verify") => {
+            let iso = unwrap_usage!(args.get(2).map(|s| s.as_str()));
+
+            let warnings = match verify_iso(std::path::Path::new(iso)) {
+                Ok(w) => w,
+                Err(ReadISOFilesError::IOError(e)) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                },
+                Err(ReadISOFilesError::InvalidISO) => {
+                    eprintln!("Error: file is not an iso or is corrupted");
+                    std::process::exit(1);
+                },
+                Err(ReadISOFilesError::InvalidFSPath(path)) => {
+                    eprintln!("Error: file path '{}' does not exist", path.display());
+                    std::process::exit(1);
+                }
+            };
+
+            for w in warnings.iter() {
+                let kind = if w.is_fatal() { "Error" } else { "Warning" };
+                match w {
+                    VerifyWarning::DolOutOfBounds { dol_offset } =>
+                        eprintln!("{}: dol offset 0x{:x} is past the end of the iso", kind, dol_offset),
+                    VerifyWarning::FstOutOfBounds { fst_offset, fs_size } =>
+                        eprintln!("{}: fst at 0x{:x} with size 0x{:x} is past the end of the iso", kind, fst_offset, fs_size),
+                    VerifyWarning::EntryCountOutOfRange { entry_count } =>
+                        eprintln!("{}: fst entry count {} does not fit in the fst", kind, entry_count),
+                    VerifyWarning::FileOutOfBounds { path, offset, size } =>
+                        eprintln!("{}: file '{}' at 0x{:x} with size 0x{:x} is past the end of the iso", kind, path.display(), offset, size),
+                    VerifyWarning::FilesOverlap { a, b } =>
+                        eprintln!("{}: files '{}' and '{}' overlap", kind, a.display(), b.display()),
+                    VerifyWarning::NextIndexOutOfRange { path, next_idx } =>
+                        eprintln!("{}: directory '{}' has invalid next index {}", kind, path.display(), next_idx),
+                    VerifyWarning::UnterminatedName { entry_index } =>
+                        eprintln!("{}: fst entry {} or its name is invalid", kind, entry_index),
+                }
+            }
+
+            if warnings.iter().any(|w| w.is_fatal()) {
+                std::process::exit(1);
+            }
+        }
         Some("read") => {
             let iso = unwrap_usage!(args.get(2).map(|s| s.as_str()));
-            let mut files = Vec::with_capacity(args[3..].len() / 2);
 
-            let mut i = 3;
-            while i < args.len() {
-                let iso_path = std::path::Path::new(&args[i]);
-                let read_path = std::path::Path::new(unwrap_usage!(args.get(i+1)));
-                files.push((iso_path, read_path));
-                i += 2;
+            // without any files, list the paths that can be read, like `tree`
+            if args.len() == 3 {
+                let options = TreeOptions {
+                    print_directories  : false,
+                    print_files        : true,
+                    print_file_offsets : false,
+                    print_file_sizes   : false,
+                    print_full_paths   : true,
+                    print_hex          : false,
+                };
+                match tree_iso(std::path::Path::new(iso), &options) {
+                    Ok(()) => {},
+                    Err(TreeISOError::IOError(e)) => {
+                        eprintln!("Error: {}", e);
+                        std::process::exit(1);
+                    }
+                    Err(TreeISOError::InvalidISO) => {
+                        eprintln!("Error: file is not an iso or is corrupted");
+                        std::process::exit(1);
+                    },
+                    Err(TreeISOError::OpenError { path, e }) => {
+                        eprintln!("Error: could not open file '{}': {}", path.display(), e);
+                        std::process::exit(1);
+                    },
+                }
+            } else {
+                let mut files = Vec::with_capacity(args[3..].len() / 2);
+
+                let mut i = 3;
+                while i < args.len() {
+                    let iso_path = std::path::Path::new(&args[i]);
+                    let read_path = std::path::Path::new(unwrap_usage!(args.get(i+1)));
+                    files.push((iso_path, read_path));
+                    i += 2;
+                }
+
+                match read_iso_files(std::path::Path::new(iso), &files) {
+                    Ok(found) => {
+                        for (path, _) in found.iter().filter(|(_, found)| !found) {
+                            eprintln!("Warning: file path '{}' does not exist", path.display());
+                        }
+                    },
+                    Err(ReadISOFilesError::IOError(e)) => {
+                        eprintln!("Error: {}", e);
+                        std::process::exit(1);
+                    },
+                    Err(ReadISOFilesError::InvalidISO) => {
+                        eprintln!("Error: file is not an iso or is corrupted");
+                        std::process::exit(1);
+                    },
+                    Err(ReadISOFilesError::InvalidFSPath(path)) => {
+                        eprintln!("Error: file path '{}' does not exist", path.display());
+                        std::process::exit(1);
+                    }
+                }
             }
+        }
+        Some("read-glob") => {
+            let iso = unwrap_usage!(args.get(2).map(|s| s.as_str()));
+            let pattern = unwrap_usage!(args.get(3).map(|s| s.as_str()));
+            let out_dir = unwrap_usage!(args.get(4).map(|s| s.as_str()));
 
-            match read_iso_files(std::path::Path::new(iso), &files) {
-                Ok(()) => {},
+            match read_iso_glob(std::path::Path::new(iso), pattern, std::path::Path::new(out_dir)) {
+                Ok(extracted) => {
+                    if extracted.is_empty() {
+                        eprintln!("Warning: no files match '{}'", pattern);
+                    }
+                },
+                Err(ReadISOFilesError::IOError(e)) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                },
+                Err(ReadISOFilesError::InvalidISO) => {
+                    eprintln!("Error: file is not an iso or is corrupted");
+                    std::process::exit(1);
+                },
+                Err(ReadISOFilesError::InvalidFSPath(path)) => {
+                    eprintln!("Error: file path '{}' does not exist", path.display());
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some("banner") => {
+            let iso = unwrap_usage!(args.get(2).map(|s| s.as_str()));
+            let out = unwrap_usage!(args.get(3).map(|s| s.as_str()));
+
+            let banner = match extract_banner(std::path::Path::new(iso)) {
+                Ok(banner) => banner,
+                Err(ReadISOFilesError::IOError(e)) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                },
+                Err(ReadISOFilesError::InvalidISO) => {
+                    eprintln!("Error: file is not an iso, or its opening.bnr is not a valid banner");
+                    std::process::exit(1);
+                },
+                Err(ReadISOFilesError::InvalidFSPath(_)) => {
+                    eprintln!("Error: iso does not contain an opening.bnr");
+                    std::process::exit(1);
+                }
+            };
+
+            #[cfg(feature = "png")]
+            if let Err(e) = lodepng::encode32_file(out, &banner.to_rgba8()[..], 96, 32) {
+                eprintln!("Error: Could not write png '{}'", e);
+                std::process::exit(1);
+            }
+            #[cfg(not(feature = "png"))]
+            {
+                let _ = (banner, out);
+                eprintln!("Error: gc_fst was built without png support. Rebuild it with '--features png'");
+                std::process::exit(1);
+            }
+        }
+        Some("set-banner-image") => {
+            let iso = unwrap_usage!(args.get(2).map(|s| s.as_str()));
+            let png = unwrap_usage!(args.get(3).map(|s| s.as_str()));
+
+            #[cfg(feature = "png")]
+            {
+                let banner = {
+                    let png = match lodepng::decode32_file(png) {
+                        Ok(png) => png,
+                        Err(e) => {
+                            eprintln!("Error: Could not read png '{}'", e);
+                            std::process::exit(1);
+                        }
+                    };
+                    let png_bytes: &[u8] = lodepng::bytemuck::cast_slice(png.buffer.as_slice());
+                    match RGB5A1Image::from_rgba8_dynamic(png_bytes, png.width as u32, png.height as u32) {
+                        Ok(banner) => banner,
+                        Err(_) => {
+                            eprintln!("Error: banner image is {}x{}, but must be 96x32", png.width, png.height);
+                            std::process::exit(1);
+                        }
+                    }
+                };
+
+                match replace_banner_image(std::path::Path::new(iso), &banner) {
+                    Ok(()) => (),
+                    Err(OperateISOError::IOError(e)) => {
+                        eprintln!("Error: {}", e);
+                        std::process::exit(1);
+                    },
+                    Err(OperateISOError::OpenError { path, e }) => {
+                        eprintln!("Error: could not open file '{}': {}", path.display(), e);
+                        std::process::exit(1);
+                    },
+                    Err(OperateISOError::InvalidISO) => {
+                        eprintln!("Error: file is not an iso, or its opening.bnr is not a valid banner");
+                        std::process::exit(1);
+                    }
+                    Err(OperateISOError::InvalidFSPath(_)) => {
+                        eprintln!("Error: iso does not contain an opening.bnr");
+                        std::process::exit(1);
+                    }
+                    Err(e) => {
+                        eprintln!("Error: {:?}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            #[cfg(not(feature = "png"))]
+            {
+                let _ = (iso, png);
+                eprintln!("Error: gc_fst was built without png support. Rebuild it with '--features png'");
+                std::process::exit(1);
+            }
+        }
+        Some("info") => {
+            let iso = unwrap_usage!(args.get(2).map(|s| s.as_str()));
+
+            match iso_info(std::path::Path::new(iso)) {
+                Ok(info) => {
+                    println!("game id: {}", String::from_utf8_lossy(&info.game_id));
+                    println!("title: {}", info.title);
+                    println!("region: {}", info.region);
+                    println!("country: {}", Country::from_game_id(&info.game_id).name());
+                    println!("files: {}", info.file_count);
+                    println!("used bytes: {}", info.used_bytes);
+                    if let Some((path, size)) = info.largest_file {
+                        println!("largest file: {}", path.display());
+                        println!("largest file size: {}", size);
+                    }
+                    println!("fst size: {}", info.fs_size);
+                    println!("free bytes: {}", info.free_bytes);
+                    if let Ok(apploader) = read_apploader(std::path::Path::new(iso)) {
+                        println!("apploader version: {}", apploader.version);
+                        println!("apploader entry point: {:#010x}", apploader.entry_point);
+                    }
+                },
+                Err(ReadISOFilesError::IOError(e)) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                },
+                Err(ReadISOFilesError::InvalidISO) => {
+                    eprintln!("Error: file is not an iso or is corrupted");
+                    std::process::exit(1);
+                },
+                Err(ReadISOFilesError::InvalidFSPath(path)) => {
+                    eprintln!("Error: file path '{}' does not exist", path.display());
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some("checksum") => {
+            let iso = unwrap_usage!(args.get(2).map(|s| s.as_str()));
+
+            #[cfg(feature = "hashes")]
+            let hex = |bytes: &[u8]| bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+            #[cfg(feature = "hashes")]
+            if args.len() == 3 {
+                match iso_hashes(std::path::Path::new(iso)) {
+                    Ok(hashes) => {
+                        println!("crc32: {:08x}", hashes.crc32);
+                        println!("md5: {}", hex(&hashes.md5));
+                        println!("sha1: {}", hex(&hashes.sha1));
+                    },
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        std::process::exit(1);
+                    },
+                }
+            } else {
+                // one line per iso, like sha1sum
+                let paths = args[2..].iter().map(std::path::Path::new).collect::<Vec<_>>();
+                let mut failed = false;
+                for (path, hashes) in verify_library(&paths) {
+                    match hashes {
+                        Ok(hashes) => println!("{:08x} {} {} {}", hashes.crc32, hex(&hashes.md5), hex(&hashes.sha1), path.display()),
+                        Err(e) => {
+                            eprintln!("Error: '{}': {}", path.display(), e);
+                            failed = true;
+                        }
+                    }
+                }
+                if failed { std::process::exit(1); }
+            }
+            #[cfg(not(feature = "hashes"))]
+            {
+                let _ = iso;
+                eprintln!("Error: gc_fst was built without checksum support. Rebuild it with '--features hashes'");
+                std::process::exit(1);
+            }
+        }
+        Some("diff") => {
+            let a = unwrap_usage!(args.get(2).map(|s| s.as_str()));
+            let b = unwrap_usage!(args.get(3).map(|s| s.as_str()));
+
+            match diff_isos(std::path::Path::new(a), std::path::Path::new(b)) {
+                Ok(diff) => {
+                    for path in diff.removed.iter() { println!("- {}", path.display()); }
+                    for path in diff.added.iter() { println!("+ {}", path.display()); }
+                    for path in diff.changed.iter() { println!("~ {}", path.display()); }
+                },
+                Err(ReadISOFilesError::IOError(e)) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                },
+                Err(ReadISOFilesError::InvalidISO) => {
+                    eprintln!("Error: file is not an iso or is corrupted");
+                    std::process::exit(1);
+                },
+                Err(ReadISOFilesError::InvalidFSPath(path)) => {
+                    eprintln!("Error: file path '{}' does not exist", path.display());
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some("hash") => {
+            let iso = unwrap_usage!(args.get(2).map(|s| s.as_str()));
+            let files = args[3..].iter().map(std::path::Path::new).collect::<Vec<_>>();
+
+            match hash_iso_files(std::path::Path::new(iso), &files) {
+                Ok(hashes) => {
+                    for (path, hash) in hashes {
+                        let hex = hash.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+                        println!("{}  {}", hex, path.display());
+                    }
+                },
                 Err(ReadISOFilesError::IOError(e)) => {
                     eprintln!("Error: {}", e);
                     std::process::exit(1);
@@ -119,10 +600,32 @@ fn main() {
             let iso = unwrap_usage!(args.get(2).map(|s| s.as_str()));
 
             let mut cmds = Vec::with_capacity(args[3..].len() / 2);
+            let mut options = OperateOptions::default();
+            let mut dry_run = false;
 
             let mut i = 3;
             while i < args.len() {
                 match args[i].as_str() {
+                    "--allow-grow" => {
+                        options.allow_grow = true;
+                        i += 1;
+                    }
+                    "--append-only" => {
+                        options.append_only = true;
+                        i += 1;
+                    }
+                    "--scrub" => {
+                        options.scrub_deleted = true;
+                        i += 1;
+                    }
+                    "--verbose" | "-v" => {
+                        options.log = Some(&log_entry);
+                        i += 1;
+                    }
+                    "--dry-run" => {
+                        dry_run = true;
+                        i += 1;
+                    }
                     "insert" => {
                         cmds.push(IsoOp::Insert {
                             iso_path: std::path::Path::new(unwrap_usage!(args.get(i+1))),
@@ -130,17 +633,73 @@ fn main() {
                         });
                         i += 3;
                     },
+                    "insert-at" => {
+                        let offset = unwrap_usage!(args.get(i+3));
+                        // offsets are usually given in hex, as printed by 'tree --offset --hex'
+                        let parsed = match offset.strip_prefix("0x") {
+                            Some(hex) => u32::from_str_radix(hex, 16),
+                            None => offset.parse::<u32>(),
+                        };
+                        let offset = match parsed {
+                            Ok(offset) => offset,
+                            Err(_) => {
+                                eprintln!("Error: Invalid offset '{}'", offset);
+                                std::process::exit(1);
+                            }
+                        };
+                        cmds.push(IsoOp::InsertAt {
+                            iso_path: std::path::Path::new(unwrap_usage!(args.get(i+1))),
+                            input_path: std::path::Path::new(unwrap_usage!(args.get(i+2))),
+                            offset,
+                        });
+                        i += 4;
+                    },
+                    "insert-dir" => {
+                        cmds.push(IsoOp::InsertDir {
+                            iso_path: std::path::Path::new(unwrap_usage!(args.get(i+1))),
+                            input_dir: std::path::Path::new(unwrap_usage!(args.get(i+2))),
+                        });
+                        i += 3;
+                    },
                     "delete" => {
                         cmds.push(IsoOp::Delete {
                             iso_path: std::path::Path::new(unwrap_usage!(args.get(i+1))),
                         });
                         i += 2;
                     }
+                    "mkdir" => {
+                        cmds.push(IsoOp::Mkdir {
+                            iso_path: std::path::Path::new(unwrap_usage!(args.get(i+1))),
+                        });
+                        i += 2;
+                    }
+                    "rename" => {
+                        cmds.push(IsoOp::Rename {
+                            from: std::path::Path::new(unwrap_usage!(args.get(i+1))),
+                            to: std::path::Path::new(unwrap_usage!(args.get(i+2))),
+                        });
+                        i += 3;
+                    }
                     _ => usage()
                 }
             }
 
-            match operate_on_iso(std::path::Path::new(iso), &cmds) {
+            let result = if dry_run {
+                plan_operations_with_options(std::path::Path::new(iso), &cmds, &options).and_then(|plan| {
+                    for insertion in plan.insertions.iter() {
+                        println!("{:#010x}\t{}\t{}", insertion.offset, insertion.size, insertion.iso_path.display());
+                    }
+                    println!("table of contents: {} bytes, {} bytes available", plan.fs_size, plan.fs_capacity);
+                    match plan.fits {
+                        true => Ok(()),
+                        false => Err(OperateISOError::TOCTooLarge),
+                    }
+                })
+            } else {
+                operate_on_iso_with_options(std::path::Path::new(iso), &cmds, &options)
+            };
+
+            match result {
                 Ok(_) => (),
                 Err(OperateISOError::IOError(e)) => {
                     eprintln!("Error: {}", e);
@@ -155,7 +714,7 @@ fn main() {
                     std::process::exit(1);
                 }
                 Err(OperateISOError::InvalidISOPath(path)) => {
-                    eprintln!("Error: iso path '{}' does not exist", path.display());
+                    eprintln!("Error: iso path '{}' does not exist, or has a directory named like an existing file", path.display());
                     std::process::exit(1);
                 }
                 Err(OperateISOError::InvalidFSPath(path)) => {
@@ -174,6 +733,30 @@ fn main() {
                     eprintln!("Error: resulting ISO is too large, too many files added.");
                     std::process::exit(1);
                 }
+                Err(OperateISOError::OffsetOverflow(path)) => {
+                    eprintln!("Error: '{}' is 4GiB or larger, which the table of contents cannot address", path.display());
+                    std::process::exit(1);
+                }
+                Err(OperateISOError::MisalignedOffset(offset)) => {
+                    eprintln!("Error: offset {:#x} is not a multiple of 4", offset);
+                    std::process::exit(1);
+                }
+                Err(OperateISOError::OffsetOccupied { offset, size, conflict }) => {
+                    eprintln!("Error: {:#x}..{:#x} overlaps '{}'", offset, offset as u64 + size as u64, conflict.display());
+                    std::process::exit(1);
+                }
+                Err(OperateISOError::DolTooLarge { available, required }) => {
+                    eprintln!("Error: Start.dol is too large. {} bytes available, {} bytes required. Use 'replace-dol' to move the table of contents.", available, required);
+                    std::process::exit(1);
+                }
+                Err(OperateISOError::ApploaderTooLarge { available, required }) => {
+                    eprintln!("Error: AppLoader.ldr is too large. {} bytes available, {} bytes required.", available, required);
+                    std::process::exit(1);
+                }
+                Err(OperateISOError::CreateOpeningBnrError(e)) => {
+                    eprintln!("Error: could not create opening.bnr: {:?}", e);
+                    std::process::exit(1);
+                }
             }
         }
 
@@ -209,69 +792,163 @@ fn main() {
             let path = unwrap_usage!(args.get(2).map(|s| s.as_str()));
             let game_id = unwrap_usage!(args.get(3).map(|s| s.as_str()));
 
-            let mut f = match std::fs::File::options().write(true).open(path) {
-                Ok(f) => f,
-                Err(e) => {
-                    eprintln!("Error: Could not open file '{}'", e);
+            let game_id = match validate_game_id(game_id) {
+                Ok(id) => id,
+                Err(_) => {
+                    eprintln!("Error: Invalid game ID: '{}'. Expected ID such as 'GALE01'", game_id);
                     std::process::exit(1);
                 }
             };
 
-            if game_id.len() != 6 
-                || game_id[0..4].chars().any(|c| !c.is_ascii_uppercase()) 
-                || game_id[4..6].chars().any(|c| !c.is_ascii_digit()) 
-            {
-                eprintln!("Error: Invalid game ID: '{}'. Expected ID such as 'GALE01'", game_id);
-                std::process::exit(1);
+            let mut edit = HeaderEdit { game_id: Some(game_id), ..HeaderEdit::default() };
+            let mut args = args[4..].iter();
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--disc" => {
+                        let n = unwrap_usage!(args.next());
+                        match n.parse::<u8>() {
+                            Ok(n) => edit.disc_number = Some(n),
+                            Err(_) => {
+                                eprintln!("Error: Invalid disc number '{}'", n);
+                                std::process::exit(1);
+                            }
+                        }
+                    }
+                    "--region" => {
+                        let n = unwrap_usage!(args.next());
+                        match n.parse::<u8>() {
+                            Ok(n) => edit.region = Some(n),
+                            Err(_) => {
+                                eprintln!("Error: Invalid region '{}'", n);
+                                std::process::exit(1);
+                            }
+                        }
+                    }
+                    "--sync-banner" => edit.sync_banner = true,
+                    "--full-title" => edit.full_title = Some(unwrap_usage!(args.next())),
+                    title if edit.title.is_none() => edit.title = Some(title),
+                    _ => usage(),
+                }
             }
 
-            use std::io::{Seek, Write};
-            if let Err(e) = f.write_all(game_id.as_bytes()) {
-                eprintln!("Error: Could not write file '{}'", e);
-                std::process::exit(1);
+            match set_header(std::path::Path::new(path), edit) {
+                Ok(()) => (),
+                Err(SetHeaderError::IOError(e)) => {
+                    eprintln!("Error: Could not write file '{}'", e);
+                    std::process::exit(1);
+                }
+                Err(SetHeaderError::OpenError { path, e }) => {
+                    eprintln!("Error: Could not open file '{}': {}", path.display(), e);
+                    std::process::exit(1);
+                }
+                Err(SetHeaderError::InvalidHeader) => {
+                    eprintln!("Error: '{}' is not an ISO.hdr file or iso", path);
+                    std::process::exit(1);
+                }
+                Err(SetHeaderError::TitleTooLong) => {
+                    eprintln!("Error: game title is too long");
+                    std::process::exit(1);
+                }
+                Err(SetHeaderError::FullTitleTooLong) => {
+                    eprintln!("Error: full title is too long. It must be less than 992 bytes");
+                    std::process::exit(1);
+                }
+                Err(SetHeaderError::ConflictingTitles) => {
+                    eprintln!("Error: pass either a game title or --full-title, not both");
+                    std::process::exit(1);
+                }
+                Err(SetHeaderError::InvalidDiscNumber(n)) => {
+                    eprintln!("Error: disc number {} is out of range. Disc numbers are zero based, so it must be 0 or 1", n);
+                    std::process::exit(1);
+                }
+                Err(SetHeaderError::MissingBanner) => {
+                    eprintln!("Error: '{}' has no opening.bnr to sync. --sync-banner needs a full iso", path);
+                    std::process::exit(1);
+                }
+                Err(SetHeaderError::BannerRegionMismatch(region)) => {
+                    let (expected, name) = match region {
+                        GameRegion::UsOrJp => ("BNR1", "NTSC"),
+                        GameRegion::Eu => ("BNR2", "PAL"),
+                    };
+                    eprintln!("Error: game ID '{}' is for a {} region, but opening.bnr is not a {} banner",
+                        String::from_utf8_lossy(&game_id), name, expected);
+                    std::process::exit(1);
+                }
             }
+        }
+        Some("extract") => {
+            let iso_path = unwrap_usage!(args.get(2).map(|s| s.as_str()));
 
-            match args.get(4).map(|s| s.as_str()) {
-                Some(title) if title.len() >= 0x20 => {
-                    eprintln!("Error: game title is too long");
+            let iso = match std::fs::read(iso_path) {
+                Ok(i) => i,
+                Err(e) => {
+                    eprintln!("Error: Could not read iso '{}'", e);
                     std::process::exit(1);
                 }
-                Some(title) => {
-                    let mut bytes = [0u8; 0x20];
-                    bytes[0..title.len()].copy_from_slice(title.as_bytes());
+            };
 
-                    if let Err(e) = f.seek(std::io::SeekFrom::Start(0x20)) {
-                        eprintln!("Error: Could not seek file '{}'", e);
+            let format = detect_format(&iso);
+            let is_rvz = matches!(format, IsoFormat::Rvz | IsoFormat::Wia);
+            #[cfg(feature = "rvz")]
+            let iso = match is_rvz {
+                false => iso,
+                true => match read_rvz(std::path::Path::new(iso_path)) {
+                    Ok(i) => i,
+                    Err(ReadRVZError::IOError(e)) => {
+                        eprintln!("Error: Could not read rvz '{}'", e);
                         std::process::exit(1);
                     }
-
-                    if let Err(e) = f.write_all(&bytes) {
-                        eprintln!("Error: Could not write file '{}'", e);
+                    Err(ReadRVZError::InvalidRVZ) => {
+                        eprintln!("Error: file is not an rvz or is corrupted");
+                        std::process::exit(1);
+                    }
+                    Err(ReadRVZError::UnsupportedDiscType(_)) => {
+                        eprintln!("Error: only GameCube discs are supported");
+                        std::process::exit(1);
+                    }
+                    Err(ReadRVZError::UnsupportedCompression(c)) => {
+                        eprintln!("Error: compression method {} is not supported, only zstd and no compression", c);
                         std::process::exit(1);
                     }
                 },
-                None => (),
             };
-        }
-        Some("extract") => {
-            let iso_path = unwrap_usage!(args.get(2).map(|s| s.as_str()));
+            #[cfg(not(feature = "rvz"))]
+            if is_rvz {
+                eprintln!("Error: gc_fst was built without RVZ support. Rebuild it with '--features rvz'");
+                std::process::exit(1);
+            }
 
-            let iso = match std::fs::read(&iso_path) {
-                Ok(i) => i,
-                Err(e) => {
-                    eprintln!("Error: Could not read iso '{}'", e);
-                    std::process::exit(1);
+            let mut options = ExtractOptions::default();
+            let mut args = args[3..].iter();
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--layout" | "-l" => options.write_layout = true,
+                    "--toc" => options.extract_toc = true,
+                    "--mtimes" => options.fst_order_mtimes = true,
+                    "--split-header" => options.split_header = true,
+                    "--force" | "-f" => options.mode = ExtractMode::Overwrite,
+                    "--verbose" | "-v" => options.log = Some(&log_entry),
+                    "--flat" => options.layout = ExtractLayout::Flat { separator: unwrap_usage!(args.next()).clone() },
+                    _ => {
+                        eprintln!("Error: unknown argument '{}'\n\n{}", arg, HELP);
+                        std::process::exit(1);
+                    }
                 }
+            }
+
+            let result = match format {
+                IsoFormat::Tgc => read_tgc_with_options(&iso, &options, &mut |_| {}),
+                _ => read_iso_with_options(&iso, &options, &mut |_| {}),
             };
 
-            match read_iso(&iso) {
+            match result {
                 Ok(_) => (),
                 Err(ReadISOError::RootDirNotEmpty) => {
-                    eprintln!("Error: root directory is not empty");
+                    eprintln!("Error: root directory is not empty. Pass --force to replace it");
                     std::process::exit(1);
                 }
                 Err(ReadISOError::InvalidISO) => {
-                    eprintln!("Error: iso path does not exist");
+                    eprintln!("Error: file is not an iso or is corrupted");
                     std::process::exit(1);
                 },
                 Err(ReadISOError::WriteFileError(e)) => {
@@ -282,37 +959,209 @@ fn main() {
                     eprintln!("Error: Could not create directory '{}'", e);
                     std::process::exit(1);
                 },
+                Err(ReadISOError::FlatNameCollision(path)) => {
+                    eprintln!("Error: '{}' has the same flattened name as another file. Use a different separator", path.display());
+                    std::process::exit(1);
+                },
+                Err(ReadISOError::InvalidSeparator(separator)) => {
+                    eprintln!("Error: '{}' can't be used as a separator. It must be part of a file name, without '/' or '\\'", separator);
+                    std::process::exit(1);
+                },
+                Err(ReadISOError::UnsafePath(path)) => {
+                    eprintln!("Error: '{}' in the iso would be written outside of the root directory", path.display());
+                    std::process::exit(1);
+                },
+                Err(ReadISOError::UnsupportedFormat(format)) => {
+                    let name = match format {
+                        IsoFormat::NKit => "NKit",
+                        IsoFormat::Ciso => "CISO",
+                        _ => "this",
+                    };
+                    eprintln!("Error: {} images are not supported. Convert it to a plain iso first", name);
+                    std::process::exit(1);
+                },
             }
         }
         Some("rebuild") => {
             let root_path = unwrap_usage!(args.get(2).map(|s| s.as_str()));
 
-            let iso_path = match args.get(3).map(|s| s.as_str()) {
-                Some(p) => p,
-                None => "out.iso",
+            let mut iso_path = "out.iso";
+            let mut options = WriteOptions::default();
+            let mut dry_run = false;
+            // none of the options apply to zips, so the first one is reported if the root is a zip
+            let mut first_option = None;
+            let mut args = args[3..].iter();
+            while let Some(arg) = args.next() {
+                if arg.starts_with('-') && first_option.is_none() { first_option = Some(arg); }
+
+                let system_file = match arg.as_str() {
+                    "--header" => Some(&mut options.system_files.header),
+                    "--boot-bin" => Some(&mut options.system_files.boot_bin),
+                    "--bi2-bin" => Some(&mut options.system_files.bi2_bin),
+                    "--apploader" => Some(&mut options.system_files.apploader),
+                    "--dol" => Some(&mut options.system_files.dol),
+                    _ => None,
+                };
+                if let Some(system_file) = system_file {
+                    *system_file = Some(unwrap_usage!(args.next()).into());
+                    continue;
+                }
+
+                match arg.as_str() {
+                    "--dedup" => options.dedup = true,
+                    "--strip-junk" => options.strip_junk = true,
+                    "--follow-symlinks" => options.follow_symlinks = true,
+                    "--case-sensitive" => options.sort_order = SortOrder::CaseSensitive,
+                    "--unsorted" => options.sort_order = SortOrder::Unsorted,
+                    "--by-mtime" => options.sort_order = SortOrder::Modified,
+                    "--dry-run" => dry_run = true,
+                    "--self-check" => options.self_check = true,
+                    "--verbose" | "-v" => options.log = Some(&log_entry),
+                    "--segment-alignment" => match unwrap_usage!(args.next()).parse::<u32>() {
+                        Ok(bits) => options.segment_alignment = Some(bits),
+                        Err(_) => usage(),
+                    },
+                    _ if arg.starts_with('-') => {
+                        eprintln!("Error: unknown argument '{}'\n\n{}", arg, HELP);
+                        std::process::exit(1);
+                    }
+                    _ => iso_path = arg,
+                }
+            }
+
+            let root_path = std::path::Path::new(root_path);
+            if let Some(option) = first_option.filter(|_| root_path.is_file()) {
+                eprintln!("Error: {} is not supported for zips", option);
+                std::process::exit(1);
+            }
+
+            let result = if dry_run {
+                plan_iso(root_path, &options).map(|layout| {
+                    for file in layout.files.iter() {
+                        println!("{:#010x}\t{}\t{}", file.offset, file.size, file.iso_path.display());
+                    }
+                    println!("table of contents: {} bytes at {:#010x}", layout.fs_size, layout.fst_offset);
+                    println!("data end: {} bytes, {} bytes available", layout.data_end, ROM_SIZE);
+                })
+            } else {
+                let mut iso = match std::fs::File::create(iso_path) {
+                    Ok(f) => f,
+                    Err(e) => {
+                        eprintln!("Error: Could not create iso '{}'", e);
+                        std::process::exit(1);
+                    }
+                };
+
+                match root_path.is_file() {
+                    #[cfg(feature = "zip")]
+                    true => match std::fs::File::open(root_path) {
+                        Ok(zip) => write_iso_from_zip(std::io::BufReader::new(zip)).and_then(|bytes| {
+                            use std::io::Write;
+                            iso.write_all(&bytes).map_err(WriteISOError::WriteFileError)
+                        }),
+                        Err(e) => Err(WriteISOError::ReadFileError { path: root_path.to_path_buf(), e }),
+                    },
+                    #[cfg(not(feature = "zip"))]
+                    true => {
+                        eprintln!("Error: gc_fst was built without zip support. Rebuild it with '--features zip'");
+                        std::process::exit(1);
+                    }
+                    false => plan_iso(root_path, &options).and_then(|layout| {
+                        write_planned_iso(&layout, &mut iso, &mut |_| {})?;
+                        const MIB: f64 = (1 << 20) as f64;
+                        match layout.iso_len() > layout.data_len() {
+                            true => println!("built {:.1} MiB (padded to {:.1} MiB)", layout.data_len() as f64 / MIB, layout.iso_len() as f64 / MIB),
+                            false => println!("built {:.1} MiB", layout.data_len() as f64 / MIB),
+                        }
+                        Ok(())
+                    }),
+                }
             };
 
-            let bytes = match write_iso(std::path::Path::new(root_path)) {
-                Ok(b) => b,
+            match result {
+                Ok(()) => (),
                 Err(WriteISOError::ISOTooLarge) => {
                     eprintln!("Error: Resulting ISO is too large");
                     std::process::exit(1);
                 },
+                Err(WriteISOError::OffsetOverflow(path)) => {
+                    eprintln!("Error: '{}' ends past 4GiB, which the table of contents cannot address", path.display());
+                    std::process::exit(1);
+                },
                 Err(WriteISOError::InvalidFilename(f)) => {
                     eprintln!("Error: Filename '{:?}' cannot be written in an ISO", f);
                     std::process::exit(1);
                 },
-                Err(WriteISOError::ReadFileError(e)) => {
-                    eprintln!("Error: Could not read file '{}'", e);
+                Err(WriteISOError::FilenameTooLong(path)) => {
+                    eprintln!("Error: Filename '{}' is too long, or there are too many names to fit in the table of contents. Names can be at most {} bytes", path.display(), MAX_FILENAME_LENGTH);
                     std::process::exit(1);
                 },
-                Err(WriteISOError::ReadDirError(e)) => {
-                    eprintln!("Error: Could not read directory '{}'", e);
+                Err(WriteISOError::ReadFileError { path, e }) => {
+                    eprintln!("Error: Could not read file '{}': {}", path.display(), e);
                     std::process::exit(1);
                 },
-            };
-
-            std::fs::write(&iso_path, &bytes).unwrap();
+                Err(WriteISOError::ReadDirError { path, e }) => {
+                    eprintln!("Error: Could not read directory '{}': {}", path.display(), e);
+                    std::process::exit(1);
+                },
+                Err(WriteISOError::WriteFileError(e)) => {
+                    eprintln!("Error: Could not write iso '{}'", e);
+                    std::process::exit(1);
+                },
+                Err(WriteISOError::InvalidLayout) => {
+                    eprintln!("Error: &&systemdata/layout.txt is malformed, or places files on top of each other or past the end of the disc");
+                    std::process::exit(1);
+                },
+                Err(WriteISOError::InvalidAlignment) => {
+                    eprintln!("Error: &&systemdata/alignment.txt is malformed");
+                    std::process::exit(1);
+                },
+                Err(WriteISOError::InvalidSegmentAlignment(bits)) => {
+                    eprintln!("Error: segment alignment {} is too large. It must be at most {}", bits, consts::MAX_ALIGNMENT);
+                    std::process::exit(1);
+                },
+                Err(WriteISOError::InvalidSizes) => {
+                    eprintln!("Error: &&systemdata/sizes.txt is malformed");
+                    std::process::exit(1);
+                },
+                Err(WriteISOError::InvalidToc) => {
+                    eprintln!("Error: &&systemdata/Game.toc is malformed");
+                    std::process::exit(1);
+                },
+                Err(WriteISOError::TocMismatch(path)) => {
+                    eprintln!("Error: '{}' does not match &&systemdata/Game.toc. Delete Game.toc to rebuild the table of contents", path.display());
+                    std::process::exit(1);
+                },
+                Err(WriteISOError::InvalidApploaderSize { declared, actual }) => {
+                    eprintln!("Error: AppLoader.ldr header declares {} bytes, but the file is {} bytes", declared, actual);
+                    std::process::exit(1);
+                },
+                Err(WriteISOError::UnsupportedSymlink(path)) => {
+                    eprintln!("Error: '{}' is a symlink. Pass --follow-symlinks to include its target", path.display());
+                    std::process::exit(1);
+                },
+                Err(WriteISOError::DuplicateFilename(path)) => {
+                    eprintln!("Error: '{}' has the same name as another file, ignoring case", path.display());
+                    std::process::exit(1);
+                },
+                Err(WriteISOError::InvalidHeaderSize(size)) => {
+                    eprintln!("Error: header is {} bytes. ISO.hdr must be 9280 bytes, or boot.bin 1088 and bi2.bin 8192 bytes", size);
+                    std::process::exit(1);
+                },
+                Err(WriteISOError::MissingSystemFile(name)) => {
+                    eprintln!("Error: {} is missing from &&systemdata", name);
+                    std::process::exit(1);
+                },
+                Err(WriteISOError::InternalLayoutError) => {
+                    eprintln!("Error: the table of contents failed its self-check and the ISO was not written. This is a bug in gc_fst, please report it");
+                    std::process::exit(1);
+                },
+                #[cfg(feature = "zip")]
+                Err(WriteISOError::ZipError(e)) => {
+                    eprintln!("Error: Could not read zip '{}'", e);
+                    std::process::exit(1);
+                },
+            }
         }
         _ => usage(),
     }
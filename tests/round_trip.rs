@@ -0,0 +1,990 @@
+use gc_fst::*;
+use std::path::{Path, PathBuf};
+
+const FILES: &[(&str, usize)] = &[
+    ("opening.bnr", 0x1960),
+    ("a.txt", 3),
+    ("Zeta.dat", 40000),
+    ("audio/bgm/track01.ast", 70000),
+    ("audio/bgm/Track02.ast", 100),
+    ("audio/se.ssm", 1234),
+    ("b/c/d/e.bin", 0),
+    ("b/readme", 77),
+];
+
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("gc_fst_{}_{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Deterministic bytes that differ between files, so misplaced contents are noticed.
+fn contents(seed: usize, len: usize) -> Vec<u8> {
+    let mut x = (seed as u32).wrapping_mul(0x9E3779B9) | 1;
+    (0..len).map(|_| {
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        x as u8
+    }).collect()
+}
+
+/// Writes a root with a plausible header, a tiny apploader and dol, and `FILES`.
+fn make_root(root: &Path) {
+    let systemdata = root.join("&&systemdata");
+    std::fs::create_dir_all(&systemdata).unwrap();
+
+    let mut header = vec![0u8; 0x2440];
+    header[0..6].copy_from_slice(b"GALE01");
+    header[0x1C..0x20].copy_from_slice(&GAMECUBE_MAGIC.to_be_bytes());
+    header[0x20..0x2B].copy_from_slice(b"Test Title!");
+    header[0x458..0x45C].copy_from_slice(&1u32.to_be_bytes());
+    std::fs::write(systemdata.join("ISO.hdr"), header).unwrap();
+
+    // 0x100 bytes of code and 0x40 bytes of trailer
+    let mut apploader = contents(1, 0x160);
+    apploader[0..0x10].copy_from_slice(b"2001/04/12\0\0\0\0\0\0");
+    apploader[0x10..0x14].copy_from_slice(&0x81200000u32.to_be_bytes());
+    apploader[0x14..0x18].copy_from_slice(&0x100u32.to_be_bytes());
+    apploader[0x18..0x1C].copy_from_slice(&0x40u32.to_be_bytes());
+    std::fs::write(systemdata.join("AppLoader.ldr"), apploader).unwrap();
+
+    std::fs::write(systemdata.join("Start.dol"), make_dol(2, 0x300)).unwrap();
+
+    for (i, &(path, len)) in FILES.iter().enumerate() {
+        let path = root.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents(i + 3, len)).unwrap();
+    }
+    std::fs::create_dir_all(root.join("emptydir")).unwrap();
+}
+
+/// A dol with one text section of `text_len` bytes.
+fn make_dol(seed: usize, text_len: usize) -> Vec<u8> {
+    let mut dol = vec![0u8; 0x100];
+    dol[0x00..0x04].copy_from_slice(&0x100u32.to_be_bytes());
+    dol[0x48..0x4C].copy_from_slice(&0x80003100u32.to_be_bytes());
+    dol[0x90..0x94].copy_from_slice(&(text_len as u32).to_be_bytes());
+    dol[0xE0..0xE4].copy_from_slice(&0x80003100u32.to_be_bytes());
+    dol.extend(contents(seed, text_len));
+    dol
+}
+
+/// Overwrites the offset of a file in the fst, like tools which give empty files offset 0.
+fn set_fst_offset(iso_path: &Path, file: &Path, offset: u32) {
+    let mut iso = std::fs::read(iso_path).unwrap();
+    let fst_offset = u32::from_be_bytes(iso[0x424..0x428].try_into().unwrap()) as usize;
+    let mut entry_index = 0;
+    for event in FstIter::from_iso(&iso).unwrap() {
+        match event.unwrap() {
+            FstEvent::File { path, .. } => {
+                entry_index += 1;
+                if path == file { break; }
+            }
+            FstEvent::EnterDir { .. } => entry_index += 1,
+            FstEvent::ExitDir => (),
+        }
+    }
+    iso[fst_offset + entry_index * 0xC + 4..][..4].copy_from_slice(&offset.to_be_bytes());
+    std::fs::write(iso_path, iso).unwrap();
+}
+
+/// Rebuilds `root` into `iso_path`, without the padding up to `ROM_SIZE`.
+fn rebuild(root: &Path, iso_path: &Path) {
+    rebuild_with_options(root, iso_path, &WriteOptions::default());
+}
+
+fn rebuild_with_options(root: &Path, iso_path: &Path, options: &WriteOptions) {
+    let layout = plan_iso(root, options).unwrap();
+    let mut iso = std::fs::File::create(iso_path).unwrap();
+    write_planned_iso(&layout, &mut iso, &mut |_| {}).unwrap();
+    iso.set_len(layout.data_end).unwrap();
+}
+
+/// Extracts `iso_path` into `dir/root`.
+fn extract(iso_path: &Path, dir: &Path) {
+    extract_with_options(iso_path, dir, &ExtractOptions::default());
+}
+
+fn extract_with_options(iso_path: &Path, dir: &Path, options: &ExtractOptions) {
+    let iso = std::fs::read(iso_path).unwrap();
+    read_iso_to(&iso, dir, options, &mut |_| {}).unwrap();
+}
+
+/// Asserts both roots have the same directories and files with the same contents, ignoring '&&systemdata'.
+fn assert_same_tree(expected: &Path, actual: &Path) {
+    let expected = tree(expected);
+    let actual = tree(actual);
+    let paths = |t: &[(PathBuf, Option<Vec<u8>>)]| t.iter().map(|(p, _)| p.clone()).collect::<Vec<_>>();
+    assert_eq!(paths(&expected), paths(&actual));
+    for ((path, a), (_, b)) in expected.iter().zip(actual.iter()) {
+        assert!(a == b, "contents of '{}' differ", path.display());
+    }
+}
+
+/// Every file and directory below `root` except '&&systemdata', with the contents of files.
+fn tree(root: &Path) -> Vec<(PathBuf, Option<Vec<u8>>)> {
+    fn walk(root: &Path, dir: &Path, out: &mut Vec<(PathBuf, Option<Vec<u8>>)>) {
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let rel = path.strip_prefix(root).unwrap().to_path_buf();
+            if rel == Path::new("&&systemdata") { continue; }
+            if path.is_dir() {
+                out.push((rel, None));
+                walk(root, &path, out);
+            } else {
+                out.push((rel, Some(std::fs::read(&path).unwrap())));
+            }
+        }
+    }
+
+    let mut out = Vec::new();
+    walk(root, root, &mut out);
+    out.sort();
+    out
+}
+
+#[test]
+fn rebuild_then_extract() {
+    let dir = test_dir("rebuild_then_extract");
+    let root = dir.join("in");
+    let iso_path = dir.join("out.iso");
+    make_root(&root);
+
+    rebuild(&root, &iso_path);
+    extract(&iso_path, &dir);
+
+    assert_same_tree(&root, &dir.join("root"));
+    for name in ["ISO.hdr", "AppLoader.ldr", "Start.dol"] {
+        let original = std::fs::read(root.join("&&systemdata").join(name)).unwrap();
+        let mut extracted = std::fs::read(dir.join("root/&&systemdata").join(name)).unwrap();
+        if name == "ISO.hdr" {
+            // the dol and fst offsets and sizes are filled in when rebuilding
+            extracted[0x420..0x430].fill(0);
+        }
+        assert!(original == extracted, "{} differs", name);
+    }
+
+    // rebuilding the extracted root gives the same iso
+    let iso_path_2 = dir.join("out2.iso");
+    rebuild(&dir.join("root"), &iso_path_2);
+    assert!(std::fs::read(&iso_path).unwrap() == std::fs::read(&iso_path_2).unwrap());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn operate_then_extract() {
+    let dir = test_dir("operate_then_extract");
+    let root = dir.join("in");
+    let iso_path = dir.join("out.iso");
+    make_root(&root);
+    rebuild(&root, &iso_path);
+
+    let new_file = dir.join("new.bin");
+    std::fs::write(&new_file, contents(100, 50000)).unwrap();
+    let replacement = dir.join("replacement.bin");
+    std::fs::write(&replacement, contents(101, 20)).unwrap();
+
+    operate_on_iso(&iso_path, &[
+        IsoOp::Delete { iso_path: Path::new("Zeta.dat") },
+        IsoOp::Insert { iso_path: Path::new("audio/new/new.bin"), input_path: &new_file },
+        IsoOp::Insert { iso_path: Path::new("audio/se.ssm"), input_path: &replacement },
+        IsoOp::Rename { from: Path::new("b/readme"), to: Path::new("readme") },
+        IsoOp::Mkdir { iso_path: Path::new("c/d") },
+    ]).unwrap();
+
+    // make the same changes to the original root
+    std::fs::remove_file(root.join("Zeta.dat")).unwrap();
+    std::fs::create_dir_all(root.join("audio/new")).unwrap();
+    std::fs::copy(&new_file, root.join("audio/new/new.bin")).unwrap();
+    std::fs::copy(&replacement, root.join("audio/se.ssm")).unwrap();
+    std::fs::rename(root.join("b/readme"), root.join("readme")).unwrap();
+    std::fs::create_dir_all(root.join("c/d")).unwrap();
+    // empty directories are removed unless created with `Mkdir`
+    std::fs::remove_dir(root.join("emptydir")).unwrap();
+
+    extract(&iso_path, &dir);
+    assert_same_tree(&root, &dir.join("root"));
+
+    assert_eq!(read_iso_file_bytes(&iso_path, Path::new("audio/new/new.bin")).unwrap(), contents(100, 50000));
+    assert!(matches!(
+        read_iso_file_bytes(&iso_path, Path::new("Zeta.dat")),
+        Err(ReadISOFilesError::InvalidFSPath(_))
+    ));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn operate_keeps_max_fst_size() {
+    let dir = test_dir("operate_keeps_max_fst_size");
+    let root = dir.join("in");
+    let iso_path = dir.join("out.iso");
+    make_root(&root);
+    rebuild(&root, &iso_path);
+
+    // reserve more memory for the fst than it takes up
+    let mut iso = std::fs::read(&iso_path).unwrap();
+    iso[0x42C..0x430].copy_from_slice(&0x10000u32.to_be_bytes());
+    std::fs::write(&iso_path, iso).unwrap();
+
+    let new_file = dir.join("new.bin");
+    std::fs::write(&new_file, contents(100, 10)).unwrap();
+    operate_on_iso(&iso_path, &[IsoOp::Insert { iso_path: Path::new("new.bin"), input_path: &new_file }]).unwrap();
+
+    let iso = std::fs::read(&iso_path).unwrap();
+    let fs_size = u32::from_be_bytes(iso[0x428..0x42C].try_into().unwrap());
+    let max_fs_size = u32::from_be_bytes(iso[0x42C..0x430].try_into().unwrap());
+    assert!(fs_size < 0x10000);
+    assert_eq!(max_fs_size, 0x10000);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn strip_junk_then_extract() {
+    let dir = test_dir("strip_junk_then_extract");
+    let root = dir.join("in");
+    let iso_path = dir.join("out.iso");
+    make_root(&root);
+
+    let options = WriteOptions { strip_junk: true, ..WriteOptions::default() };
+    let mut iso = std::fs::File::create(&iso_path).unwrap();
+    write_iso_to_with_options(&root, &mut iso, &options, &mut |_| {}).unwrap();
+    drop(iso);
+
+    let layout = plan_iso(&root, &options).unwrap();
+    assert_eq!(std::fs::metadata(&iso_path).unwrap().len(), layout.data_end);
+    assert_eq!(layout.iso_len(), layout.data_end);
+    assert_eq!(plan_iso(&root, &WriteOptions::default()).unwrap().iso_len(), ROM_SIZE as u64);
+    assert!(layout.files.iter().all(|f| f.offset % 0x800 == 0));
+    assert!(layout.data_end < plan_iso(&root, &WriteOptions::default()).unwrap().data_end);
+
+    let sized = write_iso_sized(&root, &options).unwrap();
+    assert_eq!(sized.data_len as u64, layout.data_end);
+    assert!(sized.iso == std::fs::read(&iso_path).unwrap());
+
+    // a padded iso's data ends where the next file would start
+    let padded = plan_iso(&root, &WriteOptions::default()).unwrap();
+    assert_eq!(padded.data_len() % consts::default_alignment() as u64, 0);
+    assert!(padded.data_len() >= padded.data_end && padded.data_len() < padded.iso_len());
+
+    extract(&iso_path, &dir);
+    assert_same_tree(&root, &dir.join("root"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn failed_operation_leaves_iso_unchanged() {
+    let dir = test_dir("failed_operation_leaves_iso_unchanged");
+    let root = dir.join("in");
+    let iso_path = dir.join("out.iso");
+    make_root(&root);
+    rebuild(&root, &iso_path);
+    let original = std::fs::read(&iso_path).unwrap();
+
+    let new_file = dir.join("new.bin");
+    std::fs::write(&new_file, contents(100, 10)).unwrap();
+    let result = operate_on_iso(&iso_path, &[
+        IsoOp::Delete { iso_path: Path::new("Zeta.dat") },
+        IsoOp::Insert { iso_path: Path::new("new.bin"), input_path: &new_file },
+        IsoOp::Insert { iso_path: Path::new("missing.bin"), input_path: &dir.join("missing.bin") },
+    ]);
+    assert!(result.is_err());
+    assert!(std::fs::read(&iso_path).unwrap() == original);
+
+    // directories can't share a name with a file
+    for op in [
+        IsoOp::Mkdir { iso_path: Path::new("a.txt") },
+        IsoOp::Mkdir { iso_path: Path::new("b/readme/c") },
+        IsoOp::Insert { iso_path: Path::new("a.txt/new.bin"), input_path: &new_file },
+    ] {
+        let result = operate_on_iso(&iso_path, &[op]);
+        assert!(matches!(result, Err(OperateISOError::InvalidISOPath(_))), "{:?}", result);
+        assert!(std::fs::read(&iso_path).unwrap() == original);
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn log_can_capture_state() {
+    let dir = test_dir("log_can_capture_state");
+    let root = dir.join("in");
+    let iso_path = dir.join("out.iso");
+    make_root(&root);
+
+    let logged = std::cell::RefCell::new(Vec::new());
+    let log = |entry: LogEntry| if let LogEntry::File { path, offset, size } = entry {
+        logged.borrow_mut().push((path.to_path_buf(), offset, size));
+    };
+
+    rebuild_with_options(&root, &iso_path, &WriteOptions { log: Some(&log), ..WriteOptions::default() });
+    let written = logged.take();
+    assert!(written.iter().any(|(path, _, size)| path == Path::new("audio/bgm/track01.ast") && *size == 70000));
+
+    extract_with_options(&iso_path, &dir, &ExtractOptions { log: Some(&log), ..ExtractOptions::default() });
+    assert_eq!(logged.take(), written);
+
+    let options = OperateOptions { log: Some(&log), ..OperateOptions::default() };
+    operate_on_iso_with_options(&iso_path, &[IsoOp::Delete { iso_path: Path::new("a.txt") }], &options).unwrap();
+    let operated = logged.take();
+    assert_eq!(operated.len(), written.len() - 1);
+    assert!(operated.iter().all(|(path, _, _)| path != Path::new("a.txt")));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn verify_reports_bad_next_index() {
+    let dir = test_dir("verify_reports_bad_next_index");
+    let root = dir.join("in");
+    let iso_path = dir.join("out.iso");
+    make_root(&root);
+    rebuild(&root, &iso_path);
+    assert!(verify_iso(&iso_path).unwrap().is_empty());
+
+    let mut iso = std::fs::read(&iso_path).unwrap();
+    let fst_offset = u32::from_be_bytes(iso[0x424..0x428].try_into().unwrap()) as usize;
+    let entry_count = u32::from_be_bytes(iso[fst_offset+8..][..4].try_into().unwrap()) as usize;
+    let first_dir = (1..entry_count).find(|i| iso[fst_offset + i * 0xC] == 1).unwrap();
+    let dir_path = FstIter::from_iso(&iso).unwrap()
+        .find_map(|e| match e.unwrap() { FstEvent::EnterDir { path, .. } => Some(path), _ => None })
+        .unwrap();
+
+    // past the end of the fst
+    iso[fst_offset + first_dir * 0xC + 8..][..4].copy_from_slice(&(entry_count as u32 + 1).to_be_bytes());
+    std::fs::write(&iso_path, &iso).unwrap();
+
+    let warnings = verify_iso(&iso_path).unwrap();
+    assert!(matches!(&warnings[..], [VerifyWarning::NextIndexOutOfRange { path, next_idx }]
+        if *path == dir_path && *next_idx == entry_count as u32 + 1));
+    assert!(warnings[0].is_fatal());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn set_header_syncs_banner() {
+    let dir = test_dir("set_header_syncs_banner");
+    let root = dir.join("in");
+    let iso_path = dir.join("out.iso");
+    make_root(&root);
+    let mut banner = contents(3, 0x1960);
+    banner[0..4].copy_from_slice(b"BNR1");
+    std::fs::write(root.join("opening.bnr"), &banner).unwrap();
+    rebuild(&root, &iso_path);
+
+    let edit = HeaderEdit { game_id: Some(*b"GALE01"), title: Some("New Title"), sync_banner: true, ..HeaderEdit::default() };
+    set_header(&iso_path, edit).unwrap();
+
+    let banner = read_iso_file_bytes(&iso_path, Path::new("opening.bnr")).unwrap();
+    assert_eq!(&banner[0x1820..0x182A], b"New Title\0");
+    assert!(banner[0x1820..0x1840].iter().skip(9).all(|&b| b == 0));
+    assert_eq!(&banner[0x1860..0x186A], b"New Title\0");
+    assert!(banner[0x1860..0x18A0].iter().skip(9).all(|&b| b == 0));
+    assert_eq!(read_header(&iso_path).unwrap().title, "New Title");
+
+    // a PAL game id needs a BNR2 banner, so nothing is written
+    let synced = std::fs::read(&iso_path).unwrap();
+    let pal_edit = HeaderEdit { game_id: Some(*b"GALP01"), ..edit };
+    assert!(matches!(set_header(&iso_path, pal_edit), Err(SetHeaderError::BannerRegionMismatch(GameRegion::Eu))));
+    assert!(std::fs::read(&iso_path).unwrap() == synced);
+
+    // a header alone has no banner to sync
+    let hdr_path = root.join("&&systemdata/ISO.hdr");
+    let original = std::fs::read(&hdr_path).unwrap();
+    assert!(matches!(set_header(&hdr_path, edit), Err(SetHeaderError::MissingBanner)));
+    assert!(std::fs::read(&hdr_path).unwrap() == original);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn read_iso_files_reports_missing() {
+    let dir = test_dir("read_iso_files_reports_missing");
+    let root = dir.join("in");
+    let iso_path = dir.join("out.iso");
+    make_root(&root);
+    rebuild(&root, &iso_path);
+
+    let found = read_iso_files(&iso_path, &[
+        (Path::new("audio/se.ssm"), &dir.join("se.ssm")),
+        (Path::new("audio/missing.ssm"), &dir.join("missing.ssm")),
+        (Path::new("/b"), &dir.join("b")),
+        (Path::new("Start.dol"), &dir.join("Start.dol")),
+    ]).unwrap();
+
+    assert_eq!(found, vec![
+        (PathBuf::from("audio/se.ssm"), true),
+        (PathBuf::from("audio/missing.ssm"), false),
+        (PathBuf::from("/b"), true),
+        (PathBuf::from("Start.dol"), true),
+    ]);
+    assert!(!dir.join("missing.ssm").exists());
+    assert_eq!(std::fs::read(dir.join("b/readme")).unwrap(), std::fs::read(root.join("b/readme")).unwrap());
+
+    // '/' extracts the whole filesystem
+    let found = read_iso_files(&iso_path, &[(Path::new("/"), &dir.join("all"))]).unwrap();
+    assert_eq!(found, vec![(PathBuf::from("/"), true)]);
+    assert_same_tree(&root, &dir.join("all"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn replace_banner_image_keeps_titles() {
+    let dir = test_dir("replace_banner_image_keeps_titles");
+    let root = dir.join("in");
+    let iso_path = dir.join("out.iso");
+    make_root(&root);
+    let mut banner = std::fs::read(root.join("opening.bnr")).unwrap();
+    banner[0..4].copy_from_slice(b"BNR1");
+    std::fs::write(root.join("opening.bnr"), &banner).unwrap();
+    rebuild(&root, &iso_path);
+    let original = read_iso_file_bytes(&iso_path, Path::new("opening.bnr")).unwrap();
+
+    let image = RGB5A1Image(Box::new(contents(200, 0x1800).try_into().unwrap()));
+    replace_banner_image(&iso_path, &image).unwrap();
+
+    assert!(*extract_banner(&iso_path).unwrap().0 == *image.0);
+    let banner = read_iso_file_bytes(&iso_path, Path::new("opening.bnr")).unwrap();
+    assert_eq!(banner.len(), original.len());
+    assert!(banner[..0x20] == original[..0x20]);
+    assert!(banner[0x1820..] == original[0x1820..]);
+
+    // a file which isn't a banner is left alone
+    let mut not_banner = banner.clone();
+    not_banner[0..4].copy_from_slice(b"RARC");
+    patch_file(&iso_path, Path::new("opening.bnr"), &not_banner).unwrap();
+    let unchanged = std::fs::read(&iso_path).unwrap();
+    assert!(matches!(replace_banner_image(&iso_path, &image), Err(OperateISOError::InvalidISO)));
+    assert!(matches!(extract_banner(&iso_path), Err(ReadISOFilesError::InvalidISO)));
+    assert!(std::fs::read(&iso_path).unwrap() == unchanged);
+
+    // as is one too small to hold the titles
+    patch_file(&iso_path, Path::new("opening.bnr"), &banner[..0x1900]).unwrap();
+    let unchanged = std::fs::read(&iso_path).unwrap();
+    assert!(matches!(replace_banner_image(&iso_path, &image), Err(OperateISOError::InvalidISO)));
+    assert!(std::fs::read(&iso_path).unwrap() == unchanged);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn extracted_toc_is_kept() {
+    let dir = test_dir("extracted_toc_is_kept");
+    let root = dir.join("in");
+    let iso_path = dir.join("out.iso");
+    make_root(&root);
+    rebuild(&root, &iso_path);
+
+    // files inserted into free space are out of order, so a rebuilt FST would differ
+    let new_file = dir.join("new.bin");
+    std::fs::write(&new_file, contents(100, 10)).unwrap();
+    operate_on_iso(&iso_path, &[
+        IsoOp::Delete { iso_path: Path::new("Zeta.dat") },
+        IsoOp::Insert { iso_path: Path::new("zz.bin"), input_path: &new_file },
+    ]).unwrap();
+    let iso = std::fs::read(&iso_path).unwrap();
+    let fst_offset = u32::from_be_bytes(iso[0x424..0x428].try_into().unwrap()) as usize;
+    let fs_size = u32::from_be_bytes(iso[0x428..0x42C].try_into().unwrap()) as usize;
+    let fst = &iso[fst_offset..][..fs_size];
+
+    let options = ExtractOptions { extract_toc: true, ..ExtractOptions::default() };
+    extract_with_options(&iso_path, &dir, &options);
+    let extracted = dir.join("root");
+    assert!(std::fs::read(extracted.join("&&systemdata/Game.toc")).unwrap() == fst);
+
+    let iso_path_2 = dir.join("out2.iso");
+    rebuild(&extracted, &iso_path_2);
+    let iso_2 = std::fs::read(&iso_path_2).unwrap();
+    assert!(&iso_2[fst_offset..][..fs_size] == fst);
+    for file in plan_iso(&extracted, &WriteOptions::default()).unwrap().files {
+        let original = read_iso_file_bytes(&iso_path, &file.iso_path).unwrap();
+        assert!(iso_2[file.offset as usize..][..file.size as usize] == original[..]);
+    }
+
+    // resizing a file no longer matches
+    std::fs::write(extracted.join("a.txt"), b"longer").unwrap();
+    assert!(matches!(
+        plan_iso(&extracted, &WriteOptions::default()),
+        Err(WriteISOError::TocMismatch(path)) if path.ends_with("a.txt")
+    ));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn insert_at_offset() {
+    let dir = test_dir("insert_at_offset");
+    let root = dir.join("in");
+    let iso_path = dir.join("out.iso");
+    make_root(&root);
+    rebuild(&root, &iso_path);
+
+    let new_file = dir.join("new.bin");
+    std::fs::write(&new_file, contents(100, 1000)).unwrap();
+    let end = std::fs::metadata(&iso_path).unwrap().len() as u32;
+    let offset = end + 0x1004;
+
+    // other insertions are placed around it
+    operate_on_iso(&iso_path, &[
+        IsoOp::InsertAt { iso_path: Path::new("fixed.bin"), input_path: &new_file, offset },
+        IsoOp::Insert { iso_path: Path::new("other.bin"), input_path: &new_file },
+    ]).unwrap();
+    let plan = plan_iso(&root, &WriteOptions::default()).unwrap();
+    let iso = std::fs::read(&iso_path).unwrap();
+    assert!(iso[offset as usize..][..1000] == contents(100, 1000)[..]);
+    assert_eq!(read_iso_file_bytes(&iso_path, Path::new("fixed.bin")).unwrap(), contents(100, 1000));
+    assert_eq!(read_iso_file_bytes(&iso_path, Path::new("other.bin")).unwrap(), contents(100, 1000));
+
+    let a_txt = plan.files.iter().find(|f| f.iso_path == Path::new("a.txt")).unwrap().offset;
+    let conflict = |offset| match operate_on_iso(&iso_path, &[
+        IsoOp::InsertAt { iso_path: Path::new("conflict.bin"), input_path: &new_file, offset },
+    ]) {
+        Err(OperateISOError::OffsetOccupied { conflict, .. }) => conflict,
+        result => panic!("{:?}", result),
+    };
+    assert_eq!(conflict(a_txt), Path::new("a.txt"));
+    assert_eq!(conflict(offset + 4), Path::new("fixed.bin"));
+    assert_eq!(conflict(plan.dol_offset), Path::new("Start.dol"));
+    assert_eq!(conflict(plan.fst_offset), Path::new("Game.toc"));
+    assert!(matches!(
+        operate_on_iso(&iso_path, &[IsoOp::InsertAt { iso_path: Path::new("odd.bin"), input_path: &new_file, offset: offset + 2 }]),
+        Err(OperateISOError::MisalignedOffset(_))
+    ));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn read_glob() {
+    let dir = test_dir("read_glob");
+    let root = dir.join("in");
+    let iso_path = dir.join("out.iso");
+    make_root(&root);
+    rebuild(&root, &iso_path);
+
+    let out = dir.join("out");
+    let glob = |pattern| read_iso_glob(&iso_path, pattern, &out).unwrap();
+    assert_eq!(glob("audio/bgm/*.ast"), [PathBuf::from("audio/bgm/track01.ast"), PathBuf::from("audio/bgm/Track02.ast")]);
+    assert_eq!(glob("/**/e.bin"), [PathBuf::from("b/c/d/e.bin")]);
+    assert_eq!(glob("**/?.txt"), [PathBuf::from("a.txt")]);
+    assert_eq!(glob("audio/*"), [PathBuf::from("audio/se.ssm")]);
+    assert!(glob("*.ast").is_empty());
+    assert_eq!(glob("**/bgm/**/*02.ast"), [PathBuf::from("audio/bgm/Track02.ast")]);
+
+    // many wildcards that almost match must not take exponential time
+    let slow = format!("{}/{}b", ["**"; 20].join("/"), "*a".repeat(20));
+    assert!(glob(&slow).is_empty());
+
+    assert_eq!(std::fs::read(out.join("audio/bgm/Track02.ast")).unwrap(), std::fs::read(root.join("audio/bgm/Track02.ast")).unwrap());
+    assert!(out.join("b/c/d/e.bin").is_file());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn mtimes_keep_fst_order() {
+    let dir = test_dir("mtimes_keep_fst_order");
+    let root = dir.join("in");
+    let iso_path = dir.join("out.iso");
+    make_root(&root);
+    rebuild(&root, &iso_path);
+
+    // inserted files go first in their directory, out of sorted order
+    let new_file = dir.join("new.bin");
+    std::fs::write(&new_file, contents(100, 10)).unwrap();
+    operate_on_iso(&iso_path, &[
+        IsoOp::Insert { iso_path: Path::new("zz.bin"), input_path: &new_file },
+        IsoOp::Insert { iso_path: Path::new("audio/zz.bin"), input_path: &new_file },
+    ]).unwrap();
+    let fst_paths = |iso_path: &Path| {
+        let iso = std::fs::read(iso_path).unwrap();
+        FstIter::from_iso(&iso).unwrap()
+            .filter_map(|e| match e.unwrap() {
+                FstEvent::File { path, .. } | FstEvent::EnterDir { path, .. } => Some(path),
+                FstEvent::ExitDir => None,
+            })
+            .collect::<Vec<_>>()
+    };
+    let original = fst_paths(&iso_path);
+    assert_eq!(original[0], Path::new("zz.bin"));
+
+    let options = ExtractOptions { fst_order_mtimes: true, ..ExtractOptions::default() };
+    extract_with_options(&iso_path, &dir, &options);
+
+    let options = WriteOptions { sort_order: SortOrder::Modified, ..WriteOptions::default() };
+    let iso_path_2 = dir.join("out2.iso");
+    rebuild_with_options(&dir.join("root"), &iso_path_2, &options);
+    assert_eq!(fst_paths(&iso_path_2), original);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn read_iso_to_stays_in_root() {
+    let dir = test_dir("read_iso_to_stays_in_root");
+    let root = dir.join("in");
+    let iso_path = dir.join("out.iso");
+    make_root(&root);
+    rebuild(&root, &iso_path);
+    let iso = std::fs::read(&iso_path).unwrap();
+
+    let out = dir.join("out");
+    std::fs::create_dir_all(out.join("root")).unwrap();
+    std::fs::write(out.join("unrelated.txt"), b"keep").unwrap();
+    std::fs::write(out.join("root/stale.txt"), b"stale").unwrap();
+
+    let result = read_iso_to(&iso, &out, &ExtractOptions::default(), &mut |_| {});
+    assert!(matches!(result, Err(ReadISOError::RootDirNotEmpty)));
+    assert!(out.join("root/stale.txt").exists());
+
+    let options = ExtractOptions { mode: ExtractMode::Overwrite, ..ExtractOptions::default() };
+    read_iso_to(&iso, &out, &options, &mut |_| {}).unwrap();
+    assert!(!out.join("root/stale.txt").exists());
+    assert_eq!(std::fs::read(out.join("unrelated.txt")).unwrap(), b"keep");
+    assert_same_tree(&root, &out.join("root"));
+
+    // a separator that isn't part of a file name could climb out of the root
+    for separator in ["/../../", "/", "\\", "..", ""] {
+        let layout = ExtractLayout::Flat { separator: separator.to_string() };
+        let options = ExtractOptions { mode: ExtractMode::Merge, layout, ..ExtractOptions::default() };
+        let result = read_iso_to(&iso, &out, &options, &mut |_| {});
+        assert!(matches!(result, Err(ReadISOError::InvalidSeparator(s)) if s == separator));
+    }
+    assert!(!dir.join("readme").exists() && !out.join("readme").exists());
+    assert_same_tree(&root, &out.join("root"));
+
+    let layout = ExtractLayout::Flat { separator: "__".to_string() };
+    let options = ExtractOptions { mode: ExtractMode::Overwrite, layout, ..ExtractOptions::default() };
+    read_iso_to(&iso, &out, &options, &mut |_| {}).unwrap();
+    assert!(out.join("root/audio__bgm__track01.ast").is_file());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn full_title_round_trips() {
+    let dir = test_dir("full_title_round_trips");
+    let root = dir.join("in");
+    make_root(&root);
+    let hdr_path = root.join("&&systemdata/ISO.hdr");
+
+    let full_title = "A Much Longer Title ".repeat(10);
+    set_full_title(&hdr_path, &full_title).unwrap();
+    let header = read_header(&hdr_path).unwrap();
+    assert_eq!(header.full_title, full_title);
+    assert_eq!(header.title, full_title[..0x20]);
+
+    set_full_title(&hdr_path, "Short").unwrap();
+    let header = read_header(&hdr_path).unwrap();
+    assert_eq!((header.title.as_str(), header.full_title.as_str()), ("Short", "Short"));
+
+    let original = std::fs::read(&hdr_path).unwrap();
+    assert!(matches!(set_full_title(&hdr_path, &"a".repeat(0x3E0)), Err(SetHeaderError::FullTitleTooLong)));
+    let edit = HeaderEdit { title: Some("Title"), full_title: Some("Full Title"), ..HeaderEdit::default() };
+    assert!(matches!(set_header(&hdr_path, edit), Err(SetHeaderError::ConflictingTitles)));
+    assert!(std::fs::read(&hdr_path).unwrap() == original);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn builder_string_table_limit() {
+    // 256 byte names, including the null terminator, outgrow the 24 bit name offsets after 65536 entries
+    let mut builder = IsoBuilder::new();
+    for dir in 0..256 {
+        for file in 0..257 {
+            // names differ at the start, so the duplicate name checks are quick
+            builder.add_file(format!("{:_<255}/{:_<255}", dir, file), Vec::new()).unwrap();
+        }
+    }
+
+    let mut iso = std::io::Cursor::new(Vec::new());
+    assert!(matches!(builder.build_to(&mut iso), Err(WriteISOError::FilenameTooLong(_))));
+}
+
+#[test]
+fn repack_then_extract() {
+    let dir = test_dir("repack_then_extract");
+    let root = dir.join("in");
+    let iso_path = dir.join("out.iso");
+    let repacked_path = dir.join("repacked.iso");
+    make_root(&root);
+    // shares its contents with 'Zeta.dat' in the original iso, and should keep sharing them
+    std::fs::copy(root.join("Zeta.dat"), root.join("Zeta copy.dat")).unwrap();
+    rebuild_with_options(&root, &iso_path, &WriteOptions { dedup: true, ..WriteOptions::default() });
+
+    let options = WriteOptions { strip_junk: true, ..WriteOptions::default() };
+    repack_iso(&iso_path, &repacked_path, &options).unwrap();
+
+    extract(&repacked_path, &dir);
+    assert_same_tree(&root, &dir.join("root"));
+
+    let iso = std::fs::read(&repacked_path).unwrap();
+    let offsets = FstIter::from_iso(&iso).unwrap()
+        .filter_map(|e| match e.unwrap() {
+            FstEvent::File { name, offset, .. } if name.starts_with("Zeta") => Some(offset),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(offsets.len(), 2);
+    assert_eq!(offsets[0], offsets[1]);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn grow_near_4gib_does_not_wrap() {
+    let dir = test_dir("grow_near_4gib_does_not_wrap");
+    let root = dir.join("in");
+    let iso_path = dir.join("out.iso");
+    make_root(&root);
+    rebuild(&root, &iso_path);
+
+    // the iso is left sparse, with data ending just under 4GiB
+    let small = dir.join("small.bin");
+    std::fs::write(&small, contents(100, 0x1000)).unwrap();
+    let options = OperateOptions { allow_grow: true, ..OperateOptions::default() };
+    let last_offset = 0xFFF0_0000;
+    operate_on_iso_with_options(&iso_path, &[
+        IsoOp::InsertAt { iso_path: Path::new("last.bin"), input_path: &small, offset: last_offset },
+    ], &options).unwrap();
+
+    // appending only uses the space after 'last.bin', up to 4GiB
+    let options = OperateOptions { append_only: true, ..options };
+    let large = dir.join("large.bin");
+    std::fs::File::create(&large).unwrap().set_len(0x20_0000).unwrap();
+    let result = plan_operations_with_options(&iso_path, &[
+        IsoOp::Insert { iso_path: Path::new("large.bin"), input_path: &large },
+    ], &options);
+    assert!(matches!(result, Err(OperateISOError::ISOTooLarge)), "{:?}", result.map(|p| p.insertions));
+
+    let plan = plan_operations_with_options(&iso_path, &[
+        IsoOp::Insert { iso_path: Path::new("small.bin"), input_path: &small },
+    ], &options).unwrap();
+    let offset = plan.insertions[0].offset as u64;
+    assert!(offset > last_offset as u64 && offset + 0x1000 <= 1 << 32);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn insert_after_deleting_everything() {
+    let dir = test_dir("insert_after_deleting_everything");
+    let root = dir.join("in");
+    let iso_path = dir.join("out.iso");
+    make_root(&root);
+    rebuild(&root, &iso_path);
+
+    let large = dir.join("large.bin");
+    std::fs::write(&large, contents(100, 0x10_0000)).unwrap();
+    let mut ops = FILES.iter().map(|&(path, _)| IsoOp::Delete { iso_path: Path::new(path) }).collect::<Vec<_>>();
+    ops.push(IsoOp::Insert { iso_path: Path::new("large.bin"), input_path: &large });
+
+    let plan = plan_operations(&iso_path, &ops).unwrap();
+    operate_on_iso(&iso_path, &ops).unwrap();
+
+    // with no files left, the only free space is after the fst, which must not be reused
+    let iso = std::fs::read(&iso_path).unwrap();
+    let header_u32 = |offset: usize| u32::from_be_bytes(iso[offset..][..4].try_into().unwrap()) as u64;
+    let (dol_offset, fst_offset, fs_size) = (header_u32(0x420), header_u32(0x424), header_u32(0x428));
+    let offset = plan.insertions[0].offset as u64;
+    assert!(offset >= fst_offset + fs_size, "{:#x} overlaps the fst at {:#x}", offset, fst_offset);
+    assert!(offset >= dol_offset + 0x400, "{:#x} overlaps Start.dol at {:#x}", offset, dol_offset);
+
+    let new_root = dir.join("expected");
+    std::fs::create_dir_all(&new_root).unwrap();
+    std::fs::copy(&large, new_root.join("large.bin")).unwrap();
+    extract(&iso_path, &dir);
+    assert_same_tree(&new_root, &dir.join("root"));
+    assert_eq!(std::fs::read(dir.join("root/&&systemdata/Start.dol")).unwrap(), std::fs::read(root.join("&&systemdata/Start.dol")).unwrap());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn defragment_then_extract() {
+    let dir = test_dir("defragment_then_extract");
+    let root = dir.join("in");
+    let iso_path = dir.join("out.iso");
+    make_root(&root);
+    rebuild(&root, &iso_path);
+
+    // leave a gap where 'Zeta.dat' was and place a file after the end
+    let new_file = dir.join("new.bin");
+    std::fs::write(&new_file, contents(100, 50000)).unwrap();
+    operate_on_iso(&iso_path, &[
+        IsoOp::Delete { iso_path: Path::new("Zeta.dat") },
+        IsoOp::InsertAt { iso_path: Path::new("new.bin"), input_path: &new_file, offset: 0x20_0000 },
+    ]).unwrap();
+    set_fst_offset(&iso_path, Path::new("b/c/d/e.bin"), 0);
+    let fragmented_len = std::fs::metadata(&iso_path).unwrap().len();
+
+    defragment_iso(&iso_path).unwrap();
+    assert!(std::fs::metadata(&iso_path).unwrap().len() < fragmented_len);
+    assert!(verify_iso(&iso_path).unwrap().is_empty());
+
+    std::fs::remove_file(root.join("Zeta.dat")).unwrap();
+    std::fs::copy(&new_file, root.join("new.bin")).unwrap();
+    std::fs::remove_dir(root.join("emptydir")).unwrap();
+    extract(&iso_path, &dir);
+    assert_same_tree(&root, &dir.join("root"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn replace_dol_then_extract() {
+    let dir = test_dir("replace_dol_then_extract");
+    let root = dir.join("in");
+    let iso_path = dir.join("out.iso");
+    make_root(&root);
+    rebuild(&root, &iso_path);
+    set_fst_offset(&iso_path, Path::new("b/c/d/e.bin"), 0);
+
+    // larger than the space before the fst, so the fst has to move
+    let iso = std::fs::read(&iso_path).unwrap();
+    let header_u32 = |offset: usize| u32::from_be_bytes(iso[offset..][..4].try_into().unwrap()) as usize;
+    let old_fst_offset = header_u32(0x424);
+    let dol = make_dol(200, old_fst_offset - header_u32(0x420));
+    let dol_path = dir.join("new.dol");
+    std::fs::write(&dol_path, &dol).unwrap();
+
+    replace_dol(&iso_path, &dol_path).unwrap();
+    assert!(read_header(&iso_path).is_ok());
+    let iso = std::fs::read(&iso_path).unwrap();
+    assert!(u32::from_be_bytes(iso[0x424..0x428].try_into().unwrap()) as usize > old_fst_offset);
+    assert!(verify_iso(&iso_path).unwrap().is_empty());
+
+    extract(&iso_path, &dir);
+    assert_same_tree(&root, &dir.join("root"));
+    assert!(std::fs::read(dir.join("root/&&systemdata/Start.dol")).unwrap() == dol);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn patch_file_then_extract() {
+    let dir = test_dir("patch_file_then_extract");
+    let root = dir.join("in");
+    let iso_path = dir.join("out.iso");
+    make_root(&root);
+    rebuild(&root, &iso_path);
+
+    let patches = [
+        // smaller, so written in place
+        ("Zeta.dat", contents(100, 30000)),
+        // larger, so inserted elsewhere
+        ("a.txt", contents(101, 5000)),
+        ("b/c/d/e.bin", contents(102, 10)),
+    ];
+    for (path, data) in patches.iter() {
+        patch_file(&iso_path, Path::new(path), data).unwrap();
+        std::fs::write(root.join(path), data).unwrap();
+    }
+    assert!(verify_iso(&iso_path).unwrap().is_empty());
+
+    // a mistyped path isn't created
+    let original = std::fs::read(&iso_path).unwrap();
+    let result = patch_file(&iso_path, Path::new("zeta.dat"), &[1, 2, 3]);
+    assert!(matches!(result, Err(OperateISOError::InvalidISOPath(p)) if p == Path::new("zeta.dat")));
+    assert!(std::fs::read(&iso_path).unwrap() == original);
+
+    std::fs::remove_dir(root.join("emptydir")).unwrap();
+    extract(&iso_path, &dir);
+    assert_same_tree(&root, &dir.join("root"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn shrink_then_extract() {
+    let dir = test_dir("shrink_then_extract");
+    let root = dir.join("in");
+    let iso_path = dir.join("out.iso");
+    make_root(&root);
+    let mut iso = std::fs::File::create(&iso_path).unwrap();
+    write_iso_to(&root, &mut iso).unwrap();
+    drop(iso);
+    assert_eq!(std::fs::metadata(&iso_path).unwrap().len(), ROM_SIZE as u64);
+
+    let shrunk_path = dir.join("shrunk.iso");
+    shrink_iso(&iso_path, &shrunk_path).unwrap();
+    let shrunk_len = std::fs::metadata(&shrunk_path).unwrap().len();
+    assert!(shrunk_len < ROM_SIZE as u64);
+    assert_eq!(shrunk_len % 0x800, 0);
+    assert!(verify_iso(&shrunk_path).unwrap().is_empty());
+
+    extract(&shrunk_path, &dir);
+    assert_same_tree(&root, &dir.join("root"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn layout_rejects_overlaps() {
+    let dir = test_dir("layout_rejects_overlaps");
+    let root = dir.join("in");
+    let iso_path = dir.join("out.iso");
+    make_root(&root);
+    std::fs::copy(root.join("Zeta.dat"), root.join("Zeta copy.dat")).unwrap();
+    rebuild_with_options(&root, &iso_path, &WriteOptions { dedup: true, ..WriteOptions::default() });
+
+    // files sharing contents are recorded at the same place, which is fine
+    extract_with_options(&iso_path, &dir, &ExtractOptions { write_layout: true, ..ExtractOptions::default() });
+    let extracted = dir.join("root");
+    let layout_path = extracted.join("&&systemdata/layout.txt");
+    let layout = std::fs::read_to_string(&layout_path).unwrap();
+    assert!(plan_iso(&extracted, &WriteOptions::default()).is_ok());
+
+    let offset_of = |path: &str| layout.lines().find(|l| l.ends_with(path)).unwrap().split(' ').next().unwrap().to_string();
+    let with_offset = |path: &str, offset: &str| layout.lines()
+        .map(|l| match l.ends_with(path) {
+            true => format!("{} {}", offset, l.split_once(' ').unwrap().1),
+            false => l.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    // inside 'Zeta.dat'
+    let zeta = u32::from_str_radix(offset_of(" Zeta.dat").trim_start_matches("0x"), 16).unwrap();
+    std::fs::write(&layout_path, with_offset(" a.txt", &format!("{:#x}", zeta + 0x100))).unwrap();
+    assert!(matches!(plan_iso(&extracted, &WriteOptions::default()), Err(WriteISOError::InvalidLayout)));
+
+    // past the end of the disc
+    std::fs::write(&layout_path, with_offset(" a.txt", &format!("{:#x}", ROM_SIZE - 1))).unwrap();
+    assert!(matches!(plan_iso(&extracted, &WriteOptions::default()), Err(WriteISOError::InvalidLayout)));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn rename_replaces_existing_file() {
+    let dir = test_dir("rename_replaces_existing_file");
+    let root = dir.join("in");
+    let iso_path = dir.join("out.iso");
+    make_root(&root);
+    rebuild(&root, &iso_path);
+
+    operate_on_iso(&iso_path, &[IsoOp::Rename { from: Path::new("a.txt"), to: Path::new("b/readme") }]).unwrap();
+    assert!(verify_iso(&iso_path).unwrap().is_empty());
+
+    let iso = std::fs::read(&iso_path).unwrap();
+    let readmes = FstIter::from_iso(&iso).unwrap()
+        .filter(|e| matches!(e, Ok(FstEvent::File { path, .. }) if path == Path::new("b/readme")))
+        .count();
+    assert_eq!(readmes, 1);
+
+    std::fs::rename(root.join("a.txt"), root.join("b/readme")).unwrap();
+    std::fs::remove_dir(root.join("emptydir")).unwrap();
+    extract(&iso_path, &dir);
+    assert_same_tree(&root, &dir.join("root"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}